@@ -1,5 +1,6 @@
 import argparse
 import pickle
+import time
 import pandas as pd
 
 from sklearn.ensemble import RandomForestClassifier
@@ -32,5 +33,9 @@ if __name__ == '__main__':
     forest = RandomForestClassifier(n_estimators=10)
     forest = forest.fit(features, labels)
 
+    # Store the column order so the prediction service can check it against
+    # what the Rust extractor sends
     with open(output, 'wb') as f:
-        pickle.dump(forest, f)
\ No newline at end of file
+        pickle.dump({'model': forest,
+                     'columns': list(features.columns),
+                     'version': str(int(time.time()))}, f)
\ No newline at end of file
@@ -1,52 +1,96 @@
 #!/usr/bin/python3.6
+import argparse
 import pandas as pd
 
 
-from flask import Flask
+from flask import Flask, request
 from sklearn.ensemble import RandomForestClassifier
 from io import StringIO
 
 
 app = Flask(__name__)
 
+# Models pickled before the column names were stored alongside them were
+# trained on example.csv
+LEGACY_COLUMNS = ['relative_sentiment', 'positive_sentiment', 'negative_sentiment']
+
 
-def load_model(path) -> RandomForestClassifier:
+def load_model(path) -> (RandomForestClassifier, [str], str):
     import pickle
     with open(path, 'rb') as f:
-        forest = pickle.load(f)
-    return forest
+        stored = pickle.load(f)
+
+    if isinstance(stored, dict):
+        return stored['model'], stored['columns'], stored.get('version', 'unversioned')
+    return stored, LEGACY_COLUMNS, 'legacy'
 
 
 def get_args() -> str:
-    model_path = "./model"
-    return model_path
+    # The rest of the arguments are parsed when run as a script
+    model_parser = argparse.ArgumentParser(add_help=False)
+    model_parser.add_argument('--model', type=str, default='./model')
+    args, _ = model_parser.parse_known_args()
+    return args.model
 
 model_path = get_args()
 
-forest = load_model(model_path)
+forest, columns, version = load_model(model_path)
+
+# predict_proba orders its output by forest.classes_
+spam_class = [i for i, c in enumerate(forest.classes_) if c in (True, 1, 'spam')][0]
+
+
+def read_features(body: str) -> pd.DataFrame:
+    features: pd.DataFrame = pd.read_csv(StringIO(body))
+
+    missing = set(columns) - set(features.columns)
+    if missing:
+        raise ValueError('Missing feature columns: {}'.format(sorted(missing)))
+
+    # Reorder to match the columns the model was trained on
+    return features[columns]
+
+
+@app.route('/predict', methods=['POST'])
+def predict():
+    try:
+        features = read_features(request.get_data(as_text=True))
+    except ValueError as e:
+        return str(e), 400
 
+    return str(forest.predict_proba(features)[0][spam_class])
 
-@app.route('/predict/<string:csv_features>')
-def predict(csv_features):
-    print(csv_features)
-    csv_features = StringIO(csv_features)
-    features: pd.DataFrame = pd.read_csv(csv_features, names=['a','b','c'])
 
-    p = forest.predict(features)
-    print(p)
-    return str(forest.predict(features)[0])
+@app.route('/predict_batch', methods=['POST'])
+def predict_batch():
+    try:
+        features = read_features(request.get_data(as_text=True))
+    except ValueError as e:
+        return str(e), 400
+
+    # One probability per line, in the order the rows were sent
+    return '\n'.join(str(p) for p in forest.predict_proba(features)[:, spam_class])
+
+
+@app.route('/schema')
+def schema():
+    return ','.join(columns)
+
+
+@app.route('/version')
+def model_version():
+    return version
+
 
 @app.route('/health_check')
 def health_check():
     return "UP"
 
 
-import argparse
-
-
 parser = argparse.ArgumentParser(description='Prediction service')
 parser.add_argument('--port', type=int, help='Port to bind to.', required=True)
+parser.add_argument('--model', type=str, help='Path to the pickled model.', default='./model')
 
 if __name__ == '__main__':
     port = parser.parse_args().port
-    app.run(port=port)
\ No newline at end of file
+    app.run(port=port)
@@ -0,0 +1,336 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use errors::*;
+use email::*;
+use mime::*;
+use feature_vector::FeatureColumns;
+
+const EXECUTABLE: &'static [&'static str] = &[
+    "exe", "scr", "com", "pif", "bat", "cmd", "msi", "dll", "cpl", "jar", "app", "elf", "lnk",
+];
+const SCRIPT: &'static [&'static str] = &[
+    "js", "jse", "vbs", "vbe", "wsf", "wsh", "ps1", "hta", "sh", "py", "pl",
+];
+const MACRO_CAPABLE: &'static [&'static str] = &[
+    "doc", "dot", "docm", "dotm", "xls", "xlt", "xlsm", "xltm", "xlam", "ppt", "pptm", "potm", "rtf",
+];
+const ARCHIVE: &'static [&'static str] = &[
+    "zip", "rar", "7z", "gz", "tar", "tgz", "iso", "img", "cab", "ace", "arj",
+];
+/// What a double extension pretends to be
+const DECOY: &'static [&'static str] = &[
+    "pdf", "doc", "docx", "xls", "xlsx", "txt", "jpg", "jpeg", "png", "gif", "htm", "html",
+];
+
+/// The content types an extension may be declared as
+fn expected_types(extension: &str) -> Option<&'static [&'static str]> {
+    let types: &'static [&'static str] = match extension {
+        "pdf" => &["application/pdf"],
+        "doc" | "dot" => &["application/msword"],
+        "xls" => &["application/vnd.ms-excel"],
+        "ppt" => &["application/vnd.ms-powerpoint"],
+        "docx" | "docm" | "xlsx" | "xlsm" | "pptx" | "pptm" => &["application/vnd.openxmlformats-officedocument",
+                                                              "application/vnd.ms-"],
+        "zip" => &["application/zip", "application/x-zip-compressed", "application/x-zip"],
+        "jpg" | "jpeg" => &["image/jpeg", "image/jpg", "image/pjpeg"],
+        "png" => &["image/png"],
+        "gif" => &["image/gif"],
+        "txt" => &["text/plain"],
+        "htm" | "html" => &["text/html"],
+        "exe" | "dll" => &["application/x-msdownload", "application/x-dosexec", "application/x-msdos-program"],
+        _ => return None,
+    };
+    Some(types)
+}
+
+/// The type an extension's magic bytes should sniff as, for those we sniff
+fn expected_sniff(extension: &str) -> Option<&'static str> {
+    match extension {
+        "pdf" => Some("application/pdf"),
+        "doc" | "dot" | "xls" | "ppt" | "msg" => Some("application/x-ole-storage"),
+        "docx" | "docm" | "xlsx" | "xlsm" | "pptx" | "pptm" | "zip" | "jar" => Some("application/zip"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "rar" => Some("application/x-rar"),
+        "7z" => Some("application/x-7z-compressed"),
+        "gz" | "tgz" => Some("application/gzip"),
+        "exe" | "dll" | "scr" => Some("application/x-msdownload"),
+        "rtf" => Some("application/rtf"),
+        _ => None,
+    }
+}
+
+pub struct AttachmentAnalyzer {
+    self_ref: AttachmentAnalyzerActor,
+    system: SystemActor,
+}
+
+type AttachmentFeaturesResponse = std::sync::Arc<Fn(Result<AttachmentFeatures>) + Send + Sync + 'static>;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AttachmentFeatures {
+    pub attachment_count: usize,
+    /// Filename extension contradicts the declared content type
+    pub name_type_mismatches: usize,
+    /// Filename extension contradicts the magic bytes
+    pub name_magic_mismatches: usize,
+    pub double_extensions: usize,
+    pub executables: usize,
+    pub scripts: usize,
+    pub macro_capable: usize,
+    /// Archives containing archives
+    pub nested_archives: usize,
+    pub encrypted_archives: usize,
+    pub total_attachment_bytes: usize,
+    pub max_attachment_bytes: usize,
+    pub min_attachment_bytes: usize,
+    /// Why this email's attachments look dangerous, for people to read
+    pub reasons: Vec<String>,
+}
+
+impl FeatureColumns for AttachmentFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["attachment_count", "name_type_mismatches", "name_magic_mismatches",
+             "double_extensions", "executables", "scripts", "macro_capable", "nested_archives",
+             "encrypted_archives", "total_attachment_bytes", "max_attachment_bytes",
+             "min_attachment_bytes"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.attachment_count as f32,
+             self.name_type_mismatches as f32,
+             self.name_magic_mismatches as f32,
+             self.double_extensions as f32,
+             self.executables as f32,
+             self.scripts as f32,
+             self.macro_capable as f32,
+             self.nested_archives as f32,
+             self.encrypted_archives as f32,
+             self.total_attachment_bytes as f32,
+             self.max_attachment_bytes as f32,
+             self.min_attachment_bytes as f32]
+    }
+}
+
+/// Named parts, and unnamed ones that aren't body text
+pub fn attachments(email: &Email) -> Vec<&MimePart> {
+    email.parts.iter()
+        .filter(|p| !p.is_multipart())
+        .filter(|p| p.name().is_some() || !(p.mimetype == "text/plain" || p.mimetype == "text/html"))
+        .collect()
+}
+
+/// The lowercased extensions of a file name, last one last
+fn extensions(name: &str) -> Vec<String> {
+    name.trim()
+        .split('.')
+        .skip(1)
+        .map(|e| e.trim().to_lowercase())
+        .collect()
+}
+
+impl<'a> From<&'a Email> for AttachmentFeatures {
+    fn from(email: &'a Email) -> AttachmentFeatures {
+        let mut features = AttachmentFeatures::default();
+        let attachments = attachments(email);
+
+        features.attachment_count = attachments.len();
+
+        for attachment in attachments {
+            let name = attachment.name().unwrap_or("unnamed attachment").to_owned();
+            let extensions = extensions(&name);
+            let extension = extensions.last().cloned().unwrap_or_default();
+
+            let size = attachment.body.len();
+            features.total_attachment_bytes += size;
+            features.max_attachment_bytes = std::cmp::max(features.max_attachment_bytes, size);
+            features.min_attachment_bytes = if features.min_attachment_bytes == 0 {
+                size
+            } else {
+                std::cmp::min(features.min_attachment_bytes, size)
+            };
+
+            if let Some(types) = expected_types(&extension) {
+                let declared = &attachment.mimetype;
+                if declared != "application/octet-stream" && !types.iter().any(|t| declared.starts_with(t)) {
+                    features.name_type_mismatches += 1;
+                    features.reasons.push(format!("{} is declared as {}", name, declared));
+                }
+            }
+
+            if let (Some(expected), Some(sniffed)) = (expected_sniff(&extension), attachment.sniffed) {
+                if expected != sniffed {
+                    features.name_magic_mismatches += 1;
+                    features.reasons.push(format!("{} is really {}", name, sniffed));
+                }
+            }
+
+            if extensions.len() >= 2 && DECOY.contains(&extensions[extensions.len() - 2].as_str()) {
+                features.double_extensions += 1;
+                features.reasons.push(format!("{} has a double extension", name));
+            }
+
+            let sniffed_executable = attachment.sniffed == Some("application/x-msdownload") ||
+                attachment.sniffed == Some("application/x-executable");
+            if EXECUTABLE.contains(&extension.as_str()) || sniffed_executable {
+                features.executables += 1;
+                features.reasons.push(format!("{} is an executable", name));
+            }
+
+            if SCRIPT.contains(&extension.as_str()) {
+                features.scripts += 1;
+                features.reasons.push(format!("{} is a script", name));
+            }
+
+            if MACRO_CAPABLE.contains(&extension.as_str()) {
+                features.macro_capable += 1;
+                features.reasons.push(format!("{} can carry macros", name));
+            }
+
+            if attachment.sniffed == Some("application/zip") || extension == "zip" {
+                let entries = zip_entries(&attachment.body);
+
+                if entries.iter().any(|e| e.encrypted) {
+                    features.encrypted_archives += 1;
+                    features.reasons.push(format!("{} is an encrypted archive", name));
+                }
+
+                let nested = entries.iter()
+                    .filter(|e| extensions(&e.name).last().map(|x| ARCHIVE.contains(&x.as_str())).unwrap_or(false))
+                    .count();
+                if nested > 0 {
+                    features.nested_archives += 1;
+                    features.reasons.push(format!("{} contains {} nested archive(s)", name, nested));
+                }
+            }
+        }
+
+        features
+    }
+}
+
+#[derive(Debug)]
+pub struct ZipEntry {
+    pub name: String,
+    pub encrypted: bool,
+    pub compressed_size: usize,
+    pub uncompressed_size: usize,
+}
+
+/// Lists a zip's entries from its central directory. Anything we can't make
+/// sense of yields no entries rather than an error - a broken archive can't
+/// be opened by the recipient either.
+pub fn zip_entries(zip: &[u8]) -> Vec<ZipEntry> {
+    const END_OF_DIRECTORY: &'static [u8] = b"PK\x05\x06";
+    const DIRECTORY_ENTRY: &'static [u8] = b"PK\x01\x02";
+
+    // The end record is at least 22 bytes, followed by a comment of up to 64k
+    let search_from = zip.len().saturating_sub(22 + 0xFFFF);
+    let end = match (search_from..zip.len().saturating_sub(21)).rev()
+        .find(|&i| zip[i..].starts_with(END_OF_DIRECTORY)) {
+        Some(end) => end,
+        None => return Vec::new(),
+    };
+
+    let count = LittleEndian::read_u16(&zip[end + 10..]) as usize;
+    let mut offset = LittleEndian::read_u32(&zip[end + 16..]) as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if offset + 46 > zip.len() || !zip[offset..].starts_with(DIRECTORY_ENTRY) {
+            break;
+        }
+
+        let header = &zip[offset..];
+        let flags = LittleEndian::read_u16(&header[8..]);
+        let compressed_size = LittleEndian::read_u32(&header[20..]) as usize;
+        let uncompressed_size = LittleEndian::read_u32(&header[24..]) as usize;
+        let name_len = LittleEndian::read_u16(&header[28..]) as usize;
+        let extra_len = LittleEndian::read_u16(&header[30..]) as usize;
+        let comment_len = LittleEndian::read_u16(&header[32..]) as usize;
+
+        if 46 + name_len > header.len() {
+            break;
+        }
+
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(&header[46..46 + name_len]).into_owned(),
+            encrypted: flags & 1 == 1,
+            compressed_size,
+            uncompressed_size,
+        });
+
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+
+    entries
+}
+
+#[derive_actor]
+impl AttachmentAnalyzer {
+    pub fn analyze(&self, email: ParsedEmail, res: AttachmentFeaturesResponse) {
+        res(Ok(AttachmentFeatures::from(&*email)))
+    }
+}
+
+impl AttachmentAnalyzer {
+    pub fn new(self_ref: AttachmentAnalyzerActor, system: SystemActor) -> AttachmentAnalyzer {
+        AttachmentAnalyzer {
+            self_ref,
+            system,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: AttachmentAnalyzerMessage,
+                   t: Arc<T>)
+        where T: Fn(AttachmentAnalyzerActor, SystemActor) -> AttachmentAnalyzer + Send + Sync + 'static
+    {
+        match msg {
+            AttachmentAnalyzerMessage::AnalyzeVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in attachment analyzer".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_disguised_executables() {
+        let email = Email {
+            parts: vec![MimePart {
+                mimetype: "application/pdf".to_owned(),
+                filename: Some("invoice.pdf.exe".to_owned()),
+                body: b"MZ\x90\x00".to_vec(),
+                sniffed: Some("application/x-msdownload"),
+                ..MimePart::default()
+            }],
+            ..Email::default()
+        };
+
+        let features = AttachmentFeatures::from(&email);
+
+        assert_eq!(features.attachment_count, 1);
+        assert_eq!(features.double_extensions, 1);
+        assert_eq!(features.executables, 1);
+        assert_eq!(features.name_type_mismatches, 1);
+        assert_eq!(features.max_attachment_bytes, 4);
+        assert!(!features.reasons.is_empty());
+    }
+}
@@ -0,0 +1,411 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use errors::*;
+use email::*;
+use authentication::AuthResult;
+use feature_vector::FeatureColumns;
+
+/// One Authentication-Results header (RFC 8601)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuthenticationResultsHeader {
+    pub authserv_id: String,
+    /// (method, result) in the order they appear
+    pub results: Vec<(String, AuthResult)>,
+}
+
+impl AuthenticationResultsHeader {
+    pub fn parse(value: &str) -> Option<AuthenticationResultsHeader> {
+        let value = strip_comments(value);
+        let mut statements = split_unquoted(&value, ';').into_iter();
+
+        // The authserv-id may be followed by a version number
+        let authserv_id = match statements.next().and_then(|s| s.split_whitespace().next().map(|s| s.to_owned())) {
+            Some(id) => id.to_lowercase(),
+            None => return None,
+        };
+
+        let results = statements
+            .filter_map(|statement| {
+                let resinfo = match statement.split_whitespace().next() {
+                    Some(resinfo) => resinfo.to_owned(),
+                    None => return None,
+                };
+
+                let mut kv = resinfo.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(method), Some(result)) => {
+                        // Methods may carry a version, e.g. dkim/1
+                        let method = method.split('/').next().unwrap_or(method).to_lowercase();
+                        AuthResult::parse(result).map(|result| (method, result))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+
+        Some(AuthenticationResultsHeader {
+            authserv_id,
+            results,
+        })
+    }
+
+    /// The result of `method`, the best one if it was reported more than once
+    pub fn result(&self, method: &str) -> Option<AuthResult> {
+        let results: Vec<AuthResult> = self.results.iter()
+            .filter(|&&(ref m, _)| m == method)
+            .map(|&(_, result)| result)
+            .collect();
+
+        if results.contains(&AuthResult::Pass) {
+            Some(AuthResult::Pass)
+        } else {
+            results.first().cloned()
+        }
+    }
+}
+
+/// Removes (comments), which may nest, outside of quoted strings
+fn strip_comments(value: &str) -> String {
+    let mut stripped = String::with_capacity(value.len());
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        if escaped {
+            escaped = false;
+            if depth == 0 {
+                stripped.push(c);
+            }
+            continue;
+        }
+
+        match c {
+            '\\' => escaped = true,
+            '"' if depth == 0 => quoted = !quoted,
+            '(' if !quoted => {
+                depth += 1;
+                continue;
+            }
+            ')' if !quoted && depth > 0 => {
+                depth -= 1;
+                continue;
+            }
+            _ => (),
+        }
+
+        if depth == 0 {
+            stripped.push(c);
+        }
+    }
+
+    stripped
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut quoted = false;
+
+    for c in value.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+
+        if c == separator && !quoted {
+            parts.push(String::new());
+        } else if let Some(part) = parts.last_mut() {
+            part.push(c);
+        }
+    }
+
+    parts
+}
+
+/// The `i=` instance and remaining value of an ARC header
+fn arc_instance(value: &str) -> Option<(usize, String)> {
+    let mut parts = value.splitn(2, ';');
+    let instance = parts.next()
+        .and_then(|tag| {
+            let mut kv = tag.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k.trim() == "i" => v.trim().parse().ok(),
+                _ => None,
+            }
+        });
+
+    match instance {
+        Some(instance) => Some((instance, parts.next().unwrap_or("").to_owned())),
+        None => None,
+    }
+}
+
+/// RFC 8617 limits a chain to 50 sets, anything claiming more is broken
+const MAX_ARC_INSTANCES: usize = 50;
+
+/// The ARC chain's structure: every instance from 1 up has exactly one of
+/// each header, the first seal has cv=none and the rest cv=pass. The
+/// signatures themselves aren't verified.
+fn arc_chain(email: &Email) -> (AuthResult, usize) {
+    let seals: Vec<(usize, String)> = email.header_values("ARC-Seal").into_iter()
+        .filter_map(arc_instance)
+        .collect();
+
+    if seals.is_empty() {
+        return (AuthResult::None, 0);
+    }
+
+    let instances = seals.iter().map(|&(i, _)| i).max().unwrap_or(0);
+    if instances > MAX_ARC_INSTANCES {
+        return (AuthResult::Fail, MAX_ARC_INSTANCES);
+    }
+
+    let count = |header: &str, instance: usize| {
+        email.header_values(header).into_iter()
+            .filter_map(arc_instance)
+            .filter(|&(i, _)| i == instance)
+            .count()
+    };
+
+    let complete = (1..instances + 1).all(|i| {
+        count("ARC-Seal", i) == 1 &&
+            count("ARC-Message-Signature", i) == 1 &&
+            count("ARC-Authentication-Results", i) == 1
+    });
+
+    let cv_valid = seals.iter().all(|&(i, ref seal)| {
+        let cv = seal.split(';')
+            .filter_map(|tag| {
+                let mut kv = tag.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if k.trim() == "cv" => Some(v.trim().to_lowercase()),
+                    _ => None,
+                }
+            })
+            .next()
+            .unwrap_or_default();
+
+        if i == 1 { cv == "none" } else { cv == "pass" }
+    });
+
+    if complete && cv_valid {
+        (AuthResult::Pass, instances)
+    } else {
+        (AuthResult::Fail, instances)
+    }
+}
+
+pub struct AuthResultsAnalyzer {
+    self_ref: AuthResultsAnalyzerActor,
+    system: SystemActor,
+    trusted_authserv_ids: Arc<HashSet<String>>,
+}
+
+type AuthResultsFeaturesResponse = std::sync::Arc<Fn(Result<AuthResultsFeatures>) + Send + Sync + 'static>;
+
+/// Results an upstream MTA already stamped on the email
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AuthResultsFeatures {
+    pub upstream_spf: AuthResult,
+    pub upstream_dkim: AuthResult,
+    pub upstream_dmarc: AuthResult,
+    /// As reported by a trusted server, which verified the chain's signatures
+    pub arc: AuthResult,
+    /// Whether the ARC headers form a well-formed chain. Nothing is verified,
+    /// anyone can add a chain that passes this.
+    pub arc_structure: AuthResult,
+    pub arc_instances: usize,
+    /// Authentication-Results headers from servers we don't trust - anyone
+    /// can add these, a forged "pass" is a phishing tell
+    pub untrusted_results: usize,
+}
+
+impl FeatureColumns for AuthResultsFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["upstream_spf", "upstream_dkim", "upstream_dmarc", "arc_result", "arc_structure",
+             "arc_instances", "untrusted_auth_results"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.upstream_spf.score(),
+             self.upstream_dkim.score(),
+             self.upstream_dmarc.score(),
+             self.arc.score(),
+             self.arc_structure.score(),
+             self.arc_instances as f32,
+             self.untrusted_results as f32]
+    }
+}
+
+impl AuthResultsFeatures {
+    /// `trusted` holds lowercased authserv-ids
+    pub fn from_email(email: &Email, trusted: &HashSet<String>) -> AuthResultsFeatures {
+        let headers: Vec<AuthenticationResultsHeader> = email.header_values("Authentication-Results")
+            .into_iter()
+            .filter_map(AuthenticationResultsHeader::parse)
+            .collect();
+
+        let (arc_structure, arc_instances) = arc_chain(email);
+
+        // The topmost trusted header is the one our own border added
+        let upstream = headers.iter().find(|h| trusted.contains(&h.authserv_id));
+        let arc = upstream.and_then(|h| h.result("arc")).unwrap_or_default();
+
+        // If a forwarder sits in front of our border, the results it sealed
+        // into its ARC set are the closest we have to the original delivery.
+        // Only our border can tell a real seal from a forged one.
+        let sealed = if arc == AuthResult::Pass && arc_structure == AuthResult::Pass {
+            email.header_values("ARC-Authentication-Results").into_iter()
+                .filter_map(arc_instance)
+                .filter(|&(i, _)| i == arc_instances)
+                .filter_map(|(_, value)| AuthenticationResultsHeader::parse(&value))
+                .find(|h| trusted.contains(&h.authserv_id))
+        } else {
+            None
+        };
+
+        let result = |method: &str| {
+            sealed.as_ref()
+                .and_then(|h| h.result(method))
+                .or_else(|| upstream.and_then(|h| h.result(method)))
+                .unwrap_or_default()
+        };
+
+        AuthResultsFeatures {
+            upstream_spf: result("spf"),
+            upstream_dkim: result("dkim"),
+            upstream_dmarc: result("dmarc"),
+            arc,
+            arc_structure,
+            arc_instances,
+            untrusted_results: headers.iter().filter(|h| !trusted.contains(&h.authserv_id)).count(),
+        }
+    }
+}
+
+#[derive_actor]
+impl AuthResultsAnalyzer {
+    pub fn analyze(&self, email: ParsedEmail, res: AuthResultsFeaturesResponse) {
+        res(Ok(AuthResultsFeatures::from_email(&*email, &self.trusted_authserv_ids)))
+    }
+}
+
+impl AuthResultsAnalyzer {
+    pub fn new(trusted_authserv_ids: Vec<String>,
+               self_ref: AuthResultsAnalyzerActor,
+               system: SystemActor) -> AuthResultsAnalyzer {
+        AuthResultsAnalyzer {
+            self_ref,
+            system,
+            trusted_authserv_ids: Arc::new(
+                trusted_authserv_ids.iter().map(|id| id.to_lowercase()).collect()
+            ),
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: AuthResultsAnalyzerMessage,
+                   t: Arc<T>)
+        where T: Fn(AuthResultsAnalyzerActor, SystemActor) -> AuthResultsAnalyzer + Send + Sync + 'static
+    {
+        match msg {
+            AuthResultsAnalyzerMessage::AnalyzeVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in authentication results analyzer".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusts_configured_authserv_ids() {
+        let headers = vec![
+            ("Authentication-Results", "mx.example.org (version 1); spf=softfail (sender \
+                IP is 203.0.113.5) smtp.mailfrom=bank.com; dkim=fail header.d=bank.com; \
+                dmarc=fail (p=reject) header.from=bank.com"),
+            ("Authentication-Results", "attacker.net; spf=pass; dkim=pass; dmarc=pass"),
+            ("ARC-Seal", "i=1; a=rsa-sha256; cv=none; d=lists.net; s=arc; b=abc"),
+            ("ARC-Message-Signature", "i=1; a=rsa-sha256; d=lists.net; s=arc; b=def"),
+            ("ARC-Authentication-Results", "i=1; lists.net; spf=pass"),
+        ];
+
+        let email = Email {
+            headers: headers.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect(),
+            ..Email::default()
+        };
+
+        let trusted: HashSet<String> = vec!["mx.example.org".to_owned()].into_iter().collect();
+        let features = AuthResultsFeatures::from_email(&email, &trusted);
+
+        assert_eq!(features.upstream_spf, AuthResult::SoftFail);
+        assert_eq!(features.upstream_dkim, AuthResult::Fail);
+        assert_eq!(features.upstream_dmarc, AuthResult::Fail);
+        assert_eq!(features.arc, AuthResult::None);
+        assert_eq!(features.arc_structure, AuthResult::Pass);
+        assert_eq!(features.arc_instances, 1);
+        assert_eq!(features.untrusted_results, 1);
+    }
+
+    #[test]
+    fn forged_arc_sets_are_ignored() {
+        let headers = |arc: &str| vec![
+            ("Authentication-Results", format!("mx.example.org; spf=fail smtp.mailfrom=bank.com; \
+                dkim=none; dmarc=fail header.from=bank.com; arc={}", arc)),
+            ("ARC-Seal", "i=1; a=rsa-sha256; cv=none; d=bank.com; s=arc; b=forged".to_owned()),
+            ("ARC-Message-Signature", "i=1; a=rsa-sha256; d=bank.com; s=arc; b=forged".to_owned()),
+            ("ARC-Authentication-Results",
+             "i=1; mx.example.org; spf=pass; dkim=pass; dmarc=pass".to_owned()),
+        ];
+
+        let email = |arc: &str| Email {
+            headers: headers(arc).into_iter().map(|(k, v)| (k.to_owned(), v)).collect(),
+            ..Email::default()
+        };
+
+        let trusted: HashSet<String> = vec!["mx.example.org".to_owned()].into_iter().collect();
+
+        // Well-formed, but our border couldn't verify the seal
+        let forged = AuthResultsFeatures::from_email(&email("fail"), &trusted);
+        assert_eq!(forged.arc, AuthResult::Fail);
+        assert_eq!(forged.arc_structure, AuthResult::Pass);
+        assert_eq!(forged.upstream_spf, AuthResult::Fail);
+        assert_eq!(forged.upstream_dmarc, AuthResult::Fail);
+
+        let sealed = AuthResultsFeatures::from_email(&email("pass"), &trusted);
+        assert_eq!(sealed.arc, AuthResult::Pass);
+        assert_eq!(sealed.upstream_spf, AuthResult::Pass);
+        assert_eq!(sealed.upstream_dmarc, AuthResult::Pass);
+    }
+
+    #[test]
+    fn oversized_arc_chains_fail() {
+        let email = Email {
+            headers: vec![
+                ("ARC-Seal".to_owned(), "i=18446744073709551615; cv=pass; b=x".to_owned()),
+                ("ARC-Message-Signature".to_owned(), "i=18446744073709551615; b=x".to_owned()),
+                ("ARC-Authentication-Results".to_owned(),
+                 "i=18446744073709551615; mx.example.org; spf=pass".to_owned()),
+            ],
+            ..Email::default()
+        };
+
+        assert_eq!(arc_chain(&email), (AuthResult::Fail, MAX_ARC_INSTANCES));
+
+        let features = AuthResultsFeatures::from_email(&email, &HashSet::new());
+        assert_eq!(features.arc_structure, AuthResult::Fail);
+        assert_eq!(features.upstream_spf, AuthResult::None);
+    }
+}
@@ -0,0 +1,486 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use rayon::{Configuration, ThreadPool};
+
+use errors::*;
+use email::*;
+use dkim;
+use spf;
+use resolver::Resolver;
+use config::AuthenticationConfig;
+use headers::address_domain;
+use public_suffix::registrable_domain;
+use feature_vector::FeatureColumns;
+
+/// The outcome of an SPF, DKIM or DMARC check
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthResult {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    /// Nothing was published or signed to check against
+    None,
+    TempError,
+    PermError,
+}
+
+impl Default for AuthResult {
+    fn default() -> AuthResult {
+        AuthResult::None
+    }
+}
+
+impl AuthResult {
+    /// A result as written in an Authentication-Results header
+    pub fn parse(result: &str) -> Option<AuthResult> {
+        match result.to_lowercase().as_ref() {
+            "pass" => Some(AuthResult::Pass),
+            "fail" | "hardfail" | "policy" => Some(AuthResult::Fail),
+            "softfail" => Some(AuthResult::SoftFail),
+            "neutral" => Some(AuthResult::Neutral),
+            "none" => Some(AuthResult::None),
+            "temperror" => Some(AuthResult::TempError),
+            "permerror" => Some(AuthResult::PermError),
+            _ => None,
+        }
+    }
+
+    /// 1 for a pass, -1 for a fail, somewhere between for the rest
+    pub fn score(&self) -> f32 {
+        match *self {
+            AuthResult::Pass => 1.0,
+            AuthResult::Fail => -1.0,
+            AuthResult::SoftFail | AuthResult::PermError => -0.5,
+            AuthResult::Neutral | AuthResult::None | AuthResult::TempError => 0.0,
+        }
+    }
+}
+
+/// What the domain owner asks receivers to do with mail failing DMARC
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DmarcPolicy {
+    None,
+    Quarantine,
+    Reject,
+}
+
+impl Default for DmarcPolicy {
+    fn default() -> DmarcPolicy {
+        DmarcPolicy::None
+    }
+}
+
+/// The results exposed on a Verdict
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthenticationResults {
+    pub spf: AuthResult,
+    pub dkim: AuthResult,
+    pub dmarc: AuthResult,
+    pub dmarc_policy: DmarcPolicy,
+    /// The From domain DMARC was evaluated for
+    pub domain: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AuthenticationFeatures {
+    pub results: AuthenticationResults,
+    pub reasons: Vec<String>,
+}
+
+impl FeatureColumns for AuthenticationFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["spf_result", "dkim_result", "dmarc_result", "dmarc_policy"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.results.spf.score(),
+             self.results.dkim.score(),
+             self.results.dmarc.score(),
+             self.results.dmarc_policy as u8 as f32]
+    }
+}
+
+impl AuthenticationFeatures {
+    /// What's reported when verification didn't finish in time
+    pub fn timed_out() -> AuthenticationFeatures {
+        AuthenticationFeatures {
+            results: AuthenticationResults {
+                spf: AuthResult::TempError,
+                dkim: AuthResult::TempError,
+                dmarc: AuthResult::TempError,
+                ..AuthenticationResults::default()
+            },
+            reasons: Vec::new(),
+        }
+    }
+
+    pub fn verify(resolver: &Resolver, raw: &[u8]) -> AuthenticationFeatures {
+        let (fields, _) = dkim::split_message(raw);
+        let fields: Vec<String> = fields.iter()
+            .map(|field| String::from_utf8_lossy(field).into_owned())
+            .collect();
+        let header = |name: &str| {
+            fields.iter()
+                .filter_map(|field| {
+                    let mut kv = field.splitn(2, ':');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case(name) => {
+                            Some(v.replace("\r\n", "").trim().to_owned())
+                        }
+                        _ => None,
+                    }
+                })
+                .next()
+        };
+
+        let from_domain = header("From").and_then(|from| address_domain(&from));
+        let spf_domain = header("Return-Path")
+            .and_then(|path| address_domain(&path))
+            .or_else(|| from_domain.clone());
+
+        let spf = match (client_ip(&fields), spf_domain.as_ref()) {
+            (Some(ip), Some(domain)) => spf::check_host(resolver, ip, domain),
+            _ => AuthResult::None,
+        };
+
+        let signatures = dkim::verify(resolver, raw);
+        let dkim = combine(signatures.iter().map(|s| s.result));
+
+        let (dmarc, dmarc_policy) = match from_domain {
+            Some(ref from) => {
+                let dkim_domains = signatures.iter()
+                    .filter(|s| s.result == AuthResult::Pass)
+                    .map(|s| s.domain.as_str())
+                    .collect::<Vec<_>>();
+                let spf_domain = match spf {
+                    AuthResult::Pass => spf_domain.as_ref().map(|d| d.as_str()),
+                    _ => None,
+                };
+                evaluate_dmarc(resolver, from, &dkim_domains, spf_domain)
+            }
+            None => (AuthResult::None, DmarcPolicy::None),
+        };
+
+        let mut reasons = Vec::new();
+        if dmarc == AuthResult::Fail && dmarc_policy != DmarcPolicy::None {
+            reasons.push(format!("fails DMARC for {}", from_domain.clone().unwrap_or_default()));
+        }
+
+        AuthenticationFeatures {
+            results: AuthenticationResults {
+                spf,
+                dkim,
+                dmarc,
+                dmarc_policy,
+                domain: from_domain,
+            },
+            reasons,
+        }
+    }
+}
+
+/// The connecting client's address, as recorded by our own MTA in the
+/// topmost Received header that has one
+fn client_ip(fields: &[String]) -> Option<IpAddr> {
+    fields.iter()
+        .filter(|f| f.to_lowercase().starts_with("received:"))
+        .filter_map(|received| {
+            received.split('[')
+                .skip(1)
+                .filter_map(|s| s.split(']').next())
+                .filter_map(|ip| ip.trim_left_matches("IPv6:").parse().ok())
+                .next()
+        })
+        .next()
+}
+
+/// One result for several signatures - any pass is enough
+fn combine<I>(results: I) -> AuthResult
+    where I: Iterator<Item = AuthResult>
+{
+    let results: Vec<AuthResult> = results.collect();
+    let order = [AuthResult::Pass, AuthResult::TempError, AuthResult::Fail, AuthResult::PermError];
+
+    order.iter()
+        .find(|result| results.contains(result))
+        .cloned()
+        .unwrap_or(AuthResult::None)
+}
+
+/// Evaluates the From domain's DMARC record, falling back to its
+/// organizational domain's. `dkim_domains` holds the domains of passing DKIM
+/// signatures and `spf_domain` the domain SPF passed for, if it did.
+fn evaluate_dmarc(resolver: &Resolver,
+                  from: &str,
+                  dkim_domains: &[&str],
+                  spf_domain: Option<&str>) -> (AuthResult, DmarcPolicy) {
+    let org = registrable_domain(from);
+
+    let mut record = match dmarc_record(resolver, from) {
+        Ok(record) => record.map(|tags| (tags, false)),
+        Err(e) => return (e, DmarcPolicy::None),
+    };
+
+    if record.is_none() && org != from {
+        record = match dmarc_record(resolver, &org) {
+            Ok(record) => record.map(|tags| (tags, true)),
+            Err(e) => return (e, DmarcPolicy::None),
+        };
+    }
+
+    let (tags, inherited) = match record {
+        Some(record) => record,
+        None => return (AuthResult::None, DmarcPolicy::None),
+    };
+
+    let policy = |value: Option<&String>| match value.map(|p| p.as_str()) {
+        Some("reject") => DmarcPolicy::Reject,
+        Some("quarantine") => DmarcPolicy::Quarantine,
+        _ => DmarcPolicy::None,
+    };
+
+    // Subdomains inherit sp= where it's set
+    let policy = if inherited && tags.contains_key("sp") {
+        policy(tags.get("sp"))
+    } else {
+        policy(tags.get("p"))
+    };
+
+    // Strict alignment needs the exact From domain, relaxed only the same
+    // organizational domain
+    let aligns = |mode: Option<&String>, domain: &str| {
+        if mode.map(|m| m == "s").unwrap_or(false) {
+            domain == from
+        } else {
+            registrable_domain(domain) == org
+        }
+    };
+
+    let aligned = dkim_domains.iter().any(|domain| aligns(tags.get("adkim"), *domain)) ||
+        spf_domain.map(|domain| aligns(tags.get("aspf"), domain)).unwrap_or(false);
+
+    if aligned {
+        (AuthResult::Pass, policy)
+    } else {
+        (AuthResult::Fail, policy)
+    }
+}
+
+fn dmarc_record(resolver: &Resolver, domain: &str) -> std::result::Result<Option<HashMap<String, String>>, AuthResult> {
+    let records = resolver.txt(&format!("_dmarc.{}", domain))
+        .map_err(|_| AuthResult::TempError)?;
+
+    Ok(records.iter()
+        .filter(|r| r.starts_with("v=DMARC1"))
+        .map(|r| dkim::tags(r))
+        .next())
+}
+
+/// Verifies emails on threads of its own, so a slow name server costs an
+/// email its SPF, DKIM and DMARC results rather than its whole extraction.
+///
+/// Verifications that run past the timeout carry on in the background, their
+/// answers land in the resolver's cache for the next email from the domain.
+pub struct VerificationPool {
+    resolver: Arc<Resolver>,
+    threads: ThreadPool,
+    timeout: Duration,
+}
+
+impl VerificationPool {
+    pub fn new(resolver: Arc<Resolver>, config: &AuthenticationConfig) -> VerificationPool {
+        let threads = ThreadPool::new(Configuration::new()
+            .num_threads(std::cmp::max(config.lookup_threads, 1))
+            .thread_name(|i| format!("authentication-{}", i)))
+            .expect("Failed to start the authentication threads");
+
+        VerificationPool {
+            resolver,
+            threads,
+            timeout: config.timeout(),
+        }
+    }
+
+    pub fn verify(&self, email: EmailBytes) -> AuthenticationFeatures {
+        let (tx, rx) = mpsc::channel();
+        let resolver = self.resolver.clone();
+        let timeout = self.timeout;
+        let queued = Instant::now();
+
+        self.threads.spawn(move || {
+            // Nobody is waiting any more, don't let a backlog build up
+            if queued.elapsed() < timeout {
+                let _ = tx.send(AuthenticationFeatures::verify(&*resolver, &email));
+            }
+        });
+
+        rx.recv_timeout(timeout).unwrap_or_else(|_| AuthenticationFeatures::timed_out())
+    }
+}
+
+pub struct AuthenticationVerifier {
+    self_ref: AuthenticationVerifierActor,
+    system: SystemActor,
+    verification: Arc<VerificationPool>,
+}
+
+type AuthenticationFeaturesResponse = std::sync::Arc<Fn(Result<AuthenticationFeatures>) + Send + Sync + 'static>;
+
+#[derive_actor]
+impl AuthenticationVerifier {
+    /// Takes the raw bytes since DKIM signs the message exactly as it was sent
+    pub fn verify(&self, email: EmailBytes, res: AuthenticationFeaturesResponse) {
+        res(Ok(self.verification.verify(email)))
+    }
+}
+
+impl AuthenticationVerifier {
+    pub fn new(verification: Arc<VerificationPool>,
+               self_ref: AuthenticationVerifierActor,
+               system: SystemActor) -> AuthenticationVerifier {
+        AuthenticationVerifier {
+            self_ref,
+            system,
+            verification,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: AuthenticationVerifierMessage,
+                   t: Arc<T>)
+        where T: Fn(AuthenticationVerifierActor, SystemActor) -> AuthenticationVerifier + Send + Sync + 'static
+    {
+        match msg {
+            AuthenticationVerifierMessage::VerifyVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in authentication verifier".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resolver::ZoneFileResolver;
+
+    #[test]
+    fn dmarc_follows_spf_alignment() {
+        let mut zone = ZoneFileResolver::default();
+        zone.add("bank.com", "TXT", "v=spf1 ip4:192.0.2.0/24 -all");
+        zone.add("_dmarc.bank.com", "TXT", "v=DMARC1; p=reject");
+
+        let raw = b"Received: from mx (mx.bank.com [192.0.2.10])\r\n\
+From: Bank <alerts@mail.bank.com>\r\n\
+Return-Path: <bounce@bank.com>\r\n\
+\r\n\
+Hello\r\n";
+
+        let features = AuthenticationFeatures::verify(&zone, raw);
+        assert_eq!(features.results.spf, AuthResult::Pass);
+        assert_eq!(features.results.dkim, AuthResult::None);
+        assert_eq!(features.results.dmarc, AuthResult::Pass);
+        assert_eq!(features.results.dmarc_policy, DmarcPolicy::Reject);
+
+        let spoofed = b"Received: from mx (evil.example [203.0.113.5])\r\n\
+From: Bank <alerts@bank.com>\r\n\
+\r\n\
+Hello\r\n";
+
+        let features = AuthenticationFeatures::verify(&zone, spoofed);
+        assert_eq!(features.results.spf, AuthResult::Fail);
+        assert_eq!(features.results.dmarc, AuthResult::Fail);
+        assert_eq!(features.reasons, vec!["fails DMARC for bank.com".to_owned()]);
+    }
+
+    struct SlowResolver;
+
+    impl Resolver for SlowResolver {
+        fn txt(&self, _: &str) -> Result<Vec<String>> {
+            std::thread::sleep(Duration::from_millis(500));
+            Ok(Vec::new())
+        }
+
+        fn mx(&self, name: &str) -> Result<Vec<String>> {
+            self.txt(name)
+        }
+
+        fn ip(&self, _: &str) -> Result<Vec<IpAddr>> {
+            Ok(Vec::new())
+        }
+    }
+
+    #[test]
+    fn slow_lookups_are_a_temperror() {
+        let config = AuthenticationConfig {
+            timeout_ms: 20,
+            ..AuthenticationConfig::default()
+        };
+        let verification = VerificationPool::new(Arc::new(SlowResolver), &config);
+
+        let raw = b"Received: from mx (mx.bank.com [192.0.2.10])\r\n\
+From: Bank <alerts@bank.com>\r\n\
+\r\n\
+Hello\r\n";
+
+        let started = Instant::now();
+        let features = verification.verify(Arc::new(raw.to_vec()));
+
+        assert!(started.elapsed() < Duration::from_millis(200));
+        assert_eq!(features.results.spf, AuthResult::TempError);
+        assert_eq!(features.results.dmarc, AuthResult::TempError);
+    }
+
+    #[test]
+    fn co_uk_is_not_an_organization() {
+        let mut zone = ZoneFileResolver::default();
+        zone.add("evil.co.uk", "TXT", "v=spf1 ip4:203.0.113.0/24 -all");
+        zone.add("_dmarc.bank.co.uk", "TXT", "v=DMARC1; p=reject");
+
+        let spoofed = b"Received: from mx (mx.evil.co.uk [203.0.113.5])\r\n\
+From: Bank <alerts@mail.bank.co.uk>\r\n\
+Return-Path: <bounce@evil.co.uk>\r\n\
+\r\n\
+Hello\r\n";
+
+        let features = AuthenticationFeatures::verify(&zone, spoofed);
+        assert_eq!(features.results.spf, AuthResult::Pass);
+        assert_eq!(features.results.dmarc, AuthResult::Fail);
+        assert_eq!(features.results.dmarc_policy, DmarcPolicy::Reject);
+    }
+
+    #[test]
+    fn alignment_modes_apply_per_mechanism() {
+        let mut zone = ZoneFileResolver::default();
+        zone.add("_dmarc.bank.com", "TXT", "v=DMARC1; p=reject; adkim=s; aspf=r");
+        zone.add("_dmarc.strict-spf.com", "TXT", "v=DMARC1; p=reject; adkim=r; aspf=s");
+
+        // Relaxed SPF passes for a subdomain, strict DKIM doesn't
+        assert_eq!(evaluate_dmarc(&zone, "bank.com", &[], Some("bounce.bank.com")).0,
+                   AuthResult::Pass);
+        assert_eq!(evaluate_dmarc(&zone, "bank.com", &["mail.bank.com"], None).0,
+                   AuthResult::Fail);
+        assert_eq!(evaluate_dmarc(&zone, "bank.com", &["bank.com"], None).0,
+                   AuthResult::Pass);
+
+        assert_eq!(evaluate_dmarc(&zone, "strict-spf.com", &["mail.strict-spf.com"], None).0,
+                   AuthResult::Pass);
+        assert_eq!(evaluate_dmarc(&zone, "strict-spf.com", &[], Some("bounce.strict-spf.com")).0,
+                   AuthResult::Fail);
+    }
+}
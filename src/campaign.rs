@@ -0,0 +1,412 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::hash::Hasher;
+use std::sync::{Arc, Mutex};
+
+use lru_time_cache::LruCache;
+use rand;
+use twox_hash::XxHash;
+
+use errors::*;
+use email::*;
+use headers::email_timestamp;
+use config::CampaignConfig;
+use verdict::{Label, Verdict};
+use feature_vector::FeatureColumns;
+
+/// Words per shingle
+const SHINGLE_WORDS: usize = 4;
+/// LSH splits each MinHash signature into BANDS bands of ROWS hashes. Emails
+/// sharing any band are compared, which finds pairs down to a similarity of
+/// about (1 / BANDS) ^ (1 / ROWS), 0.5 here.
+const BANDS: usize = 16;
+const ROWS: usize = 4;
+const SIGNATURE_LENGTH: usize = BANDS * ROWS;
+
+/// The extractor only fills in `signature` and `sent_at`, which depend on
+/// nothing but the email. The rest changes as more of the campaign arrives, so
+/// it's filled in by CampaignIndex::observe whenever the email is classified.
+///
+/// Ages are measured between the emails' own timestamps rather than when they
+/// were observed, so a corpus extracted in one run gets the same ages it
+/// would have had arriving over days.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CampaignFeatures {
+    /// MinHash signature of the visible text, empty without any words
+    #[serde(default)]
+    pub signature: Vec<u64>,
+    /// Seconds since the epoch when the email arrived, see `email_timestamp`
+    #[serde(default)]
+    pub sent_at: Option<i64>,
+    /// The campaign in this process's CampaignIndex, None for emails without text
+    pub campaign: Option<u64>,
+    /// Emails seen in the campaign so far, including this one
+    pub size: usize,
+    /// The earliest timestamp of any email seen in the campaign
+    pub first_sent: Option<i64>,
+    /// How long after the campaign's earliest email this one was sent, in
+    /// seconds. 0 when either has no timestamp.
+    pub age_secs: u64,
+}
+
+impl FeatureColumns for CampaignFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["campaign_size", "campaign_age_secs"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.size as f32, self.age_secs as f32]
+    }
+}
+
+struct Campaign {
+    /// The first email's signature, later emails are compared against it
+    signature: Vec<u64>,
+    size: usize,
+    first_sent: Option<i64>,
+    verdict: Option<Verdict>,
+}
+
+struct IndexState {
+    campaigns: LruCache<u64, Campaign>,
+    /// (band, hash of the band's rows) to the campaign last seen with it
+    buckets: LruCache<(usize, u64), u64>,
+    /// Email hashes to the campaign they were counted in
+    emails: LruCache<Vec<u8>, u64>,
+}
+
+/// Groups near duplicate emails into campaigns, by MinHash over word shingles
+/// of their visible text with LSH to find candidates.
+///
+/// One index is shared by every worker. Campaigns that haven't been seen for
+/// a while are forgotten once `max_campaigns` is reached.
+pub struct CampaignIndex {
+    similarity: f32,
+    reuse_verdict_after: usize,
+    state: Mutex<IndexState>,
+}
+
+impl CampaignIndex {
+    pub fn new(config: &CampaignConfig) -> CampaignIndex {
+        let max_campaigns = std::cmp::max(config.max_campaigns, 1);
+        CampaignIndex {
+            similarity: config.similarity,
+            reuse_verdict_after: config.reuse_verdict_after,
+            state: Mutex::new(IndexState {
+                campaigns: LruCache::with_capacity(max_campaigns),
+                buckets: LruCache::with_capacity(max_campaigns * BANDS),
+                emails: LruCache::with_capacity(max_campaigns),
+            }),
+        }
+    }
+
+    /// Adds an email to the campaign its `extracted` signature is most similar
+    /// to, or starts a new campaign for it. An email seen before, by its
+    /// `hash_email`, isn't counted again.
+    pub fn observe(&self, email_hash: &[u8], extracted: &CampaignFeatures) -> CampaignFeatures {
+        let signature = &extracted.signature[..];
+        let sent_at = extracted.sent_at;
+        if signature.is_empty() {
+            return CampaignFeatures {
+                sent_at,
+                ..CampaignFeatures::default()
+            };
+        }
+
+        let features = |id: u64, campaign: &Campaign| CampaignFeatures {
+            signature: signature.to_vec(),
+            sent_at,
+            campaign: Some(id),
+            size: campaign.size,
+            first_sent: campaign.first_sent,
+            age_secs: match (sent_at, campaign.first_sent) {
+                (Some(sent), Some(first)) if sent > first => (sent - first) as u64,
+                _ => 0,
+            },
+        };
+
+        let mut state = self.state.lock().unwrap();
+
+        let seen = state.emails.get(&email_hash.to_vec()).cloned();
+        if let Some(id) = seen {
+            if let Some(campaign) = state.campaigns.get(&id) {
+                return features(id, campaign);
+            }
+        }
+
+        let bands: Vec<(usize, u64)> = signature.chunks(ROWS)
+            .enumerate()
+            .map(|(band, rows)| (band, band_hash(rows)))
+            .collect();
+
+        let mut candidates: Vec<u64> = bands.iter()
+            .filter_map(|band| state.buckets.get(band).cloned())
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+
+        let mut best: Option<(u64, f32)> = None;
+        for id in candidates {
+            if let Some(campaign) = state.campaigns.get(&id) {
+                let s = similarity(signature, &campaign.signature);
+                if s >= self.similarity && best.map(|(_, b)| s > b).unwrap_or(true) {
+                    best = Some((id, s));
+                }
+            }
+        }
+
+        let (id, campaign) = match best.and_then(|(id, _)| state.campaigns.remove(&id).map(|c| (id, c))) {
+            Some((id, mut campaign)) => {
+                campaign.size += 1;
+                // Emails don't arrive in the order they were sent
+                campaign.first_sent = match (campaign.first_sent, sent_at) {
+                    (Some(first), Some(sent)) => Some(std::cmp::min(first, sent)),
+                    (first, sent) => first.or(sent),
+                };
+                (id, campaign)
+            }
+            // Random ids so one logged before a restart can't be mistaken
+            // for a different campaign after it
+            None => (rand::random(), Campaign {
+                signature: signature.to_vec(),
+                size: 1,
+                first_sent: sent_at,
+                verdict: None,
+            }),
+        };
+
+        let features = features(id, &campaign);
+
+        state.campaigns.insert(id, campaign);
+        state.emails.insert(email_hash.to_vec(), id);
+        for band in bands {
+            state.buckets.insert(band, id);
+        }
+
+        features
+    }
+
+    /// The verdict last recorded for the email's campaign, if it's big enough
+    /// for that verdict to be reused instead of classifying the email again.
+    ///
+    /// Only Spam verdicts are reused. The campaign is matched on visible text
+    /// alone, so a copy whose links or attachments were swapped for malicious
+    /// ones mustn't inherit a clean verdict.
+    pub fn prior_verdict(&self, features: &CampaignFeatures) -> Option<Verdict> {
+        let id = match features.campaign {
+            Some(id) if self.reuse_verdict_after > 0 => id,
+            _ => return None,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        match state.campaigns.get(&id) {
+            Some(campaign) if campaign.size >= self.reuse_verdict_after => {
+                campaign.verdict.clone().and_then(|verdict| match verdict.label {
+                    Label::Spam => Some(verdict),
+                    _ => None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn record_verdict(&self, features: &CampaignFeatures, verdict: &Verdict) {
+        if let Some(id) = features.campaign {
+            let mut state = self.state.lock().unwrap();
+            if let Some(mut campaign) = state.campaigns.remove(&id) {
+                campaign.verdict = Some(verdict.clone());
+                state.campaigns.insert(id, campaign);
+            }
+        }
+    }
+}
+
+/// Lowercased words, with digits masked since campaigns vary order numbers,
+/// tracking ids and amounts between copies
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| {
+            w.chars()
+                .flat_map(|c| c.to_lowercase())
+                .map(|c| if c.is_numeric() { '0' } else { c })
+                .collect()
+        })
+        .collect()
+}
+
+/// The MinHash signature of the text's word shingles, None without any words
+pub fn signature(text: &str) -> Option<Vec<u64>> {
+    let words = words(text);
+    if words.is_empty() {
+        return None;
+    }
+
+    let shingles: Vec<u64> = words.windows(std::cmp::min(SHINGLE_WORDS, words.len()))
+        .map(|shingle| {
+            let mut hasher = XxHash::default();
+            for word in shingle {
+                hasher.write(word.as_bytes());
+                hasher.write_u8(0);
+            }
+            hasher.finish()
+        })
+        .collect();
+
+    Some((0..SIGNATURE_LENGTH)
+        .map(|i| {
+            let seed = (i as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            shingles.iter()
+                .map(|shingle| mix(shingle.wrapping_add(seed)))
+                .min()
+                .unwrap_or(0)
+        })
+        .collect())
+}
+
+/// SplitMix64's finalizer, turning one shingle hash into one per signature row
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn band_hash(rows: &[u64]) -> u64 {
+    let mut hasher = XxHash::default();
+    for row in rows {
+        hasher.write_u64(*row);
+    }
+    hasher.finish()
+}
+
+/// Estimated Jaccard similarity of the shingles behind two signatures
+fn similarity(a: &[u64], b: &[u64]) -> f32 {
+    let same = a.iter().zip(b).filter(|&(x, y)| x == y).count();
+    same as f32 / SIGNATURE_LENGTH as f32
+}
+
+pub struct CampaignDetector {
+    self_ref: CampaignDetectorActor,
+    system: SystemActor,
+}
+
+type CampaignFeaturesResponse = std::sync::Arc<Fn(Result<CampaignFeatures>) + Send + Sync + 'static>;
+
+#[derive_actor]
+impl CampaignDetector {
+    /// `text` is what a reader would see, hidden padding varies too much
+    /// between copies to be compared
+    pub fn analyze(&self, email: ParsedEmail, text: String, res: CampaignFeaturesResponse) {
+        res(Ok(CampaignFeatures {
+            signature: signature(&text).unwrap_or_default(),
+            sent_at: email_timestamp(&email),
+            ..CampaignFeatures::default()
+        }))
+    }
+}
+
+impl CampaignDetector {
+    pub fn new(self_ref: CampaignDetectorActor,
+               system: SystemActor) -> CampaignDetector {
+        CampaignDetector {
+            self_ref,
+            system,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: CampaignDetectorMessage,
+                   t: Arc<T>)
+        where T: Fn(CampaignDetectorActor, SystemActor) -> CampaignDetector + Send + Sync + 'static
+    {
+        match msg {
+            CampaignDetectorMessage::AnalyzeVariant { email, text, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in campaign detector".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use verdict::Thresholds;
+
+    #[test]
+    fn near_duplicates_share_a_campaign() {
+        let index = CampaignIndex::new(&CampaignConfig::default());
+
+        let copy = |name: &str, order: u32| format!(
+            "Dear {}, your parcel with tracking number {} could not be delivered \
+             because the shipping address on file is incomplete. To arrange a new \
+             delivery date please confirm your address and pay the outstanding \
+             customs fee of 1.99 within the next 48 hours using the secure link \
+             below. If we do not hear from you the parcel will be returned to the \
+             sender and you will lose your order. Thank you for choosing our \
+             courier service, we look forward to delivering your parcel soon.",
+            name, order);
+
+        let observe = |text: &str, sent_at: i64| {
+            index.observe(text.as_bytes(), &CampaignFeatures {
+                signature: signature(text).expect("signature"),
+                sent_at: Some(sent_at),
+                ..CampaignFeatures::default()
+            })
+        };
+
+        let first = observe(&copy("Alice", 10_234_553), 1_500_000_000);
+        let second = observe(&copy("Bob", 88_120_004), 1_500_003_600);
+        let other = observe("Minutes from Tuesday's planning meeting are attached, \
+                             let me know if I missed anything before Friday.", 1_500_000_000);
+
+        assert_eq!(first.size, 1);
+        assert_eq!(first.age_secs, 0);
+        assert_eq!(second.campaign, first.campaign);
+        assert_eq!(second.size, 2);
+        assert_eq!(second.age_secs, 3600);
+        assert!(other.campaign != first.campaign);
+        assert_eq!(other.size, 1);
+
+        // Retrying an email doesn't grow its campaign
+        let retried = observe(&copy("Bob", 88_120_004), 1_500_003_600);
+        assert_eq!(retried.campaign, first.campaign);
+        assert_eq!(retried.size, 2);
+        assert!(index.observe(b"no text", &CampaignFeatures::default()).campaign.is_none());
+
+        // A copy sent earlier than any seen so far moves the campaign's start
+        let earlier = observe(&copy("Carol", 55_555_555), 1_499_996_400);
+        assert_eq!(earlier.first_sent, Some(1_499_996_400));
+        assert_eq!(observe(&copy("Dave", 1), 1_500_003_600).age_secs, 7200);
+    }
+
+    #[test]
+    fn only_spam_verdicts_are_reused() {
+        let index = CampaignIndex::new(&CampaignConfig {
+            reuse_verdict_after: 1,
+            ..CampaignConfig::default()
+        });
+
+        let features = index.observe(b"email", &CampaignFeatures {
+            signature: signature("Your invoice is attached").unwrap(),
+            ..CampaignFeatures::default()
+        });
+        let verdict = |probability| {
+            Verdict::new(probability, &Thresholds::default(), "v1".to_owned(), Duration::from_millis(1))
+        };
+
+        index.record_verdict(&features, &verdict(0.1));
+        assert!(index.prior_verdict(&features).is_none());
+
+        index.record_verdict(&features, &verdict(0.9));
+        assert_eq!(index.prior_verdict(&features).map(|v| v.label), Some(Label::Spam));
+    }
+}
@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use errors::*;
+use config::*;
+
+pub fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("spam_detection")
+        .about("Determines whether emails are malicious")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .takes_value(true)
+            .help("TOML config file, defaults to $SPAM_DETECTION_CONFIG or ./spam_detection.toml"))
+        .arg(Arg::with_name("workers")
+            .long("workers")
+            .takes_value(true)
+            .help("Number of detection workers"))
+        .arg(Arg::with_name("file-readers")
+            .long("file-readers")
+            .takes_value(true)
+            .help("Number of file reader workers"))
+        .arg(Arg::with_name("timeout-ms")
+            .long("timeout-ms")
+            .takes_value(true)
+            .help("Actor timeout in milliseconds"))
+        .arg(Arg::with_name("extraction-timeout-ms")
+            .long("extraction-timeout-ms")
+            .takes_value(true)
+            .help("Feature extraction timeout per email in milliseconds"))
+        .arg(Arg::with_name("backend")
+            .long("backend")
+            .takes_value(true)
+            .possible_values(&["python", "rust", "rules"])
+            .help("Model backend"))
+        .arg(Arg::with_name("model")
+            .long("model")
+            .takes_value(true)
+            .help("Path to the trained model"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .takes_value(true)
+            .possible_values(&["text", "json", "csv"])
+            .help("Output format"))
+        .subcommand(SubCommand::with_name("scan")
+            .about("Classifies a single .eml file or every .eml file under a directory")
+            .arg(Arg::with_name("path").required(true)))
+        .subcommand(SubCommand::with_name("extract")
+            .alias("train-extract")
+            .about("Writes a labeled feature CSV for a corpus with spam/ and ham/ directories, or a manifest")
+            .arg(Arg::with_name("corpus").required(true))
+            .arg(Arg::with_name("output").required(true)))
+        .subcommand(SubCommand::with_name("export-features")
+            .about("Writes a labeled feature CSV for a corpus from the feature store, without extracting")
+            .arg(Arg::with_name("corpus").required(true))
+            .arg(Arg::with_name("output").required(true)))
+        .subcommand(SubCommand::with_name("train")
+            .about("Trains the native model from a labeled feature CSV")
+            .arg(Arg::with_name("data").required(true))
+            .arg(Arg::with_name("output").required(true)))
+        .subcommand(SubCommand::with_name("evaluate")
+            .about("Classifies a labeled corpus and reports precision and recall")
+            .arg(Arg::with_name("corpus").required(true)))
+        .subcommand(SubCommand::with_name("serve")
+            .about("Classifies the .eml paths read from stdin, one per line"))
+        .subcommand(SubCommand::with_name("explain")
+            .about("Prints the features and verdict for a single email")
+            .arg(Arg::with_name("eml").required(true)))
+        .subcommand(SubCommand::with_name("signatures")
+            .about("Prints fuzzy hash signatures of a known bad .eml file or every .eml file under a directory")
+            .arg(Arg::with_name("path").required(true)))
+}
+
+/// Loads the config file and applies any flags on top of it
+pub fn load_config(matches: &ArgMatches) -> Result<Config> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::load(Path::new(path))?,
+        None => Config::from_env()?,
+    };
+
+    if let Some(count) = matches.value_of("workers") {
+        config.workers.count = count.parse().chain_err(|| "--workers must be a number")?;
+    }
+
+    if let Some(count) = matches.value_of("file-readers") {
+        config.workers.file_readers = count.parse().chain_err(|| "--file-readers must be a number")?;
+    }
+
+    if let Some(timeout) = matches.value_of("timeout-ms") {
+        config.timeouts.actor_ms = timeout.parse().chain_err(|| "--timeout-ms must be a number")?;
+    }
+
+    if let Some(timeout) = matches.value_of("extraction-timeout-ms") {
+        config.timeouts.extraction_ms = timeout.parse()
+            .chain_err(|| "--extraction-timeout-ms must be a number")?;
+    }
+
+    let model = matches.value_of("model").map(PathBuf::from);
+    match (matches.value_of("backend"), model) {
+        (Some(backend), model) => config.model = ModelConfig::for_backend(backend, model)?,
+        (None, Some(model)) => config.model = config.model.with_model(model),
+        (None, None) => (),
+    }
+
+    if let Some(format) = matches.value_of("format") {
+        config.output = format.parse()?;
+    }
+
+    Ok(config)
+}
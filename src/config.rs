@@ -0,0 +1,520 @@
+use std;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use toml;
+
+use errors::*;
+use verdict::Thresholds;
+
+/// Read when SPAM_DETECTION_CONFIG isn't set
+const DEFAULT_CONFIG_PATH: &'static str = "./spam_detection.toml";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub model: ModelConfig,
+    #[serde(default)]
+    pub thresholds: Thresholds,
+    #[serde(default)]
+    pub batching: BatchConfig,
+    /// Serve the newest model in a directory, switching when a new one appears
+    pub reload: Option<ReloadConfig>,
+    /// A candidate model to evaluate against the primary one
+    pub shadow: Option<ShadowConfig>,
+    #[serde(default)]
+    pub workers: WorkerConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    /// How verdicts are printed
+    #[serde(default)]
+    pub output: OutputFormat,
+    #[serde(default)]
+    pub authentication: AuthenticationConfig,
+    #[serde(default)]
+    pub impersonation: ImpersonationConfig,
+    /// Keep extracted features on disk and reuse them for the same email
+    pub feature_store: Option<FeatureStoreConfig>,
+    #[serde(default)]
+    pub prediction_cache: PredictionCacheConfig,
+    #[serde(default)]
+    pub campaigns: CampaignConfig,
+    #[serde(default)]
+    pub fuzzy_hash: FuzzyHashConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct WorkerConfig {
+    /// SpamDetectionService workers, each with its own extractor and model
+    pub count: usize,
+    pub file_readers: usize,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        WorkerConfig {
+            count: 22,
+            file_readers: 16,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// How long an actor may go without a message before `on_timeout`
+    pub actor_ms: u64,
+    /// How long feature extraction for a single email may take
+    pub extraction_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> TimeoutConfig {
+        TimeoutConfig {
+            actor_ms: 30_000,
+            extraction_ms: 50,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    pub fn actor(&self) -> Duration {
+        Duration::from_millis(self.actor_ms)
+    }
+
+    pub fn extraction(&self) -> Duration {
+        Duration::from_millis(self.extraction_ms)
+    }
+}
+
+/// Where SPF, DKIM and DMARC lookups are answered from, and whose
+/// Authentication-Results to believe
+///
+/// ```toml
+/// [authentication]
+/// zone_file = "./zones.txt"
+/// trusted_authserv_ids = ["mx.example.com"]
+/// timeout_ms = 30
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AuthenticationConfig {
+    /// Answer from this file instead of the system's name servers, see ZoneFileResolver
+    pub zone_file: Option<PathBuf>,
+    /// Authentication-Results and ARC headers are only believed when added by
+    /// one of these servers, anything else may have been written by the sender
+    pub trusted_authserv_ids: Vec<String>,
+    /// How long to wait for SPF, DKIM and DMARC before reporting them as
+    /// temperror. Keep it under `timeouts.extraction_ms`.
+    pub timeout_ms: u64,
+    /// How long a single DNS query may take
+    pub lookup_timeout_ms: u64,
+    /// Threads verifying emails, shared by every worker
+    pub lookup_threads: usize,
+    /// DNS answers are cached, per type, for this many names
+    pub cache_capacity: usize,
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for AuthenticationConfig {
+    fn default() -> AuthenticationConfig {
+        AuthenticationConfig {
+            zone_file: None,
+            trusted_authserv_ids: Vec::new(),
+            timeout_ms: 30,
+            lookup_timeout_ms: 2_000,
+            lookup_threads: 8,
+            cache_capacity: 10_000,
+            cache_ttl_secs: 300,
+        }
+    }
+}
+
+impl AuthenticationConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn lookup_timeout(&self) -> Duration {
+        Duration::from_millis(self.lookup_timeout_ms)
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs)
+    }
+}
+
+/// The brands whose names and domains lookalikes are checked against
+///
+/// ```toml
+/// [impersonation]
+/// max_distance = 1
+///
+/// [[impersonation.brands]]
+/// name = "PayPal"
+/// domains = ["paypal.com", "paypal.me"]
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct ImpersonationConfig {
+    pub brands: Vec<BrandConfig>,
+    /// The most edits a domain may be from a brand's and still be a lookalike
+    pub max_distance: usize,
+}
+
+impl Default for ImpersonationConfig {
+    fn default() -> ImpersonationConfig {
+        ImpersonationConfig {
+            brands: Vec::new(),
+            max_distance: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct BrandConfig {
+    /// As it would appear in a display name, and in verdict reasons
+    pub name: String,
+    /// The domains the brand really sends from and links to
+    pub domains: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat::Text
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            other => bail!(ErrorKind::UnrecoverableError(
+                format!("Unknown output format: {}", other).into()))
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct FeatureStoreConfig {
+    pub dir: PathBuf,
+}
+
+/// Where verdicts for previously seen emails are kept
+///
+/// ```toml
+/// [prediction_cache]
+/// backend = "redis"
+/// redis_url = "redis://127.0.0.1/"
+/// ttl_secs = 60
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PredictionCacheConfig {
+    pub backend: CacheBackend,
+    /// Verdicts kept by the memory backend, redis evicts by its own policy
+    pub capacity: usize,
+    /// Separately locked parts of the memory backend, or connections to
+    /// redis, shared by every worker
+    pub shards: usize,
+    pub ttl_secs: u64,
+    pub redis_url: String,
+    /// How long to wait for redis to connect or answer before it's a miss
+    pub redis_timeout_ms: u64,
+    /// Log the hit and miss counts after this many lookups
+    pub report_every: usize,
+}
+
+impl Default for PredictionCacheConfig {
+    fn default() -> PredictionCacheConfig {
+        PredictionCacheConfig {
+            backend: CacheBackend::Memory,
+            capacity: 10_000,
+            shards: 16,
+            ttl_secs: 60,
+            redis_url: "redis://127.0.0.1/".to_owned(),
+            redis_timeout_ms: 100,
+            report_every: 1_000,
+        }
+    }
+}
+
+impl PredictionCacheConfig {
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+
+    pub fn redis_timeout(&self) -> Duration {
+        Duration::from_millis(self.redis_timeout_ms)
+    }
+}
+
+/// How near duplicate emails are grouped into campaigns
+///
+/// ```toml
+/// [campaigns]
+/// similarity = 0.6
+/// reuse_verdict_after = 20
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CampaignConfig {
+    /// Estimated share of word shingles two emails need in common to be in the
+    /// same campaign, from 0.0 to 1.0
+    pub similarity: f32,
+    /// Campaigns remembered, the least recently seen are forgotten first
+    pub max_campaigns: usize,
+    /// Give an email its campaign's last verdict instead of classifying it
+    /// once the campaign has this many emails, 0 to always classify
+    pub reuse_verdict_after: usize,
+}
+
+impl Default for CampaignConfig {
+    fn default() -> CampaignConfig {
+        CampaignConfig {
+            similarity: 0.6,
+            max_campaigns: 100_000,
+            reuse_verdict_after: 0,
+        }
+    }
+}
+
+/// Fuzzy hashes of known bad bodies and attachments to compare emails against
+///
+/// ```toml
+/// [fuzzy_hash]
+/// signatures = "./signatures.txt"
+/// max_distance = 100
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct FuzzyHashConfig {
+    /// See SignatureDb for the format, nothing matches without it
+    pub signatures: Option<PathBuf>,
+    /// The furthest a digest may be from a signature to be given as a reason
+    pub max_distance: u32,
+}
+
+impl Default for FuzzyHashConfig {
+    fn default() -> FuzzyHashConfig {
+        FuzzyHashConfig {
+            signatures: None,
+            max_distance: 100,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheBackend {
+    Memory,
+    Redis,
+}
+
+/// The shadow model sees every email the primary model does, but its verdicts
+/// are only logged and compared, never returned
+#[derive(Clone, Debug, Deserialize)]
+pub struct ShadowConfig {
+    pub model: ModelConfig,
+    pub reload: Option<ReloadConfig>,
+    /// Log a summary of the comparison after this many emails
+    #[serde(default = "default_report_every")]
+    pub report_every: usize,
+}
+
+fn default_report_every() -> usize {
+    100
+}
+
+/// How the Model actor groups emails into requests to its backend
+///
+/// A batch is sent once it holds `max_size` emails, or `max_wait_ms` after
+/// its first email arrived, whichever is first.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    pub max_size: usize,
+    pub max_wait_ms: u64,
+}
+
+impl Default for BatchConfig {
+    fn default() -> BatchConfig {
+        BatchConfig {
+            max_size: 32,
+            max_wait_ms: 5,
+        }
+    }
+}
+
+impl BatchConfig {
+    pub fn max_wait(&self) -> Duration {
+        Duration::from_millis(self.max_wait_ms)
+    }
+}
+
+/// Which classifier backs the Model actor
+///
+/// ```toml
+/// [model]
+/// backend = "rust"
+/// model = "./rust_model.json"
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum ModelConfig {
+    Python {
+        script: PathBuf,
+        /// The pickled model, prediction_service.py picks its own default if unset
+        model: Option<PathBuf>,
+        #[serde(default)]
+        supervisor: SupervisorConfig,
+    },
+    Rust { model: PathBuf },
+    Rules,
+}
+
+impl ModelConfig {
+    /// The named backend, serving the model at `model` if given
+    pub fn for_backend(backend: &str, model: Option<PathBuf>) -> Result<ModelConfig> {
+        let config = match backend {
+            "python" => ModelConfig::default(),
+            "rust" => match model.clone() {
+                Some(model) => ModelConfig::Rust { model },
+                None => bail!(ErrorKind::UnrecoverableError("The rust backend needs a model path".into()))
+            },
+            "rules" => ModelConfig::Rules,
+            other => bail!(ErrorKind::UnrecoverableError(
+                format!("Unknown model backend: {}", other).into()))
+        };
+
+        Ok(match model {
+            Some(model) => config.with_model(model),
+            None => config,
+        })
+    }
+
+    /// The same backend serving a different model file
+    pub fn with_model(self, path: PathBuf) -> ModelConfig {
+        match self {
+            ModelConfig::Python { script, supervisor, .. } => {
+                ModelConfig::Python { script, model: Some(path), supervisor }
+            }
+            ModelConfig::Rust { .. } => ModelConfig::Rust { model: path },
+            ModelConfig::Rules => ModelConfig::Rules,
+        }
+    }
+}
+
+impl Default for ModelConfig {
+    fn default() -> ModelConfig {
+        ModelConfig::Python {
+            script: "./model_service/service/prediction_service.py".into(),
+            model: None,
+            supervisor: SupervisorConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        toml::from_str(&contents)
+            .chain_err(|| format!("Invalid config at {:#?}", path))
+    }
+
+    /// Loads the file named by SPAM_DETECTION_CONFIG, or ./spam_detection.toml if
+    /// it exists, falling back to the defaults
+    pub fn from_env() -> Result<Config> {
+        match std::env::var("SPAM_DETECTION_CONFIG") {
+            Ok(path) => Config::load(Path::new(&path)),
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::load(Path::new(DEFAULT_CONFIG_PATH)),
+            Err(_) => Ok(Config::default()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReloadConfig {
+    pub dir: PathBuf,
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+    #[serde(default = "default_smoke_test_timeout_ms")]
+    pub smoke_test_timeout_ms: u64,
+}
+
+fn default_poll_interval_ms() -> u64 {
+    30_000
+}
+
+fn default_smoke_test_timeout_ms() -> u64 {
+    10_000
+}
+
+impl ReloadConfig {
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_millis(self.poll_interval_ms)
+    }
+
+    pub fn smoke_test_timeout(&self) -> Duration {
+        Duration::from_millis(self.smoke_test_timeout_ms)
+    }
+}
+
+/// How a model service child process is watched and restarted
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    pub health_check_interval_ms: u64,
+    /// Consecutive failed health checks before the child is restarted
+    pub max_failed_checks: usize,
+    /// Health checks to wait through, with exponential delay, for a new child to come up
+    pub health_check_retries: usize,
+    pub startup_attempts: usize,
+    pub min_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> SupervisorConfig {
+        SupervisorConfig {
+            health_check_interval_ms: 5_000,
+            max_failed_checks: 3,
+            health_check_retries: 15,
+            startup_attempts: 3,
+            min_backoff_ms: 100,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+impl SupervisorConfig {
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_millis(self.health_check_interval_ms)
+    }
+
+    pub fn min_backoff(&self) -> Duration {
+        Duration::from_millis(self.min_backoff_ms)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+}
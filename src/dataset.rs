@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+use errors::*;
+use feature_vector::parse_header;
+
+/// A labeled feature CSV, as consumed by model_generator.py
+///
+/// The last column named 'label' holds the class, every other column is a feature.
+pub struct Dataset {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<f32>>,
+    pub labels: Vec<f32>,
+}
+
+impl Dataset {
+    pub fn from_csv(path: &Path) -> Result<Dataset> {
+        let file = File::open(path)?;
+        let mut lines = BufReader::new(file).lines();
+
+        let header = match lines.next() {
+            Some(header) => header?,
+            None => bail!(ErrorKind::UnrecoverableError(
+                format!("Empty training data at {:#?}", path).into()))
+        };
+
+        let mut columns = parse_header(&header);
+        let label_index = match columns.iter().position(|c| c == "label") {
+            Some(index) => index,
+            None => bail!(ErrorKind::UnrecoverableError(
+                format!("Training data at {:#?} has no 'label' column", path).into()))
+        };
+        columns.remove(label_index);
+
+        let mut rows = Vec::new();
+        let mut labels = Vec::new();
+
+        for (line_no, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut row = Vec::with_capacity(columns.len());
+            for (i, cell) in line.split(',').enumerate() {
+                if i == label_index {
+                    labels.push(Dataset::parse_label(cell)?);
+                } else {
+                    let value = cell.trim().parse::<f32>()
+                        .chain_err(|| format!("Invalid value '{}' on line {}", cell, line_no + 2))?;
+                    row.push(value);
+                }
+            }
+
+            if row.len() != columns.len() {
+                bail!(ErrorKind::UnrecoverableError(
+                    format!("Line {} has {} values, expected {}", line_no + 2, row.len(), columns.len()).into()))
+            }
+
+            rows.push(row);
+        }
+
+        Ok(Dataset {
+            columns,
+            rows,
+            labels,
+        })
+    }
+
+    /// Spam is 1.0, ham is 0.0
+    pub fn parse_label(label: &str) -> Result<f32> {
+        match label.trim() {
+            "True" | "true" | "1" | "spam" => Ok(1.0),
+            "False" | "false" | "0" | "ham" => Ok(0.0),
+            other => bail!(ErrorKind::UnrecoverableError(
+                format!("Unknown label: {}", other).into()))
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+
+use base64;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::{sha1, sha256};
+use openssl::sign::Verifier;
+
+use resolver::Resolver;
+use authentication::AuthResult;
+
+/// The raw header fields, folding intact, and the body of a message.
+///
+/// Both stay bytes: signers sign whatever bytes they sent, which needn't be
+/// UTF-8, and any lossy conversion would break their signatures.
+pub fn split_message(raw: &[u8]) -> (Vec<Vec<u8>>, &[u8]) {
+    let mut fields: Vec<Vec<u8>> = Vec::new();
+    let mut offset = 0;
+
+    while offset < raw.len() {
+        let end = match raw[offset..].iter().position(|&b| b == b'\n') {
+            Some(i) => offset + i + 1,
+            None => raw.len(),
+        };
+
+        let line = trim_line_ending(&raw[offset..end]);
+        offset = end;
+
+        if line.is_empty() {
+            break;
+        }
+
+        if is_wsp(line[0]) {
+            if let Some(field) = fields.last_mut() {
+                field.extend_from_slice(b"\r\n");
+                field.extend_from_slice(line);
+                continue;
+            }
+        }
+
+        fields.push(line.to_vec());
+    }
+
+    (fields, &raw[offset..])
+}
+
+fn is_wsp(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let end = line.iter().rposition(|&b| b != b'\r' && b != b'\n').map(|i| i + 1).unwrap_or(0);
+    &line[..end]
+}
+
+fn trim_wsp(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|&b| !is_wsp(b)).unwrap_or(s.len());
+    let end = s.iter().rposition(|&b| !is_wsp(b)).map(|i| i + 1).unwrap_or(start);
+    &s[start..end]
+}
+
+fn field_name(field: &[u8]) -> &[u8] {
+    match field.iter().position(|&b| b == b':') {
+        Some(i) => trim_wsp(&field[..i]),
+        None => trim_wsp(field),
+    }
+}
+
+fn field_value(field: &[u8]) -> &[u8] {
+    match field.iter().position(|&b| b == b':') {
+        Some(i) => &field[i + 1..],
+        None => &[],
+    }
+}
+
+/// `k=v; k=v` tag lists, as used by DKIM-Signature and key records
+pub fn tags(value: &str) -> HashMap<String, String> {
+    value.split(';')
+        .filter_map(|tag| {
+            let mut kv = tag.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) => {
+                    let v: String = v.split_whitespace().collect();
+                    Some((k.trim().to_owned(), v))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+fn canonicalization(c: Option<&String>) -> (Canonicalization, Canonicalization) {
+    let parse = |s: &str| if s == "relaxed" { Canonicalization::Relaxed } else { Canonicalization::Simple };
+    match c {
+        Some(c) => {
+            let mut parts = c.splitn(2, '/');
+            let header = parts.next().map(|s| parse(s)).unwrap_or(Canonicalization::Simple);
+            let body = parts.next().map(|s| parse(s)).unwrap_or(Canonicalization::Simple);
+            (header, body)
+        }
+        None => (Canonicalization::Simple, Canonicalization::Simple),
+    }
+}
+
+/// Runs of spaces and tabs become a single space, line breaks are kept
+fn collapse_whitespace(s: &[u8]) -> Vec<u8> {
+    let mut collapsed = Vec::with_capacity(s.len());
+    for &b in s {
+        if !is_wsp(b) {
+            collapsed.push(b);
+        } else if collapsed.last() != Some(&b' ') {
+            collapsed.push(b' ');
+        }
+    }
+    collapsed
+}
+
+fn canonical_header(field: &[u8], c: Canonicalization) -> Vec<u8> {
+    let mut canonical = match c {
+        Canonicalization::Simple => field.to_vec(),
+        Canonicalization::Relaxed => {
+            let unfolded: Vec<u8> = field_value(field).iter()
+                .cloned()
+                .filter(|&b| b != b'\r' && b != b'\n')
+                .collect();
+
+            let mut canonical = field_name(field).to_ascii_lowercase();
+            canonical.push(b':');
+            canonical.extend_from_slice(trim_wsp(&collapse_whitespace(&unfolded)));
+            canonical
+        }
+    };
+    canonical.extend_from_slice(b"\r\n");
+    canonical
+}
+
+fn canonical_body(body: &[u8], c: Canonicalization) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = body.split(|&b| b == b'\n')
+        .map(|line| match line.last() {
+            Some(&b'\r') => &line[..line.len() - 1],
+            _ => line,
+        })
+        .map(|line| match c {
+            Canonicalization::Simple => line.to_vec(),
+            Canonicalization::Relaxed => {
+                let end = line.iter().rposition(|&b| !is_wsp(b)).map(|i| i + 1).unwrap_or(0);
+                collapse_whitespace(&line[..end])
+            }
+        })
+        .collect();
+
+    while lines.last().map(|l| l.is_empty()).unwrap_or(false) {
+        lines.pop();
+    }
+
+    if lines.is_empty() {
+        return match c {
+            Canonicalization::Simple => b"\r\n".to_vec(),
+            Canonicalization::Relaxed => Vec::new(),
+        };
+    }
+
+    let mut canonical = Vec::with_capacity(body.len() + 2);
+    for line in lines {
+        canonical.extend_from_slice(&line);
+        canonical.extend_from_slice(b"\r\n");
+    }
+    canonical
+}
+
+/// The signature header with the value of its b= tag removed, as it was
+/// when it was signed
+fn without_signature(field: &[u8]) -> Vec<u8> {
+    let colon = field.iter().position(|&b| b == b':').map(|i| i + 1).unwrap_or(0);
+    let (name, value) = field.split_at(colon);
+
+    let mut stripped = name.to_vec();
+    for (i, tag) in value.split(|&b| b == b';').enumerate() {
+        if i > 0 {
+            stripped.push(b';');
+        }
+        match tag.iter().position(|&b| b == b'=') {
+            Some(eq) if trim_wsp(&tag[..eq]) == b"b" => stripped.extend_from_slice(&tag[..eq + 1]),
+            _ => stripped.extend_from_slice(tag),
+        }
+    }
+    stripped
+}
+
+/// Picks the header instances `h=` names, each name taking the next instance
+/// from the bottom
+fn signed_headers(fields: &[Vec<u8>], names: &str, c: Canonicalization) -> Vec<u8> {
+    let mut used: HashMap<String, usize> = HashMap::new();
+    let mut signed = Vec::new();
+
+    for name in names.split(':').map(|n| n.trim().to_lowercase()) {
+        let skip = used.entry(name.clone()).or_insert(0);
+        let instance = fields.iter()
+            .rev()
+            .filter(|f| field_name(f).eq_ignore_ascii_case(name.as_bytes()))
+            .nth(*skip);
+
+        // Naming an absent header signs its absence, it contributes nothing
+        if let Some(field) = instance {
+            signed.extend_from_slice(&canonical_header(field, c));
+            *skip += 1;
+        }
+    }
+
+    signed
+}
+
+/// A DKIM-Signature's result and signing domain
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub result: AuthResult,
+    pub domain: String,
+}
+
+/// Verifies every DKIM-Signature on the message
+pub fn verify(resolver: &Resolver, raw: &[u8]) -> Vec<Signature> {
+    let (fields, body) = split_message(raw);
+
+    fields.iter()
+        .filter(|f| field_name(f).eq_ignore_ascii_case(b"DKIM-Signature"))
+        .map(|signature| verify_signature(resolver, &fields, body, signature))
+        .collect()
+}
+
+fn verify_signature(resolver: &Resolver, fields: &[Vec<u8>], body: &[u8], signature: &[u8]) -> Signature {
+    // Tags are ASCII, only their values are ever compared
+    let tags = tags(&String::from_utf8_lossy(field_value(signature)));
+
+    let domain = tags.get("d").cloned().unwrap_or_default().to_lowercase();
+    let outcome = |result| Signature { result, domain: domain.clone() };
+
+    let (selector, names, bh, b) = match (tags.get("s"), tags.get("h"), tags.get("bh"), tags.get("b")) {
+        (Some(s), Some(h), Some(bh), Some(b)) if !domain.is_empty() => (s, h, bh, b),
+        _ => return outcome(AuthResult::PermError),
+    };
+
+    let sha256_digest = match tags.get("a").map(|a| a.as_str()) {
+        Some("rsa-sha256") => true,
+        Some("rsa-sha1") => false,
+        _ => return outcome(AuthResult::PermError),
+    };
+
+    let (header_c, body_c) = canonicalization(tags.get("c"));
+
+    let mut canonical = canonical_body(body, body_c);
+    if let Some(length) = tags.get("l").and_then(|l| l.parse::<usize>().ok()) {
+        canonical.truncate(length);
+    }
+
+    let body_hash = if sha256_digest {
+        base64::encode(&sha256(&canonical))
+    } else {
+        base64::encode(&sha1(&canonical))
+    };
+
+    if body_hash != *bh {
+        return outcome(AuthResult::Fail);
+    }
+
+    let key = match public_key(resolver, selector, &domain) {
+        Ok(Some(key)) => key,
+        Ok(None) => return outcome(AuthResult::PermError),
+        Err(e) => return outcome(e),
+    };
+
+    let signature_bytes = match base64::decode(b) {
+        Ok(bytes) => bytes,
+        Err(_) => return outcome(AuthResult::PermError),
+    };
+
+    // The signature's own header is signed last, without its line ending
+    let mut data = signed_headers(fields, names, header_c);
+    let own = canonical_header(&without_signature(signature), header_c);
+    data.extend_from_slice(&own[..own.len() - 2]);
+
+    let digest = if sha256_digest { MessageDigest::sha256() } else { MessageDigest::sha1() };
+    let verified = Verifier::new(digest, &key)
+        .and_then(|mut verifier| {
+            verifier.update(&data)?;
+            verifier.verify(&signature_bytes)
+        });
+
+    match verified {
+        Ok(true) => outcome(AuthResult::Pass),
+        _ => outcome(AuthResult::Fail),
+    }
+}
+
+/// The key published at `selector._domainkey.domain`, None if it's missing or revoked
+fn public_key(resolver: &Resolver, selector: &str, domain: &str) -> Result<Option<PKey>, AuthResult> {
+    let records = resolver.txt(&format!("{}._domainkey.{}", selector, domain))
+        .map_err(|_| AuthResult::TempError)?;
+
+    let key = records.iter()
+        .map(|record| tags(record))
+        .filter_map(|tags| tags.get("p").cloned())
+        .next();
+
+    match key {
+        Some(ref key) if !key.is_empty() => {
+            Ok(base64::decode(key).ok().and_then(|der| PKey::public_key_from_der(&der).ok()))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::rsa::Rsa;
+    use openssl::sign::Signer;
+    use resolver::ZoneFileResolver;
+
+    #[test]
+    fn canonicalizes_relaxed() {
+        let raw = b"Subject:  Hello \r\n\tworld\r\nFrom: a@example.com\r\n\r\nHi  there \r\n\r\n\r\n";
+        let (fields, body) = split_message(raw);
+
+        assert_eq!(fields.len(), 2);
+        assert_eq!(canonical_header(&fields[0], Canonicalization::Relaxed), b"subject:Hello world\r\n".to_vec());
+        assert_eq!(canonical_body(body, Canonicalization::Relaxed), b"Hi there\r\n".to_vec());
+        assert_eq!(canonical_body(body, Canonicalization::Simple), b"Hi  there \r\n".to_vec());
+        assert_eq!(without_signature(b"DKIM-Signature: v=1; b=abc; bh=def"),
+                   b"DKIM-Signature: v=1; b=; bh=def".to_vec());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn verifies_non_utf8_messages() {
+        let key = PKey::from_rsa(Rsa::generate(1024).unwrap()).unwrap();
+
+        let mut zone = ZoneFileResolver::default();
+        zone.add("sel._domainkey.example.com",
+                 "TXT",
+                 &format!("v=DKIM1; k=rsa; p={}", base64::encode(&key.public_key_to_der().unwrap())));
+
+        // Latin-1, as plenty of mail still is
+        let headers: &[u8] = b"From: Ren\xe9 <rene@example.com>\r\nSubject: Caf\xe9\r\n";
+        let body: &[u8] = b"Bonjour, voici le menu du caf\xe9.\r\n";
+
+        // Signed with simple canonicalization, where the body and headers are
+        // signed exactly as sent
+        let signature = format!("DKIM-Signature: v=1; a=rsa-sha256; c=simple/simple; d=example.com;\r\n\
+                                 \ts=sel; h=from:subject; bh={}; b=",
+                                base64::encode(&sha256(body)));
+
+        let mut data = headers.to_vec();
+        data.extend_from_slice(signature.as_bytes());
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(&data).unwrap();
+
+        let mut message = format!("{}{}\r\n", signature, base64::encode(&signer.finish().unwrap())).into_bytes();
+        message.extend_from_slice(headers);
+        message.extend_from_slice(b"\r\n");
+        message.extend_from_slice(body);
+
+        assert_eq!(verify(&zone, &message),
+                   vec![Signature { result: AuthResult::Pass, domain: "example.com".to_owned() }]);
+
+        // Any other byte in place of the non-UTF-8 one breaks the signature
+        let tampered: Vec<u8> = message.iter()
+            .map(|&b| if b == 0xe9 { b'e' } else { b })
+            .collect();
+        assert_eq!(verify(&zone, &tampered)[0].result, AuthResult::Fail);
+    }
+}
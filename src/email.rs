@@ -6,6 +6,7 @@ use std;
 use std::sync::Arc;
 
 use errors::*;
+use mime::*;
 
 pub struct MailParser {
     self_ref: MailParserActor,
@@ -13,12 +14,104 @@ pub struct MailParser {
 }
 
 pub type EmailBytes = std::sync::Arc<Vec<u8>>;
-type ParseResult = std::sync::Arc<Fn(Result<ParsedMail>) + Send + Sync + 'static>;
+pub type ParsedEmail = std::sync::Arc<Email>;
+type ParseResult = std::sync::Arc<Fn(Result<ParsedEmail>) + Send + Sync + 'static>;
+
+/// An owned copy of a `ParsedMail`.
+///
+/// `ParsedMail` borrows the raw bytes, so it can't be sent on to other actors.
+/// Its MIME tree is flattened into `parts`, each already decoded.
+#[derive(Clone, Debug, Default)]
+pub struct Email {
+    /// (key, value) in the order they appear
+    pub headers: Vec<(String, String)>,
+    /// Every part of the MIME tree, depth first, starting with the top level
+    pub parts: Vec<MimePart>,
+}
+
+impl<'a> From<&'a ParsedMail<'a>> for Email {
+    fn from(mail: &'a ParsedMail<'a>) -> Email {
+        let headers = mail.headers.iter()
+            .filter_map(|h| match (h.get_key(), h.get_value()) {
+                (Ok(key), Ok(value)) => Some((key, value)),
+                _ => None,
+            })
+            .collect();
+
+        Email {
+            headers,
+            parts: walk(mail),
+        }
+    }
+}
+
+impl Email {
+    /// The first value of the header `key`, ignoring case
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref k, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    /// Every value of the header `key`, ignoring case
+    pub fn header_values(&self, key: &str) -> Vec<&str> {
+        self.headers.iter()
+            .filter(|&&(ref k, _)| k.eq_ignore_ascii_case(key))
+            .map(|&(_, ref v)| v.as_str())
+            .collect()
+    }
+
+    pub fn subject(&self) -> &str {
+        self.header("Subject").unwrap_or("")
+    }
+
+    /// The top level content type
+    pub fn mimetype(&self) -> &str {
+        self.parts.first().map(|p| p.mimetype.as_str()).unwrap_or("text/plain")
+    }
+
+    /// The top level charset
+    pub fn charset(&self) -> &str {
+        self.parts.first().map(|p| p.charset.as_str()).unwrap_or("us-ascii")
+    }
+
+    /// Every text/plain part, joined
+    pub fn plain_body(&self) -> Option<String> {
+        self.text_of("text/plain")
+    }
+
+    /// Every text/html part, joined
+    pub fn html_body(&self) -> Option<String> {
+        self.text_of("text/html")
+    }
+
+    /// The body a reader would see - the text/plain parts, falling back to
+    /// the text/html parts
+    pub fn text_body(&self) -> String {
+        self.plain_body()
+            .or_else(|| self.html_body())
+            .unwrap_or_default()
+    }
+
+    fn text_of(&self, mimetype: &str) -> Option<String> {
+        let texts: Vec<&str> = self.parts.iter()
+            .filter(|p| p.mimetype == mimetype)
+            .filter_map(|p| p.text.as_ref().map(|t| t.as_str()))
+            .collect();
+
+        if texts.is_empty() {
+            None
+        } else {
+            Some(texts.join("\n"))
+        }
+    }
+}
 
 #[derive_actor]
 impl MailParser {
     pub fn parse(&self, data: EmailBytes, res: ParseResult) {
         let mail = parse_mail(&data)
+            .map(|mail| Arc::new(Email::from(&mail)))
             .map_err(|e|
                 ErrorKind::UnrecoverableError(format!("Failed to parse mail with {}", e).into())
                     .into()
@@ -47,4 +140,3 @@ impl MailParser {
         // t(self.self_ref.clone(), self.system.clone());
     }
 }
-
@@ -130,6 +130,10 @@ impl EmailReader
                                                CompletionStatus::Retry(e, tries) => {
                                                    std::thread::sleep(Duration::from_millis(2 << tries as u64));
                                                    if tries > 5 {
+                                                       // Callers wait for a final verdict for every path
+                                                       res(Err(ErrorKind::UnrecoverableError(
+                                                           format!("Gave up on {:#?} after {} tries", path, tries).into())
+                                                           .into()));
                                                        self_ref.request_next_file(id.clone());
                                                    } else {
                                                        self_ref.retry_file_by_path(id.clone(), path.clone(), res.clone(), tries);
@@ -158,9 +162,9 @@ impl EmailReader
         let self_ref = self.self_ref.clone();
 
         let res = res.clone();
-        worker.predict(work, Arc::new(move |p| {
+        worker.predict_with_cache(work, Arc::new(move |p| {
             match p {
-                Ok(p) => {
+                Ok(_) => {
                     completion_handler.success();
                 }
                 Err(ref e) => {
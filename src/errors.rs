@@ -1,6 +1,10 @@
 use std::borrow::Cow;
 
 error_chain! {
+    foreign_links {
+        Io(::std::io::Error);
+    }
+
     errors {
         RecoverableError(t: Cow<'static, str>) {
             description("A retryable operation failed.")
@@ -13,4 +17,4 @@ error_chain! {
     }
 
 
-}
\ No newline at end of file
+}
@@ -11,13 +11,96 @@ use sentiment::*;
 use errors::*;
 use email::*;
 use html::*;
+use text_analysis::*;
+use headers::*;
+use links::*;
+use hidden::*;
+use mime::*;
+use attachments::*;
+use office::*;
+use authentication::*;
+use auth_results::*;
+use impersonation::*;
+use campaign::*;
+use fuzzy_hash::*;
+use feature_vector::*;
 
-#[derive(Clone)]
+/// Bump when a feature's computation changes without its columns changing,
+/// so that stored features are re-extracted
+pub const EXTRACTOR_VERSION: u32 = 3;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[derive(Builder)]
 #[builder(setter(into))]
 pub struct Features {
-    pub sentiment_analysis: Analysis,
-    //    pub body_length: usize
+    pub sentiment: SentimentFeatures,
+    pub text: TextFeatures,
+    pub headers: HeaderFeatures,
+    pub links: LinkFeatures,
+    pub hidden: HiddenFeatures,
+    pub mime: MimeFeatures,
+    pub attachments: AttachmentFeatures,
+    pub documents: DocumentFeatures,
+    pub authentication: AuthenticationFeatures,
+    pub auth_results: AuthResultsFeatures,
+    pub impersonation: ImpersonationFeatures,
+    pub campaign: CampaignFeatures,
+    pub fuzzy_hash: FuzzyHashFeatures,
+}
+
+impl FeatureColumns for Features {
+    fn columns() -> Vec<&'static str> {
+        let mut columns = Vec::new();
+        columns.extend(SentimentFeatures::columns());
+        columns.extend(TextFeatures::columns());
+        columns.extend(HeaderFeatures::columns());
+        columns.extend(LinkFeatures::columns());
+        columns.extend(HiddenFeatures::columns());
+        columns.extend(MimeFeatures::columns());
+        columns.extend(AttachmentFeatures::columns());
+        columns.extend(DocumentFeatures::columns());
+        columns.extend(AuthenticationFeatures::columns());
+        columns.extend(AuthResultsFeatures::columns());
+        columns.extend(ImpersonationFeatures::columns());
+        columns.extend(CampaignFeatures::columns());
+        columns.extend(FuzzyHashFeatures::columns());
+        columns
+    }
+
+    fn values(&self) -> Vec<f32> {
+        let mut values = Vec::new();
+        values.extend(self.sentiment.values());
+        values.extend(self.text.values());
+        values.extend(self.headers.values());
+        values.extend(self.links.values());
+        values.extend(self.hidden.values());
+        values.extend(self.mime.values());
+        values.extend(self.attachments.values());
+        values.extend(self.documents.values());
+        values.extend(self.authentication.values());
+        values.extend(self.auth_results.values());
+        values.extend(self.impersonation.values());
+        values.extend(self.campaign.values());
+        values.extend(self.fuzzy_hash.values());
+        values
+    }
+}
+
+impl Features {
+    pub fn to_vector(&self) -> FeatureVector {
+        FeatureVector::from_features(self)
+    }
+
+    /// Human readable reasons the email looks malicious
+    pub fn reasons(&self) -> Vec<String> {
+        let mut reasons = Vec::new();
+        reasons.extend(self.attachments.reasons.iter().cloned());
+        reasons.extend(self.documents.reasons.iter().cloned());
+        reasons.extend(self.authentication.reasons.iter().cloned());
+        reasons.extend(self.impersonation.reasons.iter().cloned());
+        reasons.extend(self.fuzzy_hash.reasons.iter().cloned());
+        reasons
+    }
 }
 
 
@@ -26,6 +109,18 @@ pub struct FeatureExtractionManager {
     system: SystemActor,
     parser: MailParserActor,
     sentiment_analyzer: SentimentAnalyzerActor,
+    text_analyzer: TextFeatureExtractorActor,
+    header_analyzer: HeaderAnalyzerActor,
+    html_parser: HtmlParserActor,
+    mime_analyzer: MimeAnalyzerActor,
+    attachment_analyzer: AttachmentAnalyzerActor,
+    document_inspector: DocumentInspectorActor,
+    authentication_verifier: AuthenticationVerifierActor,
+    auth_results_analyzer: AuthResultsAnalyzerActor,
+    impersonation_detector: ImpersonationDetectorActor,
+    campaign_detector: CampaignDetectorActor,
+    fuzzy_hash_matcher: FuzzyHashMatcherActor,
+    timeout: Duration,
 }
 
 #[derive_actor]
@@ -34,12 +129,34 @@ impl FeatureExtractionManager {
         let r = res.clone();
         let parser = self.parser.clone();
         let sentiment_analyzer = self.sentiment_analyzer.clone();
+        let text_analyzer = self.text_analyzer.clone();
+        let header_analyzer = self.header_analyzer.clone();
+        let html_parser = self.html_parser.clone();
+        let mime_analyzer = self.mime_analyzer.clone();
+        let attachment_analyzer = self.attachment_analyzer.clone();
+        let document_inspector = self.document_inspector.clone();
+        let authentication_verifier = self.authentication_verifier.clone();
+        let auth_results_analyzer = self.auth_results_analyzer.clone();
+        let impersonation_detector = self.impersonation_detector.clone();
+        let campaign_detector = self.campaign_detector.clone();
+        let fuzzy_hash_matcher = self.fuzzy_hash_matcher.clone();
 
         let extractor = move |self_ref, system| {
             let r = r.clone();
             FeatureExtractor::new(
                 parser.clone(),
                 sentiment_analyzer.clone(),
+                text_analyzer.clone(),
+                header_analyzer.clone(),
+                html_parser.clone(),
+                mime_analyzer.clone(),
+                attachment_analyzer.clone(),
+                document_inspector.clone(),
+                authentication_verifier.clone(),
+                auth_results_analyzer.clone(),
+                impersonation_detector.clone(),
+                campaign_detector.clone(),
+                fuzzy_hash_matcher.clone(),
                 move || {
                     r(Err(ErrorKind::RecoverableError("FeatureExtractor timed out".into()).into()));
                 },
@@ -47,7 +164,7 @@ impl FeatureExtractionManager {
                 system)
         };
 
-        let extractor = FeatureExtractorActor::new(extractor, self.system.clone(), Duration::from_millis(50));
+        let extractor = FeatureExtractorActor::new(extractor, self.system.clone(), self.timeout);
 
         extractor.extract(email, res);
     }
@@ -56,6 +173,18 @@ impl FeatureExtractionManager {
 impl FeatureExtractionManager {
     pub fn new(parser: MailParserActor,
                sentiment_analyzer: SentimentAnalyzerActor,
+               text_analyzer: TextFeatureExtractorActor,
+               header_analyzer: HeaderAnalyzerActor,
+               html_parser: HtmlParserActor,
+               mime_analyzer: MimeAnalyzerActor,
+               attachment_analyzer: AttachmentAnalyzerActor,
+               document_inspector: DocumentInspectorActor,
+               authentication_verifier: AuthenticationVerifierActor,
+               auth_results_analyzer: AuthResultsAnalyzerActor,
+               impersonation_detector: ImpersonationDetectorActor,
+               campaign_detector: CampaignDetectorActor,
+               fuzzy_hash_matcher: FuzzyHashMatcherActor,
+               timeout: Duration,
                self_ref: FeatureExtractionManagerActor,
                system: SystemActor) -> FeatureExtractionManager {
         FeatureExtractionManager {
@@ -65,6 +194,18 @@ impl FeatureExtractionManager {
             system,
             parser,
             sentiment_analyzer,
+            text_analyzer,
+            header_analyzer,
+            html_parser,
+            mime_analyzer,
+            attachment_analyzer,
+            document_inspector,
+            authentication_verifier,
+            auth_results_analyzer,
+            impersonation_detector,
+            campaign_detector,
+            fuzzy_hash_matcher,
+            timeout,
         }
     }
 
@@ -90,6 +231,17 @@ pub struct FeatureExtractor<T>
     features: FeaturesBuilder,
     parser: MailParserActor,
     sentiment_analyzer: SentimentAnalyzerActor,
+    text_analyzer: TextFeatureExtractorActor,
+    header_analyzer: HeaderAnalyzerActor,
+    html_parser: HtmlParserActor,
+    mime_analyzer: MimeAnalyzerActor,
+    attachment_analyzer: AttachmentAnalyzerActor,
+    document_inspector: DocumentInspectorActor,
+    authentication_verifier: AuthenticationVerifierActor,
+    auth_results_analyzer: AuthResultsAnalyzerActor,
+    impersonation_detector: ImpersonationDetectorActor,
+    campaign_detector: CampaignDetectorActor,
+    fuzzy_hash_matcher: FuzzyHashMatcherActor,
     on_timeout: T,
     timed_out: bool
 }
@@ -102,7 +254,30 @@ impl<T> FeatureExtractor<T>
     pub fn extract(&self, email: EmailBytes, res: FeatureExtraction) {
         let self_ref = self.self_ref.clone();
         let sentiment_analyzer = self.sentiment_analyzer.clone();
-        let system = self.system.clone();
+        let text_analyzer = self.text_analyzer.clone();
+        let header_analyzer = self.header_analyzer.clone();
+        let html_parser = self.html_parser.clone();
+        let mime_analyzer = self.mime_analyzer.clone();
+        let attachment_analyzer = self.attachment_analyzer.clone();
+        let document_inspector = self.document_inspector.clone();
+        let auth_results_analyzer = self.auth_results_analyzer.clone();
+        let impersonation_detector = self.impersonation_detector.clone();
+        let campaign_detector = self.campaign_detector.clone();
+        let fuzzy_hash_matcher = self.fuzzy_hash_matcher.clone();
+
+        {
+            // DKIM signs the message as sent, so this runs on the raw bytes
+            let self_ref = self_ref.clone();
+            let res = res.clone();
+            self.authentication_verifier.verify(email.clone(), std::sync::Arc::new(
+                move |authentication| {
+                    match authentication {
+                        Ok(authentication) => self_ref.clone().set_authentication(authentication, res.clone()),
+                        Err(e) => res(Err(e))
+                    }
+                }
+            ));
+        }
 
         self.parser.parse(email, std::sync::Arc::new(move |r| {
             let email = match r {
@@ -113,33 +288,175 @@ impl<T> FeatureExtractor<T>
             let self_ref = self_ref.clone();
             let res = res.clone();
 
-            sentiment_analyzer.analyze(email.get_body().unwrap_or("".to_owned()), std::sync::Arc::new(
-                move |analysis| {
-                    match analysis {
-                        Ok(analysis) => {
-                            self_ref.clone().set_sentiment(analysis, res.clone())
-                        },
-                        Err(e) => {
-                            res(Err(e))
+            {
+                let self_ref = self_ref.clone();
+                let res = res.clone();
+                text_analyzer.analyze(email.clone(), std::sync::Arc::new(
+                    move |text| {
+                        match text {
+                            Ok(text) => self_ref.clone().set_text(text, res.clone()),
+                            Err(e) => res(Err(e))
+                        }
+                    }
+                ));
+            }
+
+            {
+                let self_ref = self_ref.clone();
+                let res = res.clone();
+                header_analyzer.analyze(email.clone(), std::sync::Arc::new(
+                    move |headers| {
+                        match headers {
+                            Ok(headers) => self_ref.clone().set_headers(headers, res.clone()),
+                            Err(e) => res(Err(e))
                         }
                     }
+                ));
+            }
+
+            {
+                let self_ref = self_ref.clone();
+                let res = res.clone();
+                mime_analyzer.analyze(email.clone(), std::sync::Arc::new(
+                    move |mime| {
+                        match mime {
+                            Ok(mime) => self_ref.clone().set_mime(mime, res.clone()),
+                            Err(e) => res(Err(e))
+                        }
+                    }
+                ));
+            }
+
+            {
+                let self_ref = self_ref.clone();
+                let res = res.clone();
+                attachment_analyzer.analyze(email.clone(), std::sync::Arc::new(
+                    move |attachments| {
+                        match attachments {
+                            Ok(attachments) => self_ref.clone().set_attachments(attachments, res.clone()),
+                            Err(e) => res(Err(e))
+                        }
+                    }
+                ));
+            }
+
+            {
+                let self_ref = self_ref.clone();
+                let res = res.clone();
+                document_inspector.inspect(email.clone(), std::sync::Arc::new(
+                    move |documents| {
+                        match documents {
+                            Ok(documents) => self_ref.clone().set_documents(documents, res.clone()),
+                            Err(e) => res(Err(e))
+                        }
+                    }
+                ));
+            }
+
+            {
+                let self_ref = self_ref.clone();
+                let res = res.clone();
+                auth_results_analyzer.analyze(email.clone(), std::sync::Arc::new(
+                    move |auth_results| {
+                        match auth_results {
+                            Ok(auth_results) => self_ref.clone().set_auth_results(auth_results, res.clone()),
+                            Err(e) => res(Err(e))
+                        }
+                    }
+                ));
+            }
+
+            {
+                let self_ref = self_ref.clone();
+                let res = res.clone();
+                fuzzy_hash_matcher.analyze(email.clone(), std::sync::Arc::new(
+                    move |fuzzy_hash| {
+                        match fuzzy_hash {
+                            Ok(fuzzy_hash) => self_ref.clone().set_fuzzy_hash(fuzzy_hash, res.clone()),
+                            Err(e) => res(Err(e))
+                        }
+                    }
+                ));
+            }
+
+            // Plain text emails have no links, an empty document gives us empty features
+            let html = email.html_body();
+            let plain = email.text_body();
+            let sentiment_analyzer = sentiment_analyzer.clone();
+            let impersonation_detector = impersonation_detector.clone();
+            let campaign_detector = campaign_detector.clone();
+
+            html_parser.parse(html.clone().unwrap_or_default(), std::sync::Arc::new(
+                move |document| {
+                    let document = match document {
+                        Ok(document) => document,
+                        Err(e) => return res(Err(e))
+                    };
+
+                    self_ref.clone().set_links(LinkFeatures::from(&document), res.clone());
+
+                    {
+                        let self_ref = self_ref.clone();
+                        let res = res.clone();
+                        impersonation_detector.analyze(email.clone(), link_hosts(&document), std::sync::Arc::new(
+                            move |impersonation| {
+                                match impersonation {
+                                    Ok(impersonation) => {
+                                        self_ref.clone().set_impersonation(impersonation, res.clone())
+                                    },
+                                    Err(e) => res(Err(e))
+                                }
+                            }
+                        ));
+                    }
+
+                    let hidden = if html.is_some() {
+                        HiddenContent::from(&document)
+                    } else {
+                        HiddenContent::from_text(&plain)
+                    };
+
+                    {
+                        let self_ref = self_ref.clone();
+                        let res = res.clone();
+                        campaign_detector.analyze(email.clone(), hidden.visible_text.clone(), std::sync::Arc::new(
+                            move |campaign| {
+                                match campaign {
+                                    Ok(campaign) => self_ref.clone().set_campaign(campaign, res.clone()),
+                                    Err(e) => res(Err(e))
+                                }
+                            }
+                        ));
+                    }
+
+                    // Sentiment only sees what a reader would, not text hidden from them
+                    {
+                        let self_ref = self_ref.clone();
+                        let res = res.clone();
+                        sentiment_analyzer.analyze(hidden.visible_text.clone(), std::sync::Arc::new(
+                            move |analysis| {
+                                match analysis {
+                                    Ok(analysis) => {
+                                        self_ref.clone().set_sentiment(analysis, res.clone())
+                                    },
+                                    Err(e) => {
+                                        res(Err(e))
+                                    }
+                                }
+                            }
+                        ));
+                    }
+
+                    self_ref.clone().set_hidden(hidden.features, res.clone());
                 }
             ));
-
-            //            let html = HtmlParser::new();
-            //            let html = HtmlParserActor::new(html, system.clone(), Duration::from_secs(30));
-            //
-            //            html.parse(email.get_body().unwrap_or("".to_owned()), std::sync::Arc::new(
-            //                move |href| {
-            ////                println!("")
-            //            }));
         }));
     }
 
     pub fn set_sentiment(&mut self,
                          analysis: Analysis,
                          res: FeatureExtraction) {
-        self.features.sentiment_analysis(analysis);
+        self.features.sentiment(SentimentFeatures::from(analysis));
 
         // If we've already timed out, don't bother sending features to the rest of the system
         if self.is_complete() && !self.timed_out {
@@ -150,6 +467,126 @@ impl<T> FeatureExtractor<T>
         }
     }
 
+    pub fn set_text(&mut self,
+                    text: TextFeatures,
+                    res: FeatureExtraction) {
+        self.features.text(text);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_text")))
+        }
+    }
+
+    pub fn set_headers(&mut self,
+                       headers: HeaderFeatures,
+                       res: FeatureExtraction) {
+        self.features.headers(headers);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_headers")))
+        }
+    }
+
+    pub fn set_links(&mut self,
+                     links: LinkFeatures,
+                     res: FeatureExtraction) {
+        self.features.links(links);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_links")))
+        }
+    }
+
+    pub fn set_hidden(&mut self,
+                      hidden: HiddenFeatures,
+                      res: FeatureExtraction) {
+        self.features.hidden(hidden);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_hidden")))
+        }
+    }
+
+    pub fn set_mime(&mut self,
+                    mime: MimeFeatures,
+                    res: FeatureExtraction) {
+        self.features.mime(mime);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_mime")))
+        }
+    }
+
+    pub fn set_attachments(&mut self,
+                           attachments: AttachmentFeatures,
+                           res: FeatureExtraction) {
+        self.features.attachments(attachments);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_attachments")))
+        }
+    }
+
+    pub fn set_documents(&mut self,
+                         documents: DocumentFeatures,
+                         res: FeatureExtraction) {
+        self.features.documents(documents);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_documents")))
+        }
+    }
+
+    pub fn set_authentication(&mut self,
+                              authentication: AuthenticationFeatures,
+                              res: FeatureExtraction) {
+        self.features.authentication(authentication);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_authentication")))
+        }
+    }
+
+    pub fn set_auth_results(&mut self,
+                            auth_results: AuthResultsFeatures,
+                            res: FeatureExtraction) {
+        self.features.auth_results(auth_results);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_auth_results")))
+        }
+    }
+
+    pub fn set_impersonation(&mut self,
+                             impersonation: ImpersonationFeatures,
+                             res: FeatureExtraction) {
+        self.features.impersonation(impersonation);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_impersonation")))
+        }
+    }
+
+    pub fn set_campaign(&mut self,
+                        campaign: CampaignFeatures,
+                        res: FeatureExtraction) {
+        self.features.campaign(campaign);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_campaign")))
+        }
+    }
+
+    pub fn set_fuzzy_hash(&mut self,
+                          fuzzy_hash: FuzzyHashFeatures,
+                          res: FeatureExtraction) {
+        self.features.fuzzy_hash(fuzzy_hash);
+
+        if self.is_complete() && !self.timed_out {
+            res(Ok(self.features.build().expect("set_fuzzy_hash")))
+        }
+    }
+
     //    pub fn set_body_length(&mut self,
     //                           body_len: usize,
     //                           res: FeatureExtraction) {
@@ -166,6 +603,17 @@ impl<T> FeatureExtractor<T>
 {
     pub fn new(parser: MailParserActor,
                sentiment_analyzer: SentimentAnalyzerActor,
+               text_analyzer: TextFeatureExtractorActor,
+               header_analyzer: HeaderAnalyzerActor,
+               html_parser: HtmlParserActor,
+               mime_analyzer: MimeAnalyzerActor,
+               attachment_analyzer: AttachmentAnalyzerActor,
+               document_inspector: DocumentInspectorActor,
+               authentication_verifier: AuthenticationVerifierActor,
+               auth_results_analyzer: AuthResultsAnalyzerActor,
+               impersonation_detector: ImpersonationDetectorActor,
+               campaign_detector: CampaignDetectorActor,
+               fuzzy_hash_matcher: FuzzyHashMatcherActor,
                on_timeout: T,
                self_ref: FeatureExtractorActor,
                system: SystemActor) -> FeatureExtractor<T> {
@@ -175,6 +623,17 @@ impl<T> FeatureExtractor<T>
             features: FeaturesBuilder::default(),
             parser,
             sentiment_analyzer,
+            text_analyzer,
+            header_analyzer,
+            html_parser,
+            mime_analyzer,
+            attachment_analyzer,
+            document_inspector,
+            authentication_verifier,
+            auth_results_analyzer,
+            impersonation_detector,
+            campaign_detector,
+            fuzzy_hash_matcher,
             on_timeout,
             timed_out: false
         }
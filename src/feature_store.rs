@@ -0,0 +1,204 @@
+use std;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use serde_json;
+use twox_hash::XxHash;
+use uuid::Uuid;
+
+use errors::*;
+use extraction::{Features, EXTRACTOR_VERSION};
+use campaign::CampaignIndex;
+use fuzzy_hash::SignatureDb;
+use feature_vector::FeatureColumns;
+use service::hash_email;
+use train_extract::{self, ExtractSummary, LabeledEmail};
+
+/// Features extracted earlier, kept on local disk so experiments on the same
+/// corpus don't pay for parsing and sentiment analysis again.
+///
+/// Entries live at `<dir>/<schema version>/<email hash>.json`. Entries of any
+/// other schema version are removed when the store is opened, anything else
+/// in `dir` is left alone.
+pub struct FeatureStore {
+    dir: PathBuf,
+    version: String,
+}
+
+/// Changes whenever the extractor's columns or EXTRACTOR_VERSION do, or the
+/// fuzzy hash signatures stored distances were measured against
+pub fn schema_version(signatures: &SignatureDb) -> String {
+    let mut hasher = XxHash::default();
+    hasher.write(Features::columns().join(",").as_bytes());
+    signatures.hash(&mut hasher);
+    format!("v{}-{:016x}", EXTRACTOR_VERSION, hasher.finish())
+}
+
+/// Whether a directory name is one `schema_version` could have made
+fn is_schema_version(name: &str) -> bool {
+    let mut parts = name.splitn(2, '-');
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(hash)) => {
+            version.len() > 1 && version.starts_with('v') &&
+                version[1..].chars().all(|c| c.is_digit(10)) &&
+                hash.len() == 16 && hash.chars().all(|c| c.is_digit(16))
+        }
+        _ => false,
+    }
+}
+
+impl FeatureStore {
+    /// `signatures` must be the database the extractor matches against
+    pub fn open(dir: &Path, signatures: &SignatureDb) -> Result<FeatureStore> {
+        let version = schema_version(signatures);
+        fs::create_dir_all(dir.join(&version))?;
+
+        let store = FeatureStore {
+            dir: dir.to_owned(),
+            version,
+        };
+
+        let removed = store.invalidate_stale()?;
+        if removed > 0 {
+            println!("Removed {} stale feature store versions from {:#?}", removed, dir);
+        }
+
+        Ok(store)
+    }
+
+    /// Removes the entries of every other schema version, returning how many
+    /// versions there were. Directories not named like a schema version
+    /// aren't the store's and are kept.
+    pub fn invalidate_stale(&self) -> Result<usize> {
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let stale = match entry.file_name().to_str() {
+                Some(name) => is_schema_version(name) && name != self.version,
+                None => false,
+            };
+
+            if stale && entry.file_type()?.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn path(&self, hash: &[u8]) -> PathBuf {
+        let name: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(&self.version).join(format!("{}.json", name))
+    }
+
+    /// The features stored for the email with this `hash_email`
+    pub fn get(&self, hash: &[u8]) -> Result<Option<Features>> {
+        let path = self.path(hash);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        serde_json::from_reader(file)
+            .map(Some)
+            .chain_err(|| format!("Corrupt feature store entry {:#?}", path))
+    }
+
+    pub fn put(&self, hash: &[u8], features: &Features) -> Result<()> {
+        let path = self.path(hash);
+
+        // Written beside the entry and renamed into place, so a reader never
+        // sees half an entry and two writers of the same email don't collide
+        let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            serde_json::to_writer(&mut writer, features)
+                .chain_err(|| "Failed to serialize features")?;
+            writer.flush()?;
+        }
+
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Writes the training CSV for the emails already in the store. Emails
+    /// that aren't, or can't be read, are counted as failed. Campaigns aren't
+    /// stored, they're rebuilt in `campaigns` from the emails in order.
+    pub fn export(&self,
+                  emails: &[LabeledEmail],
+                  campaigns: &CampaignIndex,
+                  output: &Path) -> Result<ExtractSummary> {
+        let mut writer = BufWriter::new(File::create(output)?);
+        writeln!(writer, "{}", train_extract::header())?;
+
+        let mut summary = ExtractSummary::default();
+        for email in emails {
+            let mut buf = Vec::new();
+            if let Err(e) = File::open(&email.path).and_then(|mut f| f.read_to_end(&mut buf)) {
+                println!("Failed to read {:#?}: {}", email.path, e);
+                summary.failed += 1;
+                continue;
+            }
+
+            let hash = hash_email(&buf);
+            match self.get(&hash) {
+                Ok(Some(mut features)) => {
+                    features.campaign = campaigns.observe(&hash, &features.campaign);
+                    writeln!(writer, "{}", train_extract::labeled_row(&features, email.spam))?;
+                    summary.written += 1;
+                }
+                Ok(None) => summary.failed += 1,
+                Err(e) => {
+                    println!("Failed to load features for {:#?}: {}", email.path, e);
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        writer.flush()?;
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fuzzy_hash::FuzzyHash;
+
+    #[test]
+    fn round_trips_and_invalidates() {
+        let dir = std::env::temp_dir().join(format!("feature_store_{}", Uuid::new_v4()));
+        fs::create_dir_all(dir.join("v0-0123456789abcdef")).unwrap();
+        fs::create_dir_all(dir.join("models")).unwrap();
+        fs::create_dir_all(dir.join("v2-backup")).unwrap();
+
+        let store = FeatureStore::open(&dir, &SignatureDb::default()).unwrap();
+        assert!(!dir.join("v0-0123456789abcdef").exists());
+        assert!(dir.join("models").exists());
+        assert!(dir.join("v2-backup").exists());
+
+        let hash = hash_email(b"Subject: hi\r\n\r\nhello");
+        assert!(store.get(&hash).unwrap().is_none());
+
+        let mut features = Features::default();
+        features.text.body_length = 5;
+        store.put(&hash, &features).unwrap();
+
+        let stored = store.get(&hash).unwrap().expect("stored features");
+        assert_eq!(stored.text.body_length, 5);
+        assert_eq!(stored.values(), features.values());
+
+        // Distances to other signatures are stored under another version
+        let mut signatures = SignatureDb::default();
+        let body = "Your mailbox is full, verify your account within 24 hours or lose access";
+        signatures.add(FuzzyHash::digest(body.as_bytes()).unwrap(), "mailbox phish");
+        assert!(schema_version(&signatures) != schema_version(&SignatureDb::default()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
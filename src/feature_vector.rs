@@ -0,0 +1,111 @@
+use errors::*;
+
+/// Implemented by every group of features that ends up in the model's input.
+///
+/// `columns` and `values` must agree in length and order - the column names
+/// are the CSV header the model was trained on.
+pub trait FeatureColumns {
+    fn columns() -> Vec<&'static str>;
+    fn values(&self) -> Vec<f32>;
+}
+
+/// An ordered, named feature vector, ready to be handed to a model.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeatureVector {
+    columns: Vec<&'static str>,
+    values: Vec<f32>,
+}
+
+impl FeatureVector {
+    pub fn new(columns: Vec<&'static str>, values: Vec<f32>) -> FeatureVector {
+        assert_eq!(columns.len(), values.len(),
+                   "Feature vector has {} columns but {} values", columns.len(), values.len());
+
+        FeatureVector {
+            columns,
+            values,
+        }
+    }
+
+    pub fn from_features<F: FeatureColumns>(features: &F) -> FeatureVector {
+        FeatureVector::new(F::columns(), features.values())
+    }
+
+    pub fn columns(&self) -> &[&'static str] {
+        &self.columns
+    }
+
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    pub fn header(&self) -> String {
+        self.columns.join(",")
+    }
+
+    pub fn row(&self) -> String {
+        self.values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// A single row CSV document, including the header
+    pub fn to_csv(&self) -> String {
+        format!("{}\n{}\n", self.header(), self.row())
+    }
+
+    /// A CSV document with one row per vector. All vectors must share the same columns.
+    pub fn batch_to_csv(vectors: &[FeatureVector]) -> String {
+        let mut csv = String::new();
+
+        if let Some(first) = vectors.first() {
+            csv.push_str(&first.header());
+            csv.push('\n');
+        }
+
+        for vector in vectors {
+            csv.push_str(&vector.row());
+            csv.push('\n');
+        }
+
+        csv
+    }
+}
+
+/// Ensures a model's expected columns match what the extractor produces, in order.
+pub fn check_schema(expected: &[String], produced: &[&'static str]) -> Result<()> {
+    let matches = expected.len() == produced.len() &&
+        expected.iter().zip(produced.iter()).all(|(e, p)| e == p);
+
+    if matches {
+        Ok(())
+    } else {
+        bail!(ErrorKind::UnrecoverableError(
+            format!("Model expects columns {:?} but extractor produces {:?}", expected, produced).into()))
+    }
+}
+
+/// Parses a CSV header line into its column names
+pub fn parse_header(header: &str) -> Vec<String> {
+    header.trim()
+        .split(',')
+        .map(|c| c.trim().to_owned())
+        .filter(|c| !c.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_round_trip() {
+        let vector = FeatureVector::new(vec!["a", "b"], vec![1.0, 0.5]);
+
+        assert_eq!(vector.to_csv(), "a,b\n1,0.5\n");
+        assert!(check_schema(&parse_header("a, b\n"), vector.columns()).is_ok());
+        assert!(check_schema(&parse_header("b,a"), vector.columns()).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::fmt;
+use std::hash::Hasher;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use errors::*;
+use email::*;
+use attachments::attachments;
+use config::FuzzyHashConfig;
+use feature_vector::FeatureColumns;
+
+/// Shorter inputs don't have enough trigrams for a meaningful digest
+const MIN_LENGTH: usize = 50;
+const BUCKETS: usize = 128;
+/// Distances are capped here, and it's the distance reported when there was
+/// nothing to compare
+pub const MAX_DISTANCE: u32 = 1000;
+
+/// A locality sensitive digest in the style of TLSH: similar inputs get
+/// digests a small `distance` apart.
+///
+/// Every 5 byte window adds six of its byte triplets to 128 buckets. The
+/// digest is which quartile each bucket's count fell in, along with the
+/// input's length on a log scale, so it can be shared without revealing the
+/// content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuzzyHash {
+    checksum: u8,
+    lvalue: u8,
+    q1_ratio: u8,
+    q2_ratio: u8,
+    /// Two bits per bucket
+    body: [u8; BUCKETS / 4],
+}
+
+impl FuzzyHash {
+    pub fn digest(data: &[u8]) -> Option<FuzzyHash> {
+        if data.len() < MIN_LENGTH {
+            return None;
+        }
+
+        let mut buckets = [0u32; BUCKETS];
+        let mut checksum = 0u8;
+
+        for j in 4..data.len() {
+            let (a0, a1, a2, a3, a4) = (data[j], data[j - 1], data[j - 2], data[j - 3], data[j - 4]);
+            checksum = bucket_hash(0, a0, a1, checksum);
+
+            let triplets = [(2, a0, a1, a2), (3, a0, a1, a3), (5, a0, a2, a3),
+                            (7, a0, a2, a4), (11, a0, a1, a4), (13, a0, a3, a4)];
+            for &(salt, x, y, z) in triplets.iter() {
+                buckets[bucket_hash(salt, x, y, z) as usize % BUCKETS] += 1;
+            }
+        }
+
+        let mut sorted = buckets.to_vec();
+        sorted.sort();
+        let q1 = sorted[BUCKETS / 4 - 1];
+        let q2 = sorted[BUCKETS / 2 - 1];
+        let q3 = sorted[BUCKETS * 3 / 4 - 1];
+
+        // Too little variety in the input for the quartiles to mean anything
+        if q3 == 0 {
+            return None;
+        }
+
+        let mut body = [0u8; BUCKETS / 4];
+        for (i, count) in buckets.iter().enumerate() {
+            let code = if *count <= q1 {
+                0
+            } else if *count <= q2 {
+                1
+            } else if *count <= q3 {
+                2
+            } else {
+                3
+            };
+            body[i / 4] |= code << ((i % 4) * 2);
+        }
+
+        Some(FuzzyHash {
+            checksum,
+            lvalue: lvalue(data.len()),
+            q1_ratio: ((q1 * 100 / q3) % 16) as u8,
+            q2_ratio: ((q2 * 100 / q3) % 16) as u8,
+            body,
+        })
+    }
+
+    /// 0 for identical digests, growing as the inputs differ. Unrelated inputs
+    /// are usually a few hundred apart.
+    pub fn distance(&self, other: &FuzzyHash) -> u32 {
+        let mut distance = 0;
+
+        if self.checksum != other.checksum {
+            distance += 1;
+        }
+
+        let length = mod_diff(self.lvalue, other.lvalue, 256);
+        distance += if length <= 1 { length } else { length * 12 };
+
+        for &(a, b) in [(self.q1_ratio, other.q1_ratio), (self.q2_ratio, other.q2_ratio)].iter() {
+            let ratio = mod_diff(a, b, 16);
+            distance += if ratio <= 1 { ratio } else { (ratio - 1) * 12 };
+        }
+
+        for (a, b) in self.body.iter().zip(other.body.iter()) {
+            for shift in [0, 2, 4, 6].iter() {
+                let x = (a >> *shift) & 3;
+                let y = (b >> *shift) & 3;
+                let d = if x > y { x - y } else { y - x };
+                // Opposite ends of the distribution count double
+                distance += if d == 3 { 6 } else { d as u32 };
+            }
+        }
+
+        distance
+    }
+}
+
+/// Hex, as kept in the signature database
+impl fmt::Display for FuzzyHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{:x}{:x}", self.checksum, self.lvalue, self.q1_ratio, self.q2_ratio)?;
+        for byte in self.body.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FuzzyHash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FuzzyHash> {
+        let invalid = || -> Error {
+            ErrorKind::UnrecoverableError(format!("Not a fuzzy hash: {}", s).into()).into()
+        };
+
+        if s.len() != 6 + BUCKETS / 2 || !s.bytes().all(|b| b < 0x80) {
+            return Err(invalid());
+        }
+
+        let byte = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| invalid());
+        let nibble = |i: usize| u8::from_str_radix(&s[i..i + 1], 16).map_err(|_| invalid());
+
+        let mut body = [0u8; BUCKETS / 4];
+        for (i, b) in body.iter_mut().enumerate() {
+            *b = byte(6 + i * 2)?;
+        }
+
+        Ok(FuzzyHash {
+            checksum: byte(0)?,
+            lvalue: byte(2)?,
+            q1_ratio: nibble(4)?,
+            q2_ratio: nibble(5)?,
+            body,
+        })
+    }
+}
+
+fn bucket_hash(salt: u8, a: u8, b: u8, c: u8) -> u8 {
+    let mut h = (salt as u32) << 24 | (a as u32) << 16 | (b as u32) << 8 | c as u32;
+    // Murmur3's finalizer
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    (h >> 24) as u8
+}
+
+/// The input length on a log scale, finer for short inputs
+fn lvalue(length: usize) -> u8 {
+    let length = length as f64;
+    let lvalue = if length <= 656.0 {
+        length.ln() / 1.5f64.ln()
+    } else if length <= 3199.0 {
+        length.ln() / 1.3f64.ln() - 8.72777
+    } else {
+        length.ln() / 1.1f64.ln() - 62.5472
+    };
+    lvalue.floor().min(255.0) as u8
+}
+
+/// The distance between two values that wrap around at `range`
+fn mod_diff(a: u8, b: u8, range: u32) -> u32 {
+    let d = (a as i32 - b as i32).abs() as u32;
+    std::cmp::min(d, range - d)
+}
+
+/// Lowercased, with whitespace collapsed and digits masked, so resending the
+/// same text with a new order number or different line wrapping still matches
+pub fn normalize_text(text: &str) -> Vec<u8> {
+    text.split_whitespace()
+        .map(|word| {
+            word.chars()
+                .flat_map(|c| c.to_lowercase())
+                .map(|c| if c.is_numeric() { '0' } else { c })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+        .into_bytes()
+}
+
+/// Digests of the email's normalized body and of each attachment
+pub fn email_digests(email: &Email) -> Vec<FuzzyHash> {
+    let body = FuzzyHash::digest(&normalize_text(&email.text_body()));
+
+    body.into_iter()
+        .chain(attachments(email).iter().filter_map(|part| FuzzyHash::digest(&part.body)))
+        .collect()
+}
+
+/// Digests of known bad messages and attachments, each with a label naming
+/// what it was taken from.
+///
+/// Loaded from lines of `<digest> <label>`, as printed by the `signatures`
+/// subcommand. Blank lines and lines starting with `#` are skipped.
+#[derive(Default)]
+pub struct SignatureDb {
+    signatures: Vec<(FuzzyHash, String)>,
+}
+
+impl SignatureDb {
+    pub fn load(path: &Path) -> Result<SignatureDb> {
+        let file = File::open(path)?;
+        let mut db = SignatureDb::default();
+
+        for (line_no, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.splitn(2, char::is_whitespace);
+            let digest = fields.next().unwrap_or("").parse()
+                .chain_err(|| format!("Line {} of {:#?} doesn't start with a fuzzy hash", line_no + 1, path))?;
+            let label = fields.next().unwrap_or("").trim();
+
+            db.add(digest, label);
+        }
+
+        Ok(db)
+    }
+
+    pub fn add(&mut self, digest: FuzzyHash, label: &str) {
+        self.signatures.push((digest, label.to_owned()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    /// Feeds every signature and label to `hasher`. An empty database adds
+    /// nothing.
+    pub fn hash<H: Hasher>(&self, hasher: &mut H) {
+        for &(ref digest, ref label) in self.signatures.iter() {
+            hasher.write(digest.to_string().as_bytes());
+            hasher.write(label.as_bytes());
+            hasher.write_u8(0);
+        }
+    }
+
+    /// The closest signature to `digest` and its distance. A linear scan, the
+    /// database is expected to hold thousands of signatures, not millions.
+    pub fn nearest(&self, digest: &FuzzyHash) -> Option<(u32, &str)> {
+        self.signatures.iter()
+            .map(|&(ref signature, ref label)| (digest.distance(signature), label.as_str()))
+            .min_by_key(|&(distance, _)| distance)
+    }
+}
+
+pub fn signature_db_from_config(config: &FuzzyHashConfig) -> Arc<SignatureDb> {
+    match config.signatures {
+        Some(ref path) => {
+            let db = SignatureDb::load(path)
+                .expect(&format!("Failed to load fuzzy hash signatures {:#?}", path));
+            Arc::new(db)
+        }
+        None => Arc::new(SignatureDb::default()),
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FuzzyHashFeatures {
+    /// The distance from the body or any attachment to the closest known bad
+    /// signature, MAX_DISTANCE when there was nothing to compare
+    pub nearest_bad_distance: u32,
+    pub reasons: Vec<String>,
+}
+
+impl Default for FuzzyHashFeatures {
+    fn default() -> FuzzyHashFeatures {
+        FuzzyHashFeatures {
+            nearest_bad_distance: MAX_DISTANCE,
+            reasons: Vec::new(),
+        }
+    }
+}
+
+impl FeatureColumns for FuzzyHashFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["nearest_bad_distance"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.nearest_bad_distance as f32]
+    }
+}
+
+impl FuzzyHashFeatures {
+    pub fn from_email(email: &Email, signatures: &SignatureDb, max_distance: u32) -> FuzzyHashFeatures {
+        let nearest = email_digests(email).iter()
+            .filter_map(|digest| signatures.nearest(digest))
+            .min_by_key(|&(distance, _)| distance);
+
+        match nearest {
+            Some((distance, label)) => {
+                let reasons = if distance <= max_distance {
+                    vec![format!("resembles known bad {}", label)]
+                } else {
+                    Vec::new()
+                };
+
+                FuzzyHashFeatures {
+                    nearest_bad_distance: std::cmp::min(distance, MAX_DISTANCE),
+                    reasons,
+                }
+            }
+            None => FuzzyHashFeatures::default(),
+        }
+    }
+}
+
+pub struct FuzzyHashMatcher {
+    self_ref: FuzzyHashMatcherActor,
+    system: SystemActor,
+    signatures: Arc<SignatureDb>,
+    max_distance: u32,
+}
+
+type FuzzyHashFeaturesResponse = std::sync::Arc<Fn(Result<FuzzyHashFeatures>) + Send + Sync + 'static>;
+
+#[derive_actor]
+impl FuzzyHashMatcher {
+    pub fn analyze(&self, email: ParsedEmail, res: FuzzyHashFeaturesResponse) {
+        res(Ok(FuzzyHashFeatures::from_email(&*email, &self.signatures, self.max_distance)))
+    }
+}
+
+impl FuzzyHashMatcher {
+    pub fn new(signatures: Arc<SignatureDb>,
+               max_distance: u32,
+               self_ref: FuzzyHashMatcherActor,
+               system: SystemActor) -> FuzzyHashMatcher {
+        FuzzyHashMatcher {
+            self_ref,
+            system,
+            signatures,
+            max_distance,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: FuzzyHashMatcherMessage,
+                   t: Arc<T>)
+        where T: Fn(FuzzyHashMatcherActor, SystemActor) -> FuzzyHashMatcher + Send + Sync + 'static
+    {
+        match msg {
+            FuzzyHashMatcherMessage::AnalyzeVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in fuzzy hash matcher".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PHISH: &'static str = "Dear customer, your mailbox has exceeded its storage limit and \
+        incoming messages are being held. To avoid losing access to your account please verify \
+        your login details within 24 hours by following the link below. Failure to verify will \
+        result in your account being suspended. Thank you, the IT helpdesk team.";
+
+    #[test]
+    fn near_duplicates_are_close() {
+        let digest = |text: &str| FuzzyHash::digest(&normalize_text(text)).unwrap();
+
+        let phish = digest(PHISH);
+        let variant = digest(&PHISH.replace("Dear customer", "Dear user").replace("24 hours", "12 hours"));
+        let unrelated = digest("Hi Sam, thanks for sending over the quarterly numbers. I had a \
+            look at the spreadsheet and the totals for March look off by a few hundred, could you \
+            double check the travel expenses column before we share it with finance on Thursday? \
+            Cheers, Priya");
+
+        assert_eq!(phish.to_string().parse::<FuzzyHash>().unwrap(), phish);
+
+        let mut db = SignatureDb::default();
+        db.add(phish, "mailbox quota phish");
+
+        let (distance, label) = db.nearest(&variant).unwrap();
+        assert!(distance <= 100, "variant is {} away", distance);
+        assert_eq!(label, "mailbox quota phish");
+
+        let (distance, _) = db.nearest(&unrelated).unwrap();
+        assert!(distance > 100, "unrelated is {} away", distance);
+    }
+}
@@ -0,0 +1,269 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::sync::Arc;
+
+use mailparse::dateparse;
+
+use errors::*;
+use email::*;
+use feature_vector::FeatureColumns;
+use public_suffix::registrable_domain;
+
+pub struct HeaderAnalyzer {
+    self_ref: HeaderAnalyzerActor,
+    system: SystemActor,
+}
+
+type HeaderFeaturesResponse = std::sync::Arc<Fn(Result<HeaderFeatures>) + Send + Sync + 'static>;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HeaderFeatures {
+    pub received_hops: usize,
+    /// Seconds between the earliest and latest Received timestamps
+    pub received_time_skew: f32,
+    /// Hops stamped earlier than the hop before them
+    pub received_out_of_order: usize,
+    pub reply_to_mismatch: bool,
+    pub return_path_mismatch: bool,
+    pub message_id_mismatch: bool,
+    pub missing_date: bool,
+    pub missing_message_id: bool,
+    pub x_mailer: Mailer,
+    /// A "Re:" subject with neither In-Reply-To nor References
+    pub forged_reply: bool,
+}
+
+/// What sent the email, according to X-Mailer
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Mailer {
+    Missing,
+    /// A desktop or webmail client people use directly
+    Client,
+    /// A library or bulk sending tool
+    Bulk,
+    Unknown,
+}
+
+impl Default for Mailer {
+    fn default() -> Mailer {
+        Mailer::Missing
+    }
+}
+
+impl Mailer {
+    fn from_header(x_mailer: Option<&str>) -> Mailer {
+        let x_mailer = match x_mailer {
+            Some(x_mailer) => x_mailer.to_lowercase(),
+            None => return Mailer::Missing,
+        };
+
+        const CLIENTS: &'static [&'static str] = &[
+            "outlook", "apple mail", "thunderbird", "iphone mail", "evolution", "mutt", "roundcube",
+        ];
+        const BULK: &'static [&'static str] = &[
+            "phpmailer", "swiftmailer", "the bat", "mailchimp", "sendblaster", "atomic mail",
+            "group mail", "massmail", "php/",
+        ];
+
+        if BULK.iter().any(|m| x_mailer.contains(m)) {
+            Mailer::Bulk
+        } else if CLIENTS.iter().any(|m| x_mailer.contains(m)) {
+            Mailer::Client
+        } else {
+            Mailer::Unknown
+        }
+    }
+
+    fn label(&self) -> f32 {
+        match *self {
+            Mailer::Missing => 0.0,
+            Mailer::Client => 1.0,
+            Mailer::Bulk => 2.0,
+            Mailer::Unknown => 3.0,
+        }
+    }
+}
+
+impl FeatureColumns for HeaderFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["received_hops", "received_time_skew", "received_out_of_order",
+             "reply_to_mismatch", "return_path_mismatch", "message_id_mismatch",
+             "missing_date", "missing_message_id", "x_mailer", "forged_reply"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.received_hops as f32,
+             self.received_time_skew,
+             self.received_out_of_order as f32,
+             flag(self.reply_to_mismatch),
+             flag(self.return_path_mismatch),
+             flag(self.message_id_mismatch),
+             flag(self.missing_date),
+             flag(self.missing_message_id),
+             self.x_mailer.label(),
+             flag(self.forged_reply)]
+    }
+}
+
+impl<'a> From<&'a Email> for HeaderFeatures {
+    fn from(email: &'a Email) -> HeaderFeatures {
+        // Received headers are prepended by each hop, so the last is the oldest
+        let stamps: Vec<i64> = email.header_values("Received").iter()
+            .rev()
+            .filter_map(|r| r.rsplit(';').next())
+            .filter_map(|date| dateparse(date.trim()).ok())
+            .collect();
+
+        let received_time_skew = match (stamps.iter().min(), stamps.iter().max()) {
+            (Some(min), Some(max)) => (max - min) as f32,
+            _ => 0.0,
+        };
+
+        let from = email.header("From").and_then(address_domain);
+        let mismatch = |header: &str| {
+            match (from.as_ref(), email.header(header).and_then(address_domain)) {
+                (Some(from), Some(other)) => registrable_domain(from) != registrable_domain(&other),
+                _ => false,
+            }
+        };
+
+        let subject = email.subject().trim_left().to_lowercase();
+        let is_reply = subject.starts_with("re:");
+
+        HeaderFeatures {
+            received_hops: email.header_values("Received").len(),
+            received_time_skew,
+            received_out_of_order: stamps.windows(2).filter(|w| w[1] < w[0]).count(),
+            reply_to_mismatch: mismatch("Reply-To"),
+            return_path_mismatch: mismatch("Return-Path"),
+            message_id_mismatch: mismatch("Message-ID"),
+            missing_date: email.header("Date").is_none(),
+            missing_message_id: email.header("Message-ID").is_none(),
+            x_mailer: Mailer::from_header(email.header("X-Mailer")),
+            forged_reply: is_reply &&
+                email.header("In-Reply-To").is_none() &&
+                email.header("References").is_none(),
+        }
+    }
+}
+
+#[derive_actor]
+impl HeaderAnalyzer {
+    pub fn analyze(&self, email: ParsedEmail, res: HeaderFeaturesResponse) {
+        res(Ok(HeaderFeatures::from(&*email)))
+    }
+}
+
+impl HeaderAnalyzer {
+    pub fn new(self_ref: HeaderAnalyzerActor, system: SystemActor) -> HeaderAnalyzer {
+        HeaderAnalyzer {
+            self_ref,
+            system,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: HeaderAnalyzerMessage,
+                   t: Arc<T>)
+        where T: Fn(HeaderAnalyzerActor, SystemActor) -> HeaderAnalyzer + Send + Sync + 'static
+    {
+        match msg {
+            HeaderAnalyzerMessage::AnalyzeVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in header analyzer".into()).into())
+                );
+            }
+        };
+    }
+}
+
+/// When the email arrived, by the timestamp of the topmost Received header,
+/// else when it says it was sent, by its Date header. Seconds since the epoch.
+pub fn email_timestamp(email: &Email) -> Option<i64> {
+    email.header_values("Received").iter()
+        .filter_map(|r| r.rsplit(';').next())
+        .filter_map(|date| dateparse(date.trim()).ok())
+        .next()
+        .or_else(|| email.header("Date").and_then(|date| dateparse(date.trim()).ok()))
+}
+
+/// The lowercased domain of the address in a From-like header, which may be
+/// `Name <user@domain>`, `<user@domain>` or a bare address. Message-IDs have
+/// the same shape.
+pub fn address_domain(value: &str) -> Option<String> {
+    let address = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+
+    let domain = match address.rfind('@') {
+        Some(at) => &address[at + 1..],
+        None => return None,
+    };
+
+    let domain = domain.trim().trim_right_matches('.').to_lowercase();
+    if domain.is_empty() {
+        None
+    } else {
+        Some(domain)
+    }
+}
+
+fn flag(b: bool) -> f32 {
+    if b { 1.0 } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_mismatches() {
+        let headers = vec![
+            ("From", "Bank <support@mail.bank.com>"),
+            ("Reply-To", "collect@example.net"),
+            ("Message-ID", "<1234@bank.com>"),
+            ("Subject", "RE: your account"),
+            ("X-Mailer", "PHPMailer 5.2"),
+        ];
+
+        let email = Email {
+            headers: headers.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect(),
+            ..Email::default()
+        };
+
+        let features = HeaderFeatures::from(&email);
+
+        assert!(features.reply_to_mismatch);
+        assert!(!features.message_id_mismatch);
+        assert!(!features.return_path_mismatch);
+        assert!(features.missing_date);
+        assert!(features.forged_reply);
+        assert_eq!(features.x_mailer, Mailer::Bulk);
+    }
+
+    #[test]
+    fn co_uk_domains_differ() {
+        let headers = vec![
+            ("From", "Bank <support@mail.bank.co.uk>"),
+            ("Return-Path", "<bounce@evil.co.uk>"),
+            ("Message-ID", "<1234@bank.co.uk>"),
+        ];
+
+        let email = Email {
+            headers: headers.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())).collect(),
+            ..Email::default()
+        };
+
+        let features = HeaderFeatures::from(&email);
+
+        assert!(features.return_path_mismatch);
+        assert!(!features.message_id_mismatch);
+    }
+}
@@ -0,0 +1,325 @@
+use select::document::Document;
+use select::node::Node;
+
+use feature_vector::FeatureColumns;
+
+/// Elements whose text is never rendered as part of the body
+const NON_CONTENT: &'static [&'static str] = &["script", "style", "head", "template"];
+
+/// Elements that don't break a line, so text either side of them runs together
+const INLINE: &'static [&'static str] = &[
+    "a", "b", "i", "u", "em", "strong", "small", "span", "font", "sub", "sup", "abbr", "mark",
+];
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HiddenFeatures {
+    /// Hidden characters per visible character
+    pub hidden_text_ratio: f32,
+    /// Zero width and other invisible characters
+    pub invisible_chars: usize,
+    /// Words mixing Latin with Cyrillic or Greek letters, e.g. homoglyphs
+    pub mixed_script_words: usize,
+    /// Elements hidden by CSS or colour
+    pub css_tricks: usize,
+    /// Comments splitting a word in two
+    pub comment_splits: usize,
+}
+
+impl FeatureColumns for HiddenFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["hidden_text_ratio", "invisible_chars", "mixed_script_words", "css_tricks",
+             "comment_splits"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.hidden_text_ratio,
+             self.invisible_chars as f32,
+             self.mixed_script_words as f32,
+             self.css_tricks as f32,
+             self.comment_splits as f32]
+    }
+}
+
+/// What a reader actually sees of an email, and the tricks used to hide the rest
+#[derive(Clone, Debug, Default)]
+pub struct HiddenContent {
+    pub features: HiddenFeatures,
+    pub visible_text: String,
+}
+
+impl HiddenContent {
+    /// For emails without HTML there is nothing to hide text with but the
+    /// characters themselves
+    pub fn from_text(text: &str) -> HiddenContent {
+        let visible_text = collapse_whitespace(&strip_invisible(text));
+
+        HiddenContent {
+            features: HiddenFeatures {
+                invisible_chars: count_invisible(text),
+                mixed_script_words: count_mixed_script(&visible_text),
+                ..HiddenFeatures::default()
+            },
+            visible_text,
+        }
+    }
+}
+
+impl<'a> From<&'a Document> for HiddenContent {
+    fn from(document: &'a Document) -> HiddenContent {
+        let mut features = HiddenFeatures::default();
+        let mut visible = String::new();
+        let mut hidden_chars = 0;
+
+        let nodes = (0..document.nodes.len()).filter_map(|i| document.nth(i));
+        for node in nodes {
+            if node.name().is_some() {
+                if hides(&node) {
+                    features.css_tricks += 1;
+                }
+                continue;
+            }
+
+            if node.as_comment().is_some() {
+                if splits_word(&node) {
+                    features.comment_splits += 1;
+                }
+                continue;
+            }
+
+            let text = match node.as_text() {
+                Some(text) => text,
+                None => continue,
+            };
+
+            features.invisible_chars += count_invisible(text);
+
+            let ancestors = ancestors(&node);
+            if ancestors.iter().any(|a| a.name().map(|n| NON_CONTENT.contains(&n)).unwrap_or(false)) {
+                continue;
+            }
+
+            if ancestors.iter().any(hides) {
+                hidden_chars += text.trim().chars().count();
+                continue;
+            }
+
+            if !joins_previous(&node) {
+                visible.push(' ');
+            }
+            visible.push_str(text);
+        }
+
+        let visible_text = collapse_whitespace(&strip_invisible(&visible));
+        let visible_chars = visible_text.chars().count();
+
+        features.hidden_text_ratio = hidden_chars as f32 / ::std::cmp::max(visible_chars, 1) as f32;
+        features.mixed_script_words = count_mixed_script(&visible_text);
+
+        HiddenContent {
+            features,
+            visible_text,
+        }
+    }
+}
+
+fn ancestors<'a>(node: &Node<'a>) -> Vec<Node<'a>> {
+    let mut ancestors = Vec::new();
+    let mut current = node.parent();
+    while let Some(parent) = current {
+        current = parent.parent();
+        ancestors.push(parent);
+    }
+    ancestors
+}
+
+/// Whether an element's content can't be seen
+fn hides(node: &Node) -> bool {
+    if node.attr("hidden").is_some() {
+        return true;
+    }
+
+    let style: String = node.attr("style")
+        .unwrap_or("")
+        .to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+
+    if style.contains("display:none") || style.contains("visibility:hidden") {
+        return true;
+    }
+
+    if is_zero(&style, "font-size") || is_zero(&style, "opacity") {
+        return true;
+    }
+
+    if (is_zero(&style, "height") || is_zero(&style, "max-height")) && style.contains("overflow:hidden") {
+        return true;
+    }
+
+    // Pushed far off screen
+    if style.contains(":-9999") || style.contains("text-indent:-") {
+        return true;
+    }
+
+    let color = css_value(&style, "color")
+        .or_else(|| node.attr("color").map(|c| c.to_lowercase()));
+
+    match color {
+        Some(ref color) if is_white(color) => background_is_white(node),
+        _ => false,
+    }
+}
+
+/// Text in white is only hidden if whatever it sits on is white too. Without
+/// a background of its own it sits on the (white) page.
+fn background_is_white(node: &Node) -> bool {
+    let mut current = Some(node.clone());
+    while let Some(n) = current {
+        let style = n.attr("style").unwrap_or("").to_lowercase().replace(' ', "");
+        let background = css_value(&style, "background-color")
+            .or_else(|| css_value(&style, "background"))
+            .or_else(|| n.attr("bgcolor").map(|c| c.to_lowercase()));
+
+        if let Some(background) = background {
+            return is_white(&background);
+        }
+
+        current = n.parent();
+    }
+
+    true
+}
+
+fn css_value(style: &str, property: &str) -> Option<String> {
+    style.split(';')
+        .filter_map(|declaration| {
+            let mut parts = declaration.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name.trim() == property => Some(value.trim().to_owned()),
+                _ => None,
+            }
+        })
+        .last()
+}
+
+fn is_white(color: &str) -> bool {
+    let color = color.trim_right_matches("!important");
+    match color {
+        "white" | "#fff" | "#ffffff" | "rgb(255,255,255)" => true,
+        _ => false,
+    }
+}
+
+/// `property:0` but not `property:0.8em`
+fn is_zero(style: &str, property: &str) -> bool {
+    css_value(style, property)
+        .map(|value| {
+            let number: String = value.chars()
+                .take_while(|c| c.is_digit(10) || *c == '.')
+                .collect();
+            number.parse::<f32>().map(|n| n == 0.0).unwrap_or(false)
+        })
+        .unwrap_or(false)
+}
+
+/// Text directly after an inline element or a comment continues the same word
+fn joins_previous(node: &Node) -> bool {
+    match node.prev() {
+        Some(prev) => {
+            prev.as_comment().is_some() ||
+                prev.name().map(|n| INLINE.contains(&n)).unwrap_or(false)
+        }
+        None => {
+            node.parent()
+                .and_then(|p| p.name().map(|n| INLINE.contains(&n)))
+                .unwrap_or(false)
+        }
+    }
+}
+
+fn splits_word(comment: &Node) -> bool {
+    let before = comment.prev().and_then(|n| n.as_text().map(|t| t.to_owned()));
+    let after = comment.next().and_then(|n| n.as_text().map(|t| t.to_owned()));
+
+    match (before, after) {
+        (Some(before), Some(after)) => {
+            before.chars().last().map(char::is_alphanumeric).unwrap_or(false) &&
+                after.chars().next().map(char::is_alphanumeric).unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+fn is_invisible(c: char) -> bool {
+    match c {
+        '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}' | '\u{00AD}' | '\u{180E}' => true,
+        _ => false,
+    }
+}
+
+fn count_invisible(text: &str) -> usize {
+    text.chars().filter(|c| is_invisible(*c)).count()
+}
+
+pub fn strip_invisible(text: &str) -> String {
+    text.chars().filter(|c| !is_invisible(*c)).collect()
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[derive(PartialEq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+}
+
+fn script(c: char) -> Option<Script> {
+    if (c as u32) < 0x80 {
+        if c.is_alphabetic() { Some(Script::Latin) } else { None }
+    } else if c >= '\u{00C0}' && c <= '\u{024F}' {
+        Some(Script::Latin)
+    } else if c >= '\u{0370}' && c <= '\u{03FF}' {
+        Some(Script::Greek)
+    } else if c >= '\u{0400}' && c <= '\u{04FF}' {
+        Some(Script::Cyrillic)
+    } else {
+        None
+    }
+}
+
+fn count_mixed_script(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|word| {
+            let mut scripts = word.chars().filter_map(script);
+            match scripts.next() {
+                Some(first) => scripts.any(|s| s != first),
+                None => false,
+            }
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_content() {
+        let html = "<div>Your acc<!-- x -->ount is l\u{200B}ocked</div>\
+                    <div style=\"display: none\">lorem ipsum dolor sit amet</div>\
+                    <p>Visit р\u{0430}ypal</p>";
+
+        let hidden = HiddenContent::from(&Document::from(html));
+
+        assert_eq!(hidden.visible_text, "Your account is locked Visit р\u{0430}ypal");
+        assert_eq!(hidden.features.comment_splits, 1);
+        assert_eq!(hidden.features.invisible_chars, 1);
+        assert_eq!(hidden.features.css_tricks, 1);
+        assert_eq!(hidden.features.mixed_script_words, 1);
+        assert!(hidden.features.hidden_text_ratio > 0.5);
+    }
+}
@@ -22,7 +22,7 @@ pub struct HtmlParser {
 }
 
 type ParseResponse = Arc<Fn(Result<Html>) + Send + Sync + 'static>;
-type Html = Document;
+pub type Html = Document;
 
 #[derive_actor]
 impl HtmlParser {
@@ -0,0 +1,413 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::cmp::min;
+use std::sync::Arc;
+
+use errors::*;
+use email::*;
+use config::BrandConfig;
+use headers::address_domain;
+use public_suffix::registrable_domain;
+use feature_vector::FeatureColumns;
+
+/// Brand labels shorter than this are too likely to match unrelated domains
+/// and display names by chance, so they're only compared exactly
+const MIN_FUZZY_LENGTH: usize = 4;
+
+/// Characters commonly swapped in for ASCII letters, after lowercasing.
+/// A small subset of Unicode's confusables.txt.
+const CONFUSABLES: &'static [(char, char)] = &[
+    // Cyrillic
+    ('а', 'a'), ('в', 'b'), ('е', 'e'), ('ё', 'e'), ('һ', 'h'), ('і', 'i'), ('ї', 'i'),
+    ('ј', 'j'), ('к', 'k'), ('ӏ', 'l'), ('м', 'm'), ('н', 'h'), ('о', 'o'), ('р', 'p'),
+    ('с', 'c'), ('ѕ', 's'), ('т', 't'), ('у', 'y'), ('х', 'x'), ('ԁ', 'd'), ('ԛ', 'q'),
+    ('ԝ', 'w'), ('ь', 'b'),
+    // Greek
+    ('α', 'a'), ('β', 'b'), ('ε', 'e'), ('η', 'n'), ('ι', 'i'), ('κ', 'k'), ('ν', 'v'),
+    ('ο', 'o'), ('ρ', 'p'), ('τ', 't'), ('υ', 'u'), ('χ', 'x'), ('ω', 'w'),
+    // Latin lookalikes and digits
+    ('ı', 'i'), ('ł', 'l'), ('ɡ', 'g'), ('ɑ', 'a'), ('0', 'o'), ('1', 'l'), ('3', 'e'),
+    ('5', 's'), ('!', 'i'), ('|', 'l'),
+];
+
+/// What a string looks like once confusable characters are folded together,
+/// so that `pаypa1` (Cyrillic а, digit 1) and `paypal` compare equal
+pub fn skeleton(s: &str) -> String {
+    let folded: String = s.to_lowercase()
+        .chars()
+        .map(|c| match CONFUSABLES.iter().find(|&&(from, _)| from == c) {
+            Some(&(_, to)) => to,
+            None => c,
+        })
+        // i and l are confusable with each other as well as with 1 and |
+        .map(|c| if c == 'i' { 'l' } else { c })
+        .collect();
+
+    folded.replace("rn", "m").replace("vv", "w")
+}
+
+/// Levenshtein distance, over chars rather than bytes
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut previous: Vec<usize> = (0..b.len() + 1).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for i in 0..a.len() {
+        current[0] = i + 1;
+        for j in 0..b.len() {
+            let substitution = previous[j] + if a[i] == b[j] { 0 } else { 1 };
+            current[j + 1] = min(substitution, min(previous[j + 1] + 1, current[j] + 1));
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+/// Decodes any `xn--` labels of an internationalized domain name
+pub fn decode_idn(domain: &str) -> String {
+    domain.split('.')
+        .map(|label| {
+            if label.to_lowercase().starts_with("xn--") {
+                decode_punycode(&label[4..]).unwrap_or_else(|| label.to_owned())
+            } else {
+                label.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// RFC 3492 Punycode decoding, None for malformed input
+fn decode_punycode(input: &str) -> Option<String> {
+    const BASE: u64 = 36;
+    const TMIN: u64 = 1;
+    const TMAX: u64 = 26;
+
+    let (basic, extended) = match input.rfind('-') {
+        Some(i) => (&input[..i], &input[i + 1..]),
+        None => ("", input),
+    };
+
+    let mut output: Vec<char> = basic.chars().collect();
+    let mut n: u64 = 128;
+    let mut i: u64 = 0;
+    let mut bias: u64 = 72;
+    let mut digits = extended.chars();
+
+    loop {
+        let old_i = i;
+        let mut w = 1;
+        let mut k = BASE;
+
+        let mut first = true;
+        loop {
+            let digit = match digits.next() {
+                Some(c @ 'a'...'z') => c as u64 - 'a' as u64,
+                Some(c @ 'A'...'Z') => c as u64 - 'A' as u64,
+                Some(c @ '0'...'9') => c as u64 - '0' as u64 + 26,
+                // Running out between code points is the normal end of input
+                None if first => return Some(output.into_iter().collect()),
+                _ => return None,
+            };
+            first = false;
+
+            i += digit * w;
+            let t = if k <= bias {
+                TMIN
+            } else if k >= bias + TMAX {
+                TMAX
+            } else {
+                k - bias
+            };
+
+            if digit < t {
+                break;
+            }
+
+            w *= BASE - t;
+            k += BASE;
+
+            if i > 0x10FFFF * 64 || w > 0x10FFFF * 64 {
+                return None;
+            }
+        }
+
+        let length = output.len() as u64 + 1;
+        bias = adapt(i - old_i, length, old_i == 0);
+        n += i / length;
+        i %= length;
+
+        let c = match std::char::from_u32(n as u32) {
+            Some(c) if n <= 0x10FFFF => c,
+            _ => return None,
+        };
+
+        output.insert(i as usize, c);
+        i += 1;
+    }
+}
+
+fn adapt(delta: u64, points: u64, first: bool) -> u64 {
+    let mut delta = if first { delta / 700 } else { delta / 2 };
+    delta += delta / points;
+
+    let mut k = 0;
+    while delta > ((36 - 1) * 26) / 2 {
+        delta /= 36 - 1;
+        k += 36;
+    }
+
+    k + (36 * delta) / (delta + 38)
+}
+
+/// The label registered under a domain's public suffix, e.g. `paypal` for
+/// www.paypal.com and `amazon` for amazon.co.uk
+fn brand_label(domain: &str) -> String {
+    registrable_domain(domain).split('.').next().unwrap_or("").to_owned()
+}
+
+/// Whether `needle` occurs in `label` between hyphens or the label's ends, so
+/// `paypal-secure` contains `paypal` but `purchase` doesn't contain `chase`
+fn contains_word(label: &str, needle: &str) -> bool {
+    label.match_indices(needle).any(|(i, _)| {
+        let before = label[..i].chars().next_back();
+        let after = label[i + needle.len()..].chars().next();
+        before.map(|c| c == '-').unwrap_or(true) && after.map(|c| c == '-').unwrap_or(true)
+    })
+}
+
+/// Whether `host` is, or is under, one of the brand's real domains
+fn is_genuine(host: &str, brand: &BrandConfig) -> bool {
+    brand.domains.iter().any(|domain| {
+        let domain = domain.to_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+/// Whether a domain that isn't the brand's looks like one of the brand's
+fn is_lookalike(host: &str, brand: &BrandConfig, max_distance: usize) -> bool {
+    let host = decode_idn(&host.to_lowercase());
+    if is_genuine(&host, brand) {
+        return false;
+    }
+
+    let label = brand_label(&host);
+    let label_skeleton = skeleton(&label);
+
+    brand.domains.iter().any(|domain| {
+        let brand = brand_label(&domain.to_lowercase());
+        if brand.is_empty() {
+            return false;
+        }
+
+        let brand_skeleton = skeleton(&brand);
+        let fuzzy = brand.chars().count() >= MIN_FUZZY_LENGTH;
+
+        label_skeleton == brand_skeleton ||
+            (fuzzy && edit_distance(&label, &brand) <= max_distance) ||
+            (fuzzy && contains_word(&label_skeleton, &brand_skeleton))
+    })
+}
+
+/// The display name of a From-like header, without quotes
+fn display_name(from: &str) -> String {
+    match from.find('<') {
+        Some(i) => from[..i].trim().trim_matches('"').trim().to_owned(),
+        None => String::new(),
+    }
+}
+
+fn names_brand(display_name: &str, brand: &BrandConfig) -> bool {
+    let name = skeleton(&brand.name);
+    if name.chars().count() < MIN_FUZZY_LENGTH {
+        return false;
+    }
+
+    let words: String = skeleton(display_name).chars().filter(|c| c.is_alphanumeric()).collect();
+    words.contains(&name.chars().filter(|c| c.is_alphanumeric()).collect::<String>())
+}
+
+pub struct ImpersonationDetector {
+    self_ref: ImpersonationDetectorActor,
+    system: SystemActor,
+    brands: Arc<Vec<BrandConfig>>,
+    max_distance: usize,
+}
+
+type ImpersonationFeaturesResponse = std::sync::Arc<Fn(Result<ImpersonationFeatures>) + Send + Sync + 'static>;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ImpersonationFeatures {
+    /// The From domain looks like a protected brand's but isn't
+    pub lookalike_from_domain: bool,
+    /// The display name names a brand the From domain doesn't belong to
+    pub display_name_impersonation: bool,
+    pub lookalike_link_domains: usize,
+    /// Internationalized domains among the From and link domains
+    pub punycode_domains: usize,
+    pub reasons: Vec<String>,
+}
+
+impl FeatureColumns for ImpersonationFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["lookalike_from_domain", "display_name_impersonation", "lookalike_link_domains",
+             "punycode_domains"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![flag(self.lookalike_from_domain),
+             flag(self.display_name_impersonation),
+             self.lookalike_link_domains as f32,
+             self.punycode_domains as f32]
+    }
+}
+
+impl ImpersonationFeatures {
+    pub fn from_email(email: &Email,
+                      link_hosts: &[String],
+                      brands: &[BrandConfig],
+                      max_distance: usize) -> ImpersonationFeatures {
+        let mut features = ImpersonationFeatures::default();
+        let mut impersonated: Vec<&str> = Vec::new();
+
+        let from = email.header("From").unwrap_or("");
+        let from_domain = address_domain(from).unwrap_or_default();
+        let display_name = display_name(from);
+
+        for brand in brands {
+            let mut matched = false;
+
+            if !from_domain.is_empty() && is_lookalike(&from_domain, brand, max_distance) {
+                features.lookalike_from_domain = true;
+                matched = true;
+            }
+
+            if !display_name.is_empty() && names_brand(&display_name, brand) &&
+                !is_genuine(&decode_idn(&from_domain), brand) {
+                features.display_name_impersonation = true;
+                matched = true;
+            }
+
+            let lookalike_links = link_hosts.iter()
+                .filter(|host| is_lookalike(host, brand, max_distance))
+                .count();
+            features.lookalike_link_domains += lookalike_links;
+
+            if matched || lookalike_links > 0 {
+                impersonated.push(brand.name.as_str());
+            }
+        }
+
+        features.punycode_domains = link_hosts.iter()
+            .chain(std::iter::once(&from_domain))
+            .filter(|host| host.split('.').any(|label| label.starts_with("xn--")))
+            .count();
+
+        features.reasons = impersonated.into_iter()
+            .map(|brand| format!("impersonation of {}", brand))
+            .collect();
+
+        features
+    }
+}
+
+fn flag(b: bool) -> f32 {
+    if b { 1.0 } else { 0.0 }
+}
+
+#[derive_actor]
+impl ImpersonationDetector {
+    /// `link_hosts` are the hosts HtmlParser found linked to
+    pub fn analyze(&self, email: ParsedEmail, link_hosts: Vec<String>, res: ImpersonationFeaturesResponse) {
+        res(Ok(ImpersonationFeatures::from_email(&*email, &link_hosts, &self.brands, self.max_distance)))
+    }
+}
+
+impl ImpersonationDetector {
+    pub fn new(brands: Vec<BrandConfig>,
+               max_distance: usize,
+               self_ref: ImpersonationDetectorActor,
+               system: SystemActor) -> ImpersonationDetector {
+        ImpersonationDetector {
+            self_ref,
+            system,
+            brands: Arc::new(brands),
+            max_distance,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: ImpersonationDetectorMessage,
+                   t: Arc<T>)
+        where T: Fn(ImpersonationDetectorActor, SystemActor) -> ImpersonationDetector + Send + Sync + 'static
+    {
+        match msg {
+            ImpersonationDetectorMessage::AnalyzeVariant { email, link_hosts, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in impersonation detector".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_lookalikes() {
+        let brands = vec![BrandConfig {
+            name: "PayPal".to_owned(),
+            domains: vec!["paypal.com".to_owned()],
+        }];
+
+        assert_eq!(decode_idn("xn--pypal-4ve.com"), "p\u{430}ypal.com");
+        assert_eq!(decode_idn("xn--bcher-kva.de"), "bücher.de");
+        assert_eq!(skeleton("p\u{430}ypa1"), skeleton("paypal"));
+        assert_eq!(edit_distance("paypa1", "paypal"), 1);
+
+        assert!(is_lookalike("paypa1.com", &brands[0], 1));
+        assert!(is_lookalike("xn--pypal-4ve.com", &brands[0], 1));
+        assert!(is_lookalike("paypal-secure.net", &brands[0], 1));
+        assert!(!is_lookalike("www.paypal.com", &brands[0], 1));
+        assert!(!is_lookalike("example.com", &brands[0], 1));
+
+        let brand = |name: &str, domain: &str| BrandConfig {
+            name: name.to_owned(),
+            domains: vec![domain.to_owned()],
+        };
+
+        // Compared by the label under the public suffix, not the first of two
+        assert_eq!(brand_label("www.amazon.co.uk"), "amazon");
+        assert!(!is_lookalike("amazon.co.uk", &brand("Amazon", "amazon.co.uk"), 1));
+        assert!(is_lookalike("amazon-billing.co.uk", &brand("Amazon", "amazon.co.uk"), 1));
+        assert!(is_lookalike("arnazon.co.uk", &brand("Amazon", "amazon.com"), 1));
+
+        // Brands inside a longer word aren't lookalikes
+        assert!(!is_lookalike("purchase.com", &brand("Chase", "chase.com"), 1));
+        assert!(!is_lookalike("pineapple.com", &brand("Apple", "apple.com"), 1));
+        assert!(is_lookalike("apple-id.com", &brand("Apple", "apple.com"), 1));
+        assert!(is_lookalike("verify-chase.com", &brand("Chase", "chase.com"), 1));
+
+        let email = Email {
+            headers: vec![("From".to_owned(), "\"PayPal Support\" <help@mailer.example.com>".to_owned())],
+            ..Email::default()
+        };
+
+        let features = ImpersonationFeatures::from_email(&email, &["paypa1.com".to_owned()], &brands, 1);
+        assert!(features.display_name_impersonation);
+        assert!(!features.lookalike_from_domain);
+        assert_eq!(features.lookalike_link_domains, 1);
+        assert_eq!(features.reasons, vec!["impersonation of PayPal".to_owned()]);
+    }
+}
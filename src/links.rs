@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+
+use select::document::Document;
+use select::predicate::{Attr, Name, Predicate};
+
+use feature_vector::FeatureColumns;
+use public_suffix::registrable_domain;
+
+const SHORTENERS: &'static [&'static str] = &[
+    "bit.ly", "tinyurl.com", "goo.gl", "t.co", "ow.ly", "is.gd", "buff.ly",
+    "rebrand.ly", "cutt.ly", "tiny.cc", "shorturl.at", "rb.gy",
+];
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LinkFeatures {
+    pub link_count: usize,
+    pub distinct_domains: usize,
+    /// Anchors whose text reads as a URL on a different domain than the href
+    pub mismatched_anchor_text: usize,
+    pub ip_links: usize,
+    pub shortener_links: usize,
+    pub data_uris: usize,
+    pub javascript_uris: usize,
+    /// Links with an explicit port other than 80 or 443
+    pub nonstandard_port_links: usize,
+}
+
+impl FeatureColumns for LinkFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["link_count", "distinct_domains", "mismatched_anchor_text", "ip_links",
+             "shortener_links", "data_uris", "javascript_uris", "nonstandard_port_links"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.link_count as f32,
+             self.distinct_domains as f32,
+             self.mismatched_anchor_text as f32,
+             self.ip_links as f32,
+             self.shortener_links as f32,
+             self.data_uris as f32,
+             self.javascript_uris as f32,
+             self.nonstandard_port_links as f32]
+    }
+}
+
+impl<'a> From<&'a Document> for LinkFeatures {
+    fn from(document: &'a Document) -> LinkFeatures {
+        let mut features = LinkFeatures::default();
+        let mut domains = HashSet::new();
+
+        for anchor in document.find(Name("a").and(Attr("href", ()))) {
+            let href = anchor.attr("href").unwrap_or("").trim();
+            features.link_count += 1;
+
+            let link = match Link::parse(href) {
+                Some(link) => link,
+                None => continue,
+            };
+
+            match link.scheme.as_ref() {
+                "data" => features.data_uris += 1,
+                "javascript" => features.javascript_uris += 1,
+                _ => (),
+            }
+
+            let host = match link.host {
+                Some(ref host) => host,
+                None => continue,
+            };
+
+            if is_ip_literal(host) {
+                features.ip_links += 1;
+                domains.insert(host.clone());
+            } else {
+                domains.insert(registrable_domain(host));
+            }
+
+            if SHORTENERS.contains(&host.trim_left_matches("www.")) {
+                features.shortener_links += 1;
+            }
+
+            match link.port {
+                Some(80) | Some(443) | None => (),
+                Some(_) => features.nonstandard_port_links += 1,
+            }
+
+            if let Some(shown) = shown_host(&anchor.text()) {
+                if registrable_domain(&shown) != registrable_domain(host) {
+                    features.mismatched_anchor_text += 1;
+                }
+            }
+        }
+
+        features.distinct_domains = domains.len();
+        features
+    }
+}
+
+/// The distinct hosts linked to, other than IP literals
+pub fn link_hosts(document: &Document) -> Vec<String> {
+    let hosts: HashSet<String> = document.find(Name("a").and(Attr("href", ())))
+        .filter_map(|anchor| Link::parse(anchor.attr("href").unwrap_or("").trim()))
+        .filter_map(|link| link.host)
+        .filter(|host| !is_ip_literal(host))
+        .collect();
+
+    hosts.into_iter().collect()
+}
+
+/// The parts of a URL we care about. Hosts are lowercased.
+#[derive(Debug, PartialEq)]
+struct Link {
+    scheme: String,
+    host: Option<String>,
+    port: Option<u16>,
+}
+
+impl Link {
+    /// Only absolute URLs parse, relative links tell us nothing
+    fn parse(url: &str) -> Option<Link> {
+        let colon = match url.find(':') {
+            Some(colon) => colon,
+            None => return None,
+        };
+
+        let scheme = url[..colon].to_lowercase();
+        if scheme.is_empty() || !scheme.chars().all(|c| c.is_alphanumeric() || "+-.".contains(c)) {
+            return None;
+        }
+
+        let rest = &url[colon + 1..];
+        if !rest.starts_with("//") {
+            return Some(Link { scheme, host: None, port: None });
+        }
+
+        let authority = rest[2..].split(|c: char| c == '/' || c == '?' || c == '#')
+            .next()
+            .unwrap_or("");
+        // Drop any user:password@
+        let authority = authority.rsplit('@').next().unwrap_or("");
+
+        let (host, port) = split_port(authority);
+
+        Some(Link {
+            scheme,
+            host: if host.is_empty() { None } else { Some(host.to_lowercase()) },
+            port,
+        })
+    }
+}
+
+fn split_port(authority: &str) -> (&str, Option<u16>) {
+    // IPv6 literals carry their own colons
+    let host_end = if authority.starts_with('[') {
+        authority.find(']').map(|i| i + 1).unwrap_or(authority.len())
+    } else {
+        authority.find(':').unwrap_or(authority.len())
+    };
+
+    let port = authority[host_end..].trim_left_matches(':').parse().ok();
+    (&authority[..host_end], port)
+}
+
+fn is_ip_literal(host: &str) -> bool {
+    host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok()
+}
+
+/// The host shown by anchor text that looks like a URL or a bare domain
+fn shown_host(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() || text.contains(char::is_whitespace) {
+        return None;
+    }
+
+    if let Some(link) = Link::parse(text) {
+        if link.host.is_some() {
+            return link.host;
+        }
+    }
+
+    let host = text.split(|c: char| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+    let looks_like_domain = host.contains('.') &&
+        host.split('.').all(|l| !l.is_empty() && l.chars().all(|c| c.is_alphanumeric() || c == '-')) &&
+        host.rsplit('.').next().map(|tld| tld.chars().all(char::is_alphabetic)).unwrap_or(false);
+
+    if looks_like_domain {
+        Some(host.to_lowercase())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_features() {
+        let html = r#"
+            <a href="http://192.168.0.1:8080/login">www.paypal.com</a>
+            <a href="https://bit.ly/abc">click here</a>
+            <a href="javascript:alert(1)">x</a>
+            <a href="https://www.example.com/">example.com</a>
+        "#;
+
+        let features = LinkFeatures::from(&Document::from(html));
+
+        assert_eq!(features.link_count, 4);
+        assert_eq!(features.distinct_domains, 3);
+        assert_eq!(features.mismatched_anchor_text, 1);
+        assert_eq!(features.ip_links, 1);
+        assert_eq!(features.shortener_links, 1);
+        assert_eq!(features.javascript_uris, 1);
+        assert_eq!(features.nonstandard_port_links, 1);
+    }
+
+    #[test]
+    fn co_uk_anchor_text() {
+        let html = r#"
+            <a href="https://login.evil.co.uk/">www.bank.co.uk</a>
+            <a href="https://online.bank.co.uk/">www.bank.co.uk</a>
+        "#;
+
+        let features = LinkFeatures::from(&Document::from(html));
+
+        assert_eq!(features.distinct_domains, 2);
+        assert_eq!(features.mismatched_anchor_text, 1);
+    }
+}
@@ -6,24 +6,37 @@ extern crate derive_builder;
 extern crate derive_aktor;
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate serde_derive;
 
 
 extern crate aktors;
+extern crate base64;
 extern crate byteorder;
 extern crate channel;
+extern crate clap;
 extern crate futures;
 extern crate lru_time_cache;
 extern crate mailparse;
+extern crate openssl;
 extern crate rand;
 extern crate rayon;
 extern crate redis;
 extern crate reqwest;
+extern crate rustlearn;
 extern crate select;
 extern crate sentiment as _sentiment;
+extern crate serde;
+extern crate serde_json;
 extern crate stopwatch;
+extern crate toml;
+extern crate trust_dns_resolver;
 extern crate twox_hash;
 extern crate uuid;
 extern crate walkdir;
+extern crate zip;
 
 macro_rules! random_panic {
     ($x:expr) => {
@@ -62,18 +75,49 @@ pub mod state;
 pub mod email_reader;
 pub mod html;
 pub mod files;
+pub mod feature_vector;
+pub mod dataset;
+pub mod rust_model;
+pub mod rules_model;
+pub mod config;
+pub mod verdict;
+pub mod supervisor;
+pub mod reload;
+pub mod shadow;
+pub mod train_extract;
+pub mod cli;
+pub mod report;
+pub mod text_analysis;
+pub mod public_suffix;
+pub mod headers;
+pub mod links;
+pub mod hidden;
+pub mod mime;
+pub mod attachments;
+pub mod office;
+pub mod resolver;
+pub mod spf;
+pub mod dkim;
+pub mod authentication;
+pub mod auth_results;
+pub mod impersonation;
+pub mod feature_store;
+pub mod verdict_cache;
+pub mod campaign;
+pub mod fuzzy_hash;
 
 use aktors::actor::SystemActor;
 use stopwatch::Stopwatch;
 use std::time::Duration;
 use walkdir::WalkDir;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use rayon::prelude::*;
 
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use errors::*;
 use sentiment::*;
@@ -84,36 +128,245 @@ use service::*;
 use email_reader::*;
 use state::*;
 use files::*;
+use rust_model::*;
+use config::*;
+use shadow::*;
+use verdict::*;
+use report::*;
+use text_analysis::*;
+use headers::*;
+use html::*;
+use mime::*;
+use attachments::*;
+use office::*;
+use authentication::*;
+use auth_results::*;
+use impersonation::*;
+use feature_store::FeatureStore;
+use resolver::{offline_resolver_from_config, resolver_from_config, Resolver};
+use verdict_cache::{CacheStats, VerdictCache, verdict_cache_from_config};
+use campaign::*;
+use fuzzy_hash::*;
 
 fn main() {
     // TODO: A macro where message timings (send/ receive duration) are automatically sent to
     // TODO: some separate place, should just be a matter of having a stopwatch on the sender
     // TODO: and then call it right before route_msg
 
-    let system = SystemActor::new();
+    let matches = cli::app().get_matches();
+    let config = cli::load_config(&matches).expect("Failed to load config");
+
+    match matches.subcommand() {
+        ("scan", Some(args)) => scan(Path::new(args.value_of("path").unwrap()), &config),
+        ("extract", Some(args)) => {
+            train_extract(Path::new(args.value_of("corpus").unwrap()),
+                          Path::new(args.value_of("output").unwrap()),
+                          &config)
+        }
+        ("export-features", Some(args)) => {
+            export_features(Path::new(args.value_of("corpus").unwrap()),
+                            Path::new(args.value_of("output").unwrap()),
+                            &config)
+        }
+        ("train", Some(args)) => {
+            RustModel::train(Path::new(args.value_of("data").unwrap()),
+                             Path::new(args.value_of("output").unwrap()))
+                .expect("Failed to train model");
+        }
+        ("evaluate", Some(args)) => evaluate(Path::new(args.value_of("corpus").unwrap()), &config),
+        ("serve", Some(_)) => serve(&config),
+        ("explain", Some(args)) => explain(Path::new(args.value_of("eml").unwrap()), &config),
+        ("signatures", Some(args)) => signatures(Path::new(args.value_of("path").unwrap())),
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
 
-    let mut walker = WalkDir::new("./TRAINING/");
-    let paths = walker
+fn scan(path: &Path, config: &Config) {
+    let paths = WalkDir::new(path)
         .into_iter()
         .filter_map(std::result::Result::ok)
         .filter(|p| p.file_type().is_file())
         .map(|s| s.path().to_owned())
         .filter(|p| p.extension() == Some(std::ffi::OsStr::new("eml")))
-        .map(|s| s)
         .collect::<Vec<_>>();
 
-    let worker = get_workers(22, system.clone());
+    let system = SystemActor::new();
+    let worker = get_workers(system.clone(), config, resolver_from_config(&config.authentication));
+
+    if config.output == OutputFormat::Csv {
+        println!("{}", CSV_HEADER);
+    }
 
     let mut sw = Stopwatch::new();
+    sw.start();
+
+    classify(worker, paths, |path, verdict| {
+        println!("{}", format_verdict(config.output, &path, &verdict));
+    });
+
+    println!("{} millis", sw.elapsed_ms());
+}
+
+/// Classifies a labeled corpus and prints how the verdicts compare to the labels
+fn evaluate(corpus: &Path, config: &Config) {
+    let emails = load_corpus(corpus);
+    let labels: HashMap<PathBuf, bool> = emails.iter()
+        .map(|email| (email.path.clone(), email.spam))
+        .collect();
+
+    let system = SystemActor::new();
+    let worker = get_workers(system.clone(), config, offline_resolver_from_config(&config.authentication));
+
+    let mut evaluation = Evaluation::default();
+    classify(worker, labels.keys().cloned().collect(), |path, verdict| {
+        match verdict {
+            Ok(verdict) => evaluation.record(labels[&path], &verdict),
+            Err(e) => {
+                println!("Failed to classify {:#?}: {}", path, e);
+                evaluation.failed += 1;
+            }
+        }
+    });
+
+    println!("{}", evaluation.report());
+}
+
+/// Classifies the paths read from stdin, one per line, printing each verdict as
+/// soon as it's ready
+fn serve(config: &Config) {
+    let system = SystemActor::new();
+    let worker = get_workers(system.clone(), config, resolver_from_config(&config.authentication));
+
+    let format = config.output;
+    if format == OutputFormat::Csv {
+        println!("{}", CSV_HEADER);
+    }
+
+    let outstanding = Arc::new(AtomicUsize::new(0));
+    let finished = Arc::new(Mutex::new(HashSet::new()));
+
+    let stdin = std::io::stdin();
+    for (id, line) in stdin.lock().lines().enumerate() {
+        let path = match line {
+            Ok(ref line) if line.trim().is_empty() => continue,
+            Ok(line) => PathBuf::from(line.trim()),
+            Err(_) => break,
+        };
+
+        outstanding.fetch_add(1, Ordering::SeqCst);
+
+        let outstanding = outstanding.clone();
+        let finished = finished.clone();
+        worker.add_file(path.clone(), Arc::new(move |verdict| {
+            // Recoverable errors will be retried, only report final verdicts and only once
+            if !is_final(&verdict) || !finished.lock().unwrap().insert(id) {
+                return;
+            }
+
+            println!("{}", format_verdict(format, &path, &verdict));
+            outstanding.fetch_sub(1, Ordering::SeqCst);
+        }));
+    }
+
+    while outstanding.load(Ordering::SeqCst) > 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Prints every feature extracted from a single email along with its verdict
+fn explain(path: &Path, config: &Config) {
+    let mut email = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut email))
+        .expect(&format!("Failed to read {:#?}", path));
+    let email = Arc::new(email);
+
+    let system = SystemActor::new();
+    let verification = Arc::new(VerificationPool::new(resolver_from_config(&config.authentication),
+                                                      &config.authentication));
+    let signatures = signature_db_from_config(&config.fuzzy_hash);
+    let extractor = gen_extractor(system.clone(), config, signatures, verification);
+    let backend = backend_from_config(&config.model, config.reload.as_ref(), system.clone());
+    let model = gen_model(system.clone(), config, backend);
+
+    let mut features = None;
+    for _ in 0..5 {
+        let (tx, rx) = channel::unbounded();
+        extractor.extract(email.clone(), Arc::new(move |features| {
+            tx.send(features);
+        }));
+
+        match rx.recv().expect("Extractor went away") {
+            Ok(f) => {
+                features = Some(f);
+                break;
+            }
+            Err(e) => println!("Failed to extract features: {}", e),
+        }
+    }
+
+    let mut features = features.expect("Could not extract features");
+    let campaigns = CampaignIndex::new(&config.campaigns);
+    features.campaign = campaigns.observe(&hash_email(&email), &features.campaign);
+
+    let vector = features.to_vector();
+    for (column, value) in vector.columns().iter().zip(vector.values()) {
+        println!("{:>30} {}", column, value);
+    }
+
     let (tx, rx) = channel::unbounded();
-    let mut path_count = 0;
-    let mut path_dups = HashMap::new();
+    model.predict(features, Arc::new(move |verdict| {
+        tx.send(verdict);
+    }));
+
+    let verdict = rx.recv().expect("Model went away");
+    println!("{}", format_verdict(config.output, path, &verdict));
+}
+
+/// Prints fuzzy hash signatures for a known bad .eml file, or every one under a
+/// directory, in the format SignatureDb loads. Only the digests are printed,
+/// so they can be shared without the emails.
+fn signatures(path: &Path) {
+    let paths = WalkDir::new(path)
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|p| p.file_type().is_file())
+        .map(|s| s.path().to_owned())
+        .filter(|p| p.extension() == Some(std::ffi::OsStr::new("eml")));
+
+    for path in paths {
+        let mut raw = Vec::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut raw)) {
+            println!("# Failed to read {:#?}: {}", path, e);
+            continue;
+        }
+
+        let email = match mailparse::parse_mail(&raw) {
+            Ok(mail) => Email::from(&mail),
+            Err(e) => {
+                println!("# Failed to parse {:#?}: {}", path, e);
+                continue;
+            }
+        };
+
+        let label = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        for digest in email_digests(&email) {
+            println!("{} {}", digest, label);
+        }
+    }
+}
+
+/// Sends every path to the workers and calls `on_verdict` once per path with
+/// its final verdict - success or an unrecoverable error.
+fn classify<F>(worker: EmailReaderActor, paths: Vec<PathBuf>, mut on_verdict: F)
+    where F: FnMut(PathBuf, Result<Verdict>)
+{
+    let (tx, rx) = channel::unbounded();
+    let path_count = paths.len();
+
     for path in paths.into_iter() {
-        path_count += 1;
-        *path_dups.entry(path.clone()).or_insert(0) += 1;
         let tx = tx.clone();
         worker.add_file(path.clone(), Arc::new(move |prediction| {
-            println!("{:#?} prediction {:#?}", path, prediction);
             tx.send((path.clone(), prediction));
         }));
         std::thread::sleep(Duration::from_millis(2));
@@ -121,73 +374,149 @@ fn main() {
 
     drop(worker);
 
-    for (p, c) in path_dups {
-        if c > 1 {
-            println!("DUPLICATED PATH {:#?}", p);
-        }
-    }
-
-    let mut ok_count = HashMap::new();
-    let mut aborted = HashMap::new();
-    let mut retry_count = HashMap::new();
+    let mut finished = HashSet::new();
+    let mut retries = 0;
 
-
-    sw.start();
     for (path, prediction) in rx {
-        if prediction.is_ok() {
-            let count = ok_count.entry(path.clone()).or_insert(0);
-            *count += 1;
-            let count = *count;
-            if count > 1 {
-                println!("ok_count > 1");
-            }
+        if !is_final(&prediction) {
+            retries += 1;
+            continue;
         }
 
-        if let Err(e) = prediction {
-            match *e.kind() {
-                ErrorKind::RecoverableError(ref e) => {
-                    *retry_count.entry(path.clone()).or_insert(0) += 1;
-                }
-                ErrorKind::UnrecoverableError(ref e) => {
-                    let count = aborted.entry(path.clone()).or_insert(0);
-                    *count += 1;
-                    let count = *count;
-                    if count > 1 {
-                        println!("aborted > 1");
-                    }
-                }
-                ErrorKind::Msg(ref e) => {
-                    *retry_count.entry(path.clone()).or_insert(0) += 1;
-                }
-                _ => *retry_count.entry(path.clone()).or_insert(0) += 1,
-            }
+        if finished.insert(path.clone()) {
+            on_verdict(path, prediction);
         }
 
-        println!("total count {} ok_count {} aborted {} tried {}",
-                 path_count,
-                 ok_count.len(),
-                 aborted.len(),
-                 retry_count.len());
-        if ok_count.len() + aborted.len() == path_count {
+        if finished.len() == path_count {
             break
         }
     }
 
-    println!("{} millis", sw.elapsed_ms());
-    //    loop {
-    //        std::thread::park();
-    //    }
+    println!("classified {} emails with {} retries", finished.len(), retries);
+}
+
+/// Successes and unrecoverable errors are final, anything else will be retried
+fn is_final(prediction: &Result<Verdict>) -> bool {
+    match *prediction {
+        Ok(_) => true,
+        Err(ref e) => match *e.kind() {
+            ErrorKind::UnrecoverableError(_) => true,
+            _ => false,
+        }
+    }
 }
 
-fn get_workers(count: usize, system: SystemActor) -> EmailReaderActor {
+fn load_corpus(corpus: &Path) -> Vec<train_extract::LabeledEmail> {
+    if corpus.is_dir() {
+        train_extract::from_directory(corpus)
+    } else {
+        train_extract::from_manifest(corpus).expect("Failed to read manifest")
+    }
+}
+
+/// Writes a labeled feature CSV for a corpus - either a directory with spam/ and
+/// ham/ subdirectories, or a manifest file of `path,label` lines
+fn train_extract(corpus: &Path, output: &Path, config: &Config) {
+    let emails = load_corpus(corpus);
+
+    println!("Extracting features for {} labeled emails", emails.len());
+
+    let system = SystemActor::new();
+    let campaigns = CampaignIndex::new(&config.campaigns);
+    let verification = Arc::new(VerificationPool::new(offline_resolver_from_config(&config.authentication),
+                                                      &config.authentication));
+    let signatures = signature_db_from_config(&config.fuzzy_hash);
+    let extractors = (0..config.workers.count)
+        .map(|_| gen_extractor(system.clone(), config, signatures.clone(), verification.clone()))
+        .collect();
+
+    let store = open_feature_store(config, &signatures);
+
+    let summary = train_extract::extract_training_data(emails,
+                                                       extractors,
+                                                       store.as_ref().map(|s| &**s),
+                                                       &campaigns,
+                                                       output)
+        .expect("Failed to write training data");
+
+    println!("Wrote {} rows to {:#?} ({} from the feature store), {} emails failed",
+             summary.written, output, summary.stored, summary.failed);
+}
+
+/// Writes a labeled feature CSV using only the features already stored
+fn export_features(corpus: &Path, output: &Path, config: &Config) {
+    let signatures = signature_db_from_config(&config.fuzzy_hash);
+    let store = open_feature_store(config, &signatures).expect("No [feature_store] is configured");
+    let emails = load_corpus(corpus);
+
+    let campaigns = CampaignIndex::new(&config.campaigns);
+    let summary = store.export(&emails, &campaigns, output)
+        .expect("Failed to write training data");
+
+    println!("Wrote {} rows to {:#?}, {} emails weren't stored", summary.written, output, summary.failed);
+}
+
+fn open_feature_store(config: &Config, signatures: &SignatureDb) -> Option<Arc<FeatureStore>> {
+    config.feature_store.as_ref().map(|store| {
+        let store = FeatureStore::open(&store.dir, signatures)
+            .expect(&format!("Failed to open the feature store at {:#?}", store.dir));
+        Arc::new(store)
+    })
+}
+
+/// `resolver` answers the DNS lookups sender authentication makes
+fn get_workers(system: SystemActor, config: &Config, resolver: Arc<Resolver>) -> EmailReaderActor {
+    let count = config.workers.count;
+    let timeout = config.timeouts.actor();
     let mut workers = Vec::with_capacity(count);
 
+    // Loaded once, every worker matches against the same signatures
+    let signatures = signature_db_from_config(&config.fuzzy_hash);
+    let feature_store = open_feature_store(config, &signatures);
+
+    // One cache for every worker, so a repeated email hits whichever worker gets it
+    let verdict_cache = verdict_cache_from_config(&config.prediction_cache);
+    let cache_stats = Arc::new(CacheStats::default());
+    let campaigns = Arc::new(CampaignIndex::new(&config.campaigns));
+    let verification = Arc::new(VerificationPool::new(resolver, &config.authentication));
+
+    // Built once, so a Python backend is one supervised child process and a
+    // reloading backend one watcher, however many workers there are
+    let backend = backend_from_config(&config.model, config.reload.as_ref(), system.clone());
+
+    // One Model actor for every worker, so batches fill up with all of their emails
+    let model = gen_model(system.clone(), config, backend.clone());
+
+    // All workers report to the same comparator so the shadow stats cover every email
+    let shadow = config.shadow.as_ref().map(|shadow| {
+        let backend = backend_from_config(&shadow.model, shadow.reload.as_ref(), system.clone());
+
+        let report_every = shadow.report_every;
+        let comparator =
+            move |self_ref, system| ShadowComparator::new(report_every, self_ref, system);
+
+        Shadow::new(gen_model(system.clone(), config, backend),
+                    ShadowComparatorActor::new(comparator, system.clone(), timeout))
+    });
+
     vec![(); count]
         .par_iter()
-        .map(|_| gen_worker(system.clone()))
+        .map(|_| {
+            gen_worker(system.clone(),
+                       config,
+                       backend.clone(),
+                       model.clone(),
+                       shadow.clone(),
+                       feature_store.clone(),
+                       verdict_cache.clone(),
+                       cache_stats.clone(),
+                       campaigns.clone(),
+                       signatures.clone(),
+                       verification.clone())
+        })
         .collect_into(&mut workers);
 
-    let file_reader_pool = file_reader_pool(system.clone(), 16);
+    let file_reader_pool = file_reader_pool(system.clone(), config.workers.file_readers, timeout);
 
     let w = workers.clone();
     let email_reader = move |self_ref, system|
@@ -196,81 +525,290 @@ fn get_workers(count: usize, system: SystemActor) -> EmailReaderActor {
                          self_ref,
                          system);
 
-    let email_reader = EmailReaderActor::new(email_reader, system.clone(), Duration::from_secs(30));
+    let email_reader = EmailReaderActor::new(email_reader, system.clone(), timeout);
 
     email_reader
 }
 
 
-fn file_reader_pool(system: SystemActor, count: usize) -> FileReaderPoolActor {
+fn file_reader_pool(system: SystemActor, count: usize, timeout: Duration) -> FileReaderPoolActor {
     let mut file_reader_workers = Vec::new();
 
     for _ in 0..count {
         let file_reader =
             move |self_ref, system| LocalFileReader::new(self_ref, system);
-        let file_reader = LocalFileReaderActor::new(file_reader, system.clone(),
-                                                    Duration::from_secs(30));
+        let file_reader = LocalFileReaderActor::new(file_reader, system.clone(), timeout);
 
         file_reader_workers.push(file_reader);
     }
 
-    println!("{:#?}", file_reader_workers.len());
     let file_reader_pool = move |self_ref, system|
         FileReaderPool::new(
             file_reader_workers.clone().into_iter(),
             self_ref,
             system);
 
-    FileReaderPoolActor::new(file_reader_pool, system.clone(),
-                             Duration::from_secs(30))
+    FileReaderPoolActor::new(file_reader_pool, system.clone(), timeout)
 }
 
-fn gen_worker(system: SystemActor) -> SpamDetectionServiceActor {
+fn gen_worker(system: SystemActor,
+              config: &Config,
+              backend: Arc<ModelBackend>,
+              model: ModelActor,
+              shadow: Option<Shadow>,
+              feature_store: Option<Arc<FeatureStore>>,
+              verdict_cache: Arc<VerdictCache>,
+              cache_stats: Arc<CacheStats>,
+              campaigns: Arc<CampaignIndex>,
+              signatures: Arc<SignatureDb>,
+              verification: Arc<VerificationPool>) -> SpamDetectionServiceActor {
+    let timeout = config.timeouts.actor();
+
+    let report_every = config.prediction_cache.report_every;
+    let cache_backend = backend.clone();
     let prediction_cache =
-        move |self_ref, system| PredictionCache::new(self_ref, system);
-    let prediction_cache = PredictionCacheActor::new(prediction_cache, system.clone(), Duration::from_secs(30));
+        move |self_ref, system| PredictionCache::new(
+            verdict_cache.clone(),
+            cache_stats.clone(),
+            report_every,
+            cache_backend.clone(),
+            self_ref,
+            system);
+    let prediction_cache = PredictionCacheActor::new(prediction_cache, system.clone(), timeout);
+
+    let extractor = gen_extractor(system.clone(), config, signatures, verification);
+
+    let service =
+        move |self_ref, system| SpamDetectionService::new(
+            prediction_cache.clone(),
+            extractor.clone(),
+            model.clone(),
+            shadow.clone(),
+            feature_store.clone(),
+            campaigns.clone(),
+            self_ref,
+            system
+        );
+
+    SpamDetectionServiceActor::new(service, system.clone(), timeout)
+}
+
+fn gen_extractor(system: SystemActor,
+                 config: &Config,
+                 signatures: Arc<SignatureDb>,
+                 verification: Arc<VerificationPool>) -> FeatureExtractionManagerActor {
+    let timeout = config.timeouts.actor();
+    let extraction_timeout = config.timeouts.extraction();
 
     let mail_parser =
         move |self_ref, system| MailParser::new(self_ref, system);
-    let mail_parser = MailParserActor::new(mail_parser, system.clone(), Duration::from_secs(30));
+    let mail_parser = MailParserActor::new(mail_parser, system.clone(), timeout);
 
     let sentiment_analyzer =
         move |self_ref, system| SentimentAnalyzer::new(self_ref, system);
-    let sentiment_analyzer = SentimentAnalyzerActor::new(sentiment_analyzer, system.clone(), Duration::from_secs(30));
+    let sentiment_analyzer = SentimentAnalyzerActor::new(sentiment_analyzer, system.clone(), timeout);
 
-    let python_model =
-        move |self_ref, system| PythonModel::new("./model_service/service/prediction_service.py".into());
-    let python_model = PythonModelActor::new(python_model, system.clone(), Duration::from_secs(30));
+    let text_analyzer =
+        move |self_ref, system| TextFeatureExtractor::new(self_ref, system);
+    let text_analyzer = TextFeatureExtractorActor::new(text_analyzer, system.clone(), timeout);
 
-    let model =
-        move |self_ref, system| Model::new(self_ref, system, python_model.clone());
-    let model = ModelActor::new(model, system.clone(), Duration::from_secs(30));
+    let header_analyzer =
+        move |self_ref, system| HeaderAnalyzer::new(self_ref, system);
+    let header_analyzer = HeaderAnalyzerActor::new(header_analyzer, system.clone(), timeout);
+
+    let html_parser =
+        move |self_ref, system| HtmlParser::new(self_ref, system);
+    let html_parser = HtmlParserActor::new(html_parser, system.clone(), timeout);
+
+    let mime_analyzer =
+        move |self_ref, system| MimeAnalyzer::new(self_ref, system);
+    let mime_analyzer = MimeAnalyzerActor::new(mime_analyzer, system.clone(), timeout);
+
+    let attachment_analyzer =
+        move |self_ref, system| AttachmentAnalyzer::new(self_ref, system);
+    let attachment_analyzer = AttachmentAnalyzerActor::new(attachment_analyzer, system.clone(), timeout);
+
+    let document_inspector =
+        move |self_ref, system| DocumentInspector::new(self_ref, system);
+    let document_inspector = DocumentInspectorActor::new(document_inspector, system.clone(), timeout);
+
+    let authentication_verifier =
+        move |self_ref, system| AuthenticationVerifier::new(verification.clone(), self_ref, system);
+    let authentication_verifier =
+        AuthenticationVerifierActor::new(authentication_verifier, system.clone(), timeout);
+
+    let trusted_authserv_ids = config.authentication.trusted_authserv_ids.clone();
+    let auth_results_analyzer =
+        move |self_ref, system| AuthResultsAnalyzer::new(trusted_authserv_ids.clone(), self_ref, system);
+    let auth_results_analyzer = AuthResultsAnalyzerActor::new(auth_results_analyzer, system.clone(), timeout);
+
+    let brands = config.impersonation.brands.clone();
+    let max_distance = config.impersonation.max_distance;
+    let impersonation_detector =
+        move |self_ref, system| ImpersonationDetector::new(brands.clone(), max_distance, self_ref, system);
+    let impersonation_detector = ImpersonationDetectorActor::new(impersonation_detector, system.clone(), timeout);
+
+    let campaign_detector =
+        move |self_ref, system| CampaignDetector::new(self_ref, system);
+    let campaign_detector = CampaignDetectorActor::new(campaign_detector, system.clone(), timeout);
+
+    let max_distance = config.fuzzy_hash.max_distance;
+    let fuzzy_hash_matcher =
+        move |self_ref, system| FuzzyHashMatcher::new(signatures.clone(), max_distance, self_ref, system);
+    let fuzzy_hash_matcher = FuzzyHashMatcherActor::new(fuzzy_hash_matcher, system.clone(), timeout);
 
     let extractor =
         move |self_ref, system|
-            FeatureExtractionManager::new(mail_parser.clone(), sentiment_analyzer.clone(), self_ref, system);
-    let extractor = FeatureExtractionManagerActor::new(extractor, system.clone(), Duration::from_secs(30));
-
-    let service =
-        move |self_ref, system| SpamDetectionService::new(
-            prediction_cache.clone(),
-            extractor.clone(),
-            model.clone(),
-            self_ref,
-            system
-        );
+            FeatureExtractionManager::new(mail_parser.clone(),
+                                          sentiment_analyzer.clone(),
+                                          text_analyzer.clone(),
+                                          header_analyzer.clone(),
+                                          html_parser.clone(),
+                                          mime_analyzer.clone(),
+                                          attachment_analyzer.clone(),
+                                          document_inspector.clone(),
+                                          authentication_verifier.clone(),
+                                          auth_results_analyzer.clone(),
+                                          impersonation_detector.clone(),
+                                          campaign_detector.clone(),
+                                          fuzzy_hash_matcher.clone(),
+                                          extraction_timeout,
+                                          self_ref,
+                                          system);
+    FeatureExtractionManagerActor::new(extractor, system.clone(), timeout)
+}
 
-    SpamDetectionServiceActor::new(service, system.clone(), Duration::from_secs(30))
+fn gen_model(system: SystemActor, config: &Config, backend: Arc<ModelBackend>) -> ModelActor {
+    let thresholds = config.thresholds.clone();
+    let batching = config.batching.clone();
+    let model =
+        move |self_ref, system|
+            Model::new(self_ref, system, backend.clone(), thresholds.clone(), batching.clone());
+    ModelActor::new(model, system.clone(), config.timeouts.actor())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use verdict_cache::cache_key;
+
+    const LUNCH: &'static [u8] = b"From: Alice <alice@example.com>\r\n\
+        To: bob@example.com\r\n\
+        Subject: Lunch\r\n\
+        \r\n\
+        Are we still on for lunch on Friday?\r\n";
+
+    /// The rules backend needs no Python or model file, and extraction gets
+    /// long enough to finish in a debug build
+    fn test_config() -> Config {
+        let mut config = Config::default();
+        config.model = ModelConfig::Rules;
+        config.timeouts.extraction_ms = 10_000;
+        config
+    }
+
+    fn test_worker(system: SystemActor,
+                   config: &Config,
+                   backend: Arc<ModelBackend>,
+                   verdict_cache: Arc<VerdictCache>,
+                   cache_stats: Arc<CacheStats>) -> SpamDetectionServiceActor {
+        let verification = Arc::new(VerificationPool::new(offline_resolver_from_config(&config.authentication),
+                                                          &config.authentication));
+        let model = gen_model(system.clone(), config, backend.clone());
+
+        gen_worker(system,
+                   config,
+                   backend,
+                   model,
+                   None,
+                   None,
+                   verdict_cache,
+                   cache_stats,
+                   Arc::new(CampaignIndex::new(&config.campaigns)),
+                   Arc::new(SignatureDb::default()),
+                   verification)
+    }
+
+    fn predict(worker: &SpamDetectionServiceActor, email: &[u8]) -> Result<Verdict> {
+        let (tx, rx) = channel::unbounded();
+        worker.predict(Arc::new(email.to_vec()), Arc::new(move |verdict| {
+            tx.send(verdict);
+        }));
+        rx.recv().expect("Worker went away")
+    }
+
+    fn predict_with_cache(worker: &SpamDetectionServiceActor, email: &[u8]) -> Result<Verdict> {
+        let (tx, rx) = channel::unbounded();
+        worker.predict_with_cache(Arc::new(email.to_vec()), Arc::new(move |verdict| {
+            tx.send(verdict);
+        }));
+        rx.recv().expect("Worker went away")
+    }
 
     #[test]
     fn integration_test() {
         let system = SystemActor::new();
+        let config = test_config();
+
+        let backend = backend_from_config(&config.model, None, system.clone());
+        let worker = test_worker(system,
+                                 &config,
+                                 backend.clone(),
+                                 verdict_cache_from_config(&config.prediction_cache),
+                                 Arc::new(CacheStats::default()));
+
+        let verdict = predict(&worker, LUNCH).expect("verdict");
+        assert_eq!(verdict.model_version, backend.version());
+        assert_eq!(verdict.label, Label::Ham);
+        assert!(verdict.reasons.is_empty());
+    }
 
-        let worker = gen_worker(system);
+    #[test]
+    fn repeated_emails_hit_the_shared_cache() {
+        let system = SystemActor::new();
+        let config = test_config();
+
+        let backend = backend_from_config(&config.model, None, system.clone());
+        let verdict_cache = verdict_cache_from_config(&config.prediction_cache);
+        let stats = Arc::new(CacheStats::default());
+        let first = test_worker(system.clone(), &config, backend.clone(), verdict_cache.clone(), stats.clone());
+        let second = test_worker(system, &config, backend.clone(), verdict_cache.clone(), stats.clone());
+
+        let verdict = predict_with_cache(&first, LUNCH).expect("verdict");
+        assert_eq!(stats.summary(), "prediction cache: 0 hits, 1 misses (0 errors), 0.0% hit rate");
+
+        // The first worker writes its verdict back without waiting for it
+        let key = cache_key(&hash_email(LUNCH), &backend.version());
+        for _ in 0..100 {
+            if verdict_cache.get(&key).unwrap().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let cached = predict_with_cache(&second, LUNCH).expect("cached verdict");
+        assert_eq!(cached.probability, verdict.probability);
+        assert_eq!(stats.summary(), "prediction cache: 1 hits, 1 misses (0 errors), 50.0% hit rate");
+    }
+
+    #[test]
+    fn unreachable_redis_is_a_miss() {
+        let system = SystemActor::new();
+        let mut config = test_config();
+        config.prediction_cache.backend = CacheBackend::Redis;
+        // Nothing listens on port 1
+        config.prediction_cache.redis_url = "redis://127.0.0.1:1/".to_owned();
+
+        let backend = backend_from_config(&config.model, None, system.clone());
+        let stats = Arc::new(CacheStats::default());
+        let worker = test_worker(system,
+                                 &config,
+                                 backend.clone(),
+                                 verdict_cache_from_config(&config.prediction_cache),
+                                 stats.clone());
+
+        let verdict = predict_with_cache(&worker, LUNCH).expect("verdict without the cache");
+        assert_eq!(verdict.model_version, backend.version());
+        assert_eq!(stats.summary(), "prediction cache: 0 hits, 1 misses (1 errors), 0.0% hit rate");
     }
 }
\ No newline at end of file
@@ -0,0 +1,322 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use mailparse::*;
+use select::document::Document;
+
+use errors::*;
+use email::*;
+use hidden::HiddenContent;
+use feature_vector::FeatureColumns;
+
+/// One node of the MIME tree, decoded
+#[derive(Clone, Debug, Default)]
+pub struct MimePart {
+    /// 0 for the top level
+    pub depth: usize,
+    pub mimetype: String,
+    pub charset: String,
+    /// The `name` parameter of the Content-Type
+    pub content_name: Option<String>,
+    /// The `filename` parameter of the Content-Disposition
+    pub filename: Option<String>,
+    /// Transfer decoded body, empty for multipart containers
+    pub body: Vec<u8>,
+    /// Charset decoded body of text/* parts
+    pub text: Option<String>,
+    /// The type the body's leading bytes say it is, if we recognise them
+    pub sniffed: Option<&'static str>,
+}
+
+impl MimePart {
+    pub fn is_multipart(&self) -> bool {
+        self.mimetype.starts_with("multipart/")
+    }
+
+    /// The attachment's name, from either header
+    pub fn name(&self) -> Option<&str> {
+        self.filename.as_ref().or(self.content_name.as_ref()).map(|n| n.as_str())
+    }
+
+    /// Whether the type sniffed from the body contradicts the declared type
+    pub fn type_mismatch(&self) -> bool {
+        match self.sniffed {
+            Some(sniffed) => !compatible(&self.mimetype, sniffed),
+            None => false,
+        }
+    }
+}
+
+/// Visits every part of the tree depth first, decoding each one
+pub fn walk(mail: &ParsedMail) -> Vec<MimePart> {
+    let mut parts = Vec::new();
+    visit(mail, 0, &mut parts);
+    parts
+}
+
+fn visit(mail: &ParsedMail, depth: usize, parts: &mut Vec<MimePart>) {
+    let mimetype = mail.ctype.mimetype.to_lowercase();
+    let multipart = mimetype.starts_with("multipart/");
+
+    let body = if multipart {
+        Vec::new()
+    } else {
+        mail.get_body_raw().unwrap_or_default()
+    };
+
+    let text = if mimetype.starts_with("text/") {
+        mail.get_body().ok()
+    } else {
+        None
+    };
+
+    let filename = mail.headers.get_first_value("Content-Disposition")
+        .ok()
+        .and_then(|d| d)
+        .and_then(|d| header_param(&d, "filename"));
+
+    parts.push(MimePart {
+        depth,
+        sniffed: sniff(&body),
+        mimetype,
+        charset: mail.ctype.charset.to_lowercase(),
+        content_name: mail.ctype.params.get("name").cloned(),
+        filename,
+        body,
+        text,
+    });
+
+    for part in &mail.subparts {
+        visit(part, depth + 1, parts);
+    }
+}
+
+/// A `key=value` parameter of a structured header such as Content-Disposition
+fn header_param(value: &str, key: &str) -> Option<String> {
+    value.split(';')
+        .skip(1)
+        .filter_map(|param| {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case(key) => {
+                    Some(v.trim().trim_matches('"').to_owned())
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Magic bytes of the types we care about
+const SIGNATURES: &'static [(&'static [u8], &'static str)] = &[
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1", "application/x-ole-storage"),
+    (b"MZ", "application/x-msdownload"),
+    (b"\x7FELF", "application/x-executable"),
+    (b"Rar!\x1A\x07", "application/x-rar"),
+    (b"7z\xBC\xAF\x27\x1C", "application/x-7z-compressed"),
+    (b"\x1F\x8B", "application/gzip"),
+    (b"\x89PNG\r\n\x1A\n", "image/png"),
+    (b"\xFF\xD8\xFF", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"{\\rtf", "application/rtf"),
+];
+
+pub fn sniff(body: &[u8]) -> Option<&'static str> {
+    if let Some(&(_, mimetype)) = SIGNATURES.iter().find(|&&(magic, _)| body.starts_with(magic)) {
+        return Some(mimetype);
+    }
+
+    let start: String = String::from_utf8_lossy(&body[..std::cmp::min(body.len(), 64)])
+        .trim_left()
+        .to_lowercase();
+
+    if start.starts_with("<html") || start.starts_with("<!doctype html") {
+        Some("text/html")
+    } else {
+        None
+    }
+}
+
+/// Declared types a sniffed type is allowed to hide behind
+fn compatible(declared: &str, sniffed: &str) -> bool {
+    if declared == sniffed || declared == "application/octet-stream" {
+        return true;
+    }
+
+    match sniffed {
+        // Office Open XML, OpenDocument and jar files are all zips
+        "application/zip" => {
+            declared.contains("zip") || declared.contains("openxmlformats") ||
+                declared.contains("opendocument") || declared.contains("java-archive")
+        }
+        "application/x-ole-storage" => {
+            declared == "application/msword" || declared.starts_with("application/vnd.ms-")
+        }
+        "image/jpeg" => declared == "image/jpg" || declared == "image/pjpeg",
+        "application/rtf" => declared == "text/rtf" || declared == "application/msword",
+        _ => false,
+    }
+}
+
+pub struct MimeAnalyzer {
+    self_ref: MimeAnalyzerActor,
+    system: SystemActor,
+}
+
+type MimeFeaturesResponse = std::sync::Arc<Fn(Result<MimeFeatures>) + Send + Sync + 'static>;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MimeFeatures {
+    pub mime_depth: usize,
+    /// Parts that aren't multipart containers
+    pub part_count: usize,
+    pub text_parts: usize,
+    pub html_parts: usize,
+    pub distinct_content_types: usize,
+    pub distinct_charsets: usize,
+    /// 1 - the word overlap of the text/plain and text/html alternatives
+    pub text_html_disagreement: f32,
+    /// Parts whose magic bytes contradict their declared type
+    pub sniffed_type_mismatches: usize,
+}
+
+impl FeatureColumns for MimeFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["mime_depth", "part_count", "text_parts", "html_parts", "distinct_content_types",
+             "distinct_charsets", "text_html_disagreement", "sniffed_type_mismatches"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.mime_depth as f32,
+             self.part_count as f32,
+             self.text_parts as f32,
+             self.html_parts as f32,
+             self.distinct_content_types as f32,
+             self.distinct_charsets as f32,
+             self.text_html_disagreement,
+             self.sniffed_type_mismatches as f32]
+    }
+}
+
+impl<'a> From<&'a Email> for MimeFeatures {
+    fn from(email: &'a Email) -> MimeFeatures {
+        let leaves: Vec<&MimePart> = email.parts.iter().filter(|p| !p.is_multipart()).collect();
+
+        let text_html_disagreement = match (email.plain_body(), email.html_body()) {
+            (Some(plain), Some(html)) => {
+                let visible = HiddenContent::from(&Document::from(html.as_str())).visible_text;
+                1.0 - word_overlap(&plain, &visible)
+            }
+            _ => 0.0,
+        };
+
+        MimeFeatures {
+            mime_depth: email.parts.iter().map(|p| p.depth).max().unwrap_or(0),
+            part_count: leaves.len(),
+            text_parts: leaves.iter().filter(|p| p.mimetype == "text/plain").count(),
+            html_parts: leaves.iter().filter(|p| p.mimetype == "text/html").count(),
+            distinct_content_types: leaves.iter().map(|p| &p.mimetype).collect::<HashSet<_>>().len(),
+            distinct_charsets: leaves.iter()
+                .filter(|p| p.text.is_some())
+                .map(|p| &p.charset)
+                .collect::<HashSet<_>>()
+                .len(),
+            text_html_disagreement,
+            sniffed_type_mismatches: leaves.iter().filter(|p| p.type_mismatch()).count(),
+        }
+    }
+}
+
+/// Jaccard similarity of the two texts' lowercased words
+fn word_overlap(a: &str, b: &str) -> f32 {
+    let words = |text: &str| -> HashSet<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_lowercase())
+            .collect()
+    };
+
+    let (a, b) = (words(a), words(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.0;
+    }
+
+    a.intersection(&b).count() as f32 / union as f32
+}
+
+#[derive_actor]
+impl MimeAnalyzer {
+    pub fn analyze(&self, email: ParsedEmail, res: MimeFeaturesResponse) {
+        res(Ok(MimeFeatures::from(&*email)))
+    }
+}
+
+impl MimeAnalyzer {
+    pub fn new(self_ref: MimeAnalyzerActor, system: SystemActor) -> MimeAnalyzer {
+        MimeAnalyzer {
+            self_ref,
+            system,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: MimeAnalyzerMessage,
+                   t: Arc<T>)
+        where T: Fn(MimeAnalyzerActor, SystemActor) -> MimeAnalyzer + Send + Sync + 'static
+    {
+        match msg {
+            MimeAnalyzerMessage::AnalyzeVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in mime analyzer".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walks_nested_parts() {
+        let raw = b"Content-Type: multipart/mixed; boundary=outer\r\n\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=inner\r\n\r\n\
+--inner\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\r\n\
+Hello there\r\n\
+--inner\r\n\
+Content-Type: text/html; charset=utf-8\r\n\r\n\
+<p>Win a prize</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/pdf\r\n\
+Content-Disposition: attachment; filename=\"invoice.pdf\"\r\n\r\n\
+MZ\x90\x00\r\n\
+--outer--\r\n";
+
+        let email = Email::from(&parse_mail(raw).unwrap());
+        let features = MimeFeatures::from(&email);
+
+        assert_eq!(features.mime_depth, 2);
+        assert_eq!(features.part_count, 3);
+        assert_eq!(features.text_parts, 1);
+        assert_eq!(features.html_parts, 1);
+        assert_eq!(features.sniffed_type_mismatches, 1);
+        assert_eq!(features.text_html_disagreement, 1.0);
+        assert_eq!(email.parts[4].name(), Some("invoice.pdf"));
+    }
+}
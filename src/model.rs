@@ -2,49 +2,248 @@ use derive_aktor::derive_actor;
 use aktors::actor::SystemActor;
 
 use std;
-use std::path::PathBuf;
-use std::time::Duration;
-use std::sync::Arc;
-
-use lru_time_cache::LruCache;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Arc, Mutex};
 
 use errors::*;
+use config::{BatchConfig, ModelConfig, ReloadConfig, SupervisorConfig};
+use verdict::*;
 use extraction::Features;
+use feature_vector::*;
+use rust_model::RustBackend;
+use rules_model::RulesBackend;
+use reload::{BackendFactory, ReloadingBackend};
+use supervisor::*;
+use verdict_cache::{CacheStats, VerdictCache, cache_key};
 
-use rand::Rng;
-use redis::{self, Connection, Commands};
 use reqwest::Client;
 
+/// A classifier the Model actor can forward predictions to
+pub trait ModelBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn version(&self) -> String;
+    /// The feature columns the classifier was trained on, in order
+    fn schema(&self) -> Vec<String>;
+    /// False while the backend can't serve predictions, e.g. while restarting
+    fn is_available(&self) -> bool {
+        true
+    }
+    /// Scores each email with a spam probability between 0.0 and 1.0, in the
+    /// same order as `features`
+    fn predict_batch(&self, features: Vec<Features>, res: BatchScore);
+}
+
+/// Builds the configured backend, refusing to start if it expects different
+/// columns than the extractor produces
+pub fn backend_from_config(model: &ModelConfig,
+                           reload: Option<&ReloadConfig>,
+                           system: SystemActor) -> Arc<ModelBackend> {
+    let backend: Arc<ModelBackend> = match reload {
+        Some(reload) => {
+            let factory = model_factory(model, system);
+            Arc::new(ReloadingBackend::start(reload.clone(), factory))
+        }
+        None => {
+            match *model {
+                ModelConfig::Python { ref script, ref model, ref supervisor } =>
+                    Arc::new(PythonBackend::new(script.clone(), model.clone(), supervisor.clone(), system)),
+                ModelConfig::Rust { ref model } => Arc::new(RustBackend::new(model.clone(), system)),
+                ModelConfig::Rules => Arc::new(RulesBackend::new()),
+            }
+        }
+    };
+
+    if let Err(e) = check_schema(&backend.schema(), &Features::columns()) {
+        panic!("Refusing to start {} model {}: {}", backend.name(), backend.version(), e);
+    }
+
+    println!("Using {} model version {}", backend.name(), backend.version());
+    backend
+}
+
+/// Builds backends of the configured kind for arbitrary model files
+fn model_factory(config: &ModelConfig, system: SystemActor) -> BackendFactory {
+    match config.clone() {
+        ModelConfig::Python { script, supervisor, .. } => {
+            Arc::new(move |path: &Path| {
+                let backend: Arc<ModelBackend> = Arc::new(
+                    PythonBackend::new(script.clone(), Some(path.to_owned()), supervisor.clone(), system.clone()));
+                backend
+            })
+        }
+        ModelConfig::Rust { .. } => {
+            Arc::new(move |path: &Path| {
+                let backend: Arc<ModelBackend> = Arc::new(RustBackend::new(path.to_owned(), system.clone()));
+                backend
+            })
+        }
+        ModelConfig::Rules => panic!("The rules backend has no model files to reload"),
+    }
+}
+
+/// Asks the model to flush each batch once its deadline passes. Batches start,
+/// and so their deadlines arrive, in order, so one thread sleeping until each
+/// in turn keeps every batch's. Each deadline carries the model to flush, so
+/// the thread holds no handle while it's idle and exits once its Model, and
+/// with it the sending half of `deadlines`, is dropped.
+fn flush_batches(deadlines: mpsc::Receiver<(Instant, usize, ModelActor)>) {
+    for (deadline, batch_id, model) in deadlines {
+        let now = Instant::now();
+        if deadline > now {
+            std::thread::sleep(deadline - now);
+        }
+        model.flush(batch_id);
+    }
+}
+
+/// Errors aren't Clone, but every email in a failed batch needs one
+fn copy_error(e: &Error) -> Error {
+    match *e.kind() {
+        ErrorKind::UnrecoverableError(ref e) => ErrorKind::UnrecoverableError(e.clone()).into(),
+        ErrorKind::RecoverableError(ref e) => ErrorKind::RecoverableError(e.clone()).into(),
+        _ => ErrorKind::RecoverableError(e.to_string().into()).into(),
+    }
+}
+
 pub struct Model {
     self_ref: ModelActor,
     system: SystemActor,
-    python_model: PythonModelActor,
+    backend: Arc<ModelBackend>,
+    thresholds: Thresholds,
+    batching: BatchConfig,
+    batch: Vec<(Features, Prediction)>,
+    // Incremented whenever a batch is sent, so that a stale flush timer can't
+    // cut the next batch short
+    batch_id: usize,
+    // When each batch must be sent by, see flush_batches
+    deadlines: mpsc::Sender<(Instant, usize, ModelActor)>,
     predictions: usize
 }
 
-type Prediction = std::sync::Arc<Fn(Result<bool>) + Send + Sync + 'static>;
+pub type Prediction = std::sync::Arc<Fn(Result<Verdict>) + Send + Sync + 'static>;
+pub type BatchScore = std::sync::Arc<Fn(Result<Scores>) + Send + Sync + 'static>;
+pub type Probabilities = std::sync::Arc<Fn(Result<Vec<f32>>) + Send + Sync + 'static>;
+
+/// Spam probabilities for a batch, along with the model version that produced them
+pub struct Scores {
+    pub model_version: String,
+    pub probabilities: Vec<f32>,
+}
+
+impl Scores {
+    /// Wraps a callback expecting Scores so it can be handed to an actor that only
+    /// knows about probabilities
+    pub fn with_version(model_version: String, res: BatchScore) -> Probabilities {
+        Arc::new(move |probabilities| {
+            res(probabilities.map(|probabilities| Scores {
+                model_version: model_version.clone(),
+                probabilities,
+            }))
+        })
+    }
+}
 
 #[derive_actor]
 impl Model {
     pub fn predict(&mut self, features: Features, res: Prediction) {
-        std::thread::sleep(Duration::from_millis(10));
+        self.batch.push((features, res));
+
+        if self.batch.len() >= self.batching.max_size {
+            self.send_batch();
+        } else if self.batch.len() == 1 {
+            // First email of a new batch - make sure it waits no longer than max_wait
+            let deadline = Instant::now() + self.batching.max_wait();
+            let _ = self.deadlines.send((deadline, self.batch_id, self.self_ref.clone()));
+        }
+    }
 
-        self.python_model.predict(features, res);
+    pub fn flush(&mut self, batch_id: usize) {
+        if batch_id == self.batch_id {
+            self.send_batch();
+        }
     }
 }
 
 impl Model {
     pub fn new(self_ref: ModelActor,
                system: SystemActor,
-               python_model: PythonModelActor) -> Model {
+               backend: Arc<ModelBackend>,
+               thresholds: Thresholds,
+               batching: BatchConfig) -> Model {
+        let (deadlines, timer) = mpsc::channel();
+        std::thread::spawn(move || flush_batches(timer));
+
         Model {
             self_ref,
             system,
-            python_model,
+            backend,
+            thresholds,
+            batching,
+            batch: Vec::new(),
+            batch_id: 0,
+            deadlines,
             predictions: 0
         }
     }
 
+    fn send_batch(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+
+        self.batch_id += 1;
+        self.predictions += self.batch.len();
+
+        let batch = std::mem::replace(&mut self.batch, Vec::new());
+        let (features, callbacks): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+        let reasons: Vec<Vec<String>> = features.iter().map(|f| f.reasons()).collect();
+        let authentication: Vec<_> = features.iter().map(|f| f.authentication.results.clone()).collect();
+
+        if !self.backend.is_available() {
+            for res in callbacks.iter() {
+                res(Err(ErrorKind::RecoverableError(
+                    format!("The {} model is unavailable", self.backend.name()).into())
+                    .into()));
+            }
+            return;
+        }
+
+        let thresholds = self.thresholds.clone();
+        let started = Instant::now();
+
+        self.backend.predict_batch(features, Arc::new(move |scores| {
+            let latency = started.elapsed();
+
+            match scores {
+                Ok(ref scores) if scores.probabilities.len() == callbacks.len() => {
+                    let verdicts = scores.probabilities.iter()
+                        .zip(callbacks.iter())
+                        .zip(reasons.iter().zip(authentication.iter()));
+                    for ((probability, res), (reasons, authentication)) in verdicts {
+                        let verdict = Verdict::new(*probability, &thresholds, scores.model_version.clone(), latency)
+                            .with_reasons(reasons.clone())
+                            .with_authentication(authentication.clone());
+                        res(Ok(verdict));
+                    }
+                }
+                Ok(ref scores) => {
+                    for res in callbacks.iter() {
+                        res(Err(ErrorKind::UnrecoverableError(
+                            format!("Model returned {} scores for {} emails",
+                                    scores.probabilities.len(), callbacks.len()).into())
+                            .into()));
+                    }
+                }
+                Err(ref e) => {
+                    for res in callbacks.iter() {
+                        res(Err(copy_error(e)));
+                    }
+                }
+            }
+        }));
+    }
+
     fn on_timeout(&mut self) {}
 
     fn on_error<T>(&mut self,
@@ -57,67 +256,182 @@ impl Model {
     }
 }
 
-use std::process::{Child, Command};
+use std::process::Command;
+
+/// Runs prediction_service.py as a supervised child process and talks to it over HTTP
+pub struct PythonBackend {
+    supervisor: ChildSupervisor,
+    model: PythonModelActor,
+    version: String,
+    schema: Vec<String>,
+}
+
+impl PythonBackend {
+    pub fn new(path: PathBuf,
+               model: Option<PathBuf>,
+               config: SupervisorConfig,
+               system: SystemActor) -> PythonBackend {
+        let script = path.clone();
+        let spawn: SpawnCommand = Arc::new(move |port| {
+            let mut command = Command::new(&script);
+            command.env("FLASK_APP", &script)
+                .arg("--port")
+                .arg(port.to_string());
+
+            if let Some(ref model) = model {
+                command.arg("--model").arg(model);
+            }
+
+            command
+        });
+
+        // The first child's schema and version are the backend's. A restarted
+        // child that reports others, say because the model file was replaced,
+        // would be scored under the wrong version, so it's never used.
+        let identity: Arc<Mutex<Option<(Vec<String>, String)>>> = Arc::new(Mutex::new(None));
+        let expected = identity.clone();
+        let ready: ReadyCheck = Arc::new(move |port| {
+            let client = Client::new();
+            let schema = PythonBackend::fetch(&client, port, "schema")
+                .map(|header| parse_header(&header))
+                .chain_err(|| "Failed to fetch the model's feature schema")?;
+            let version = PythonBackend::fetch(&client, port, "version")
+                .map(|version| version.trim().to_owned())
+                .chain_err(|| "Failed to fetch the model's version")?;
+
+            let mut expected = expected.lock().unwrap();
+            if let Some((ref expected_schema, ref expected_version)) = *expected {
+                if version != *expected_version {
+                    bail!(ErrorKind::RecoverableError(
+                        format!("Model version {} replaced {}", version, expected_version).into()));
+                }
+                if schema != *expected_schema {
+                    bail!(ErrorKind::RecoverableError(
+                        format!("Model {} changed its feature schema", version).into()));
+                }
+                return Ok(());
+            }
+
+            *expected = Some((schema, version));
+            Ok(())
+        });
+
+        let supervisor = ChildSupervisor::start("python model".to_owned(),
+                                                spawn,
+                                                "health_check".to_owned(),
+                                                ready,
+                                                config)
+            .expect(&format!("Could not start the Python service at {:#?}", path));
+        let status = supervisor.status();
+
+        let (schema, version) = identity.lock().unwrap().clone()
+            .expect("The schema and version are fetched before the child is used");
+
+        let python_model =
+            move |self_ref, system| PythonModel::new(status.clone());
+        let model = PythonModelActor::new(python_model, system.clone(), Duration::from_secs(30));
+
+        PythonBackend {
+            supervisor,
+            model,
+            version,
+            schema,
+        }
+    }
+
+    fn fetch(client: &Client, port: u16, endpoint: &str) -> Result<String> {
+        let url = format!("http://127.0.0.1:{}/{}", port, endpoint);
+        client.get(&url)
+            .send()
+            .and_then(|mut r| r.text())
+            .chain_err(|| format!("Failed to GET {}", url))
+    }
+}
+
+impl ModelBackend for PythonBackend {
+    fn name(&self) -> &'static str {
+        "python"
+    }
+
+    fn version(&self) -> String {
+        self.version.clone()
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.schema.clone()
+    }
+
+    fn is_available(&self) -> bool {
+        self.supervisor.status().is_available()
+    }
 
+    fn predict_batch(&self, features: Vec<Features>, res: BatchScore) {
+        self.model.predict_batch(features, Scores::with_version(self.version.clone(), res));
+    }
+}
 
 pub struct PythonModel {
-    python: Child,
     client: Client,
-    port: u16,
-    path: PathBuf
+    status: ChildStatus,
 }
 
 #[derive_actor]
 impl PythonModel {
-    pub fn predict(&mut self, features: Features, res: Prediction) {
-        println!("poython pred");
-        if let Err(e) = self.client.get(&format!("http://127.0.0.1:{}/predict/1,2,3", self.port)).send() {
-            res(Err(ErrorKind::RecoverableError(
-                format!("Failed to predict {}", e).into())
+    pub fn predict_batch(&mut self, features: Vec<Features>, res: Probabilities) {
+        if !self.status.is_available() {
+            return res(Err(ErrorKind::RecoverableError(
+                "The Python model is restarting".into())
                 .into()));
-        } else {
-            res(Ok(false));
         }
-    }
-}
 
-impl PythonModel {
-    pub fn new(path: PathBuf) -> PythonModel {
-        let mut rng = ::rand::weak_rng();
-        let port: u16 = rng.gen_range(10000, 16000);
-        let python =
-            Command::new(path.to_str().unwrap())
-                .env("FLASK_APP", path.to_str().unwrap())
-                .arg("--port")
-                .arg(port.to_string())
-                .spawn()
-                .expect(&format!("Invalid path: {:#?}", path));
-
-        let client = Client::new();
-        let url = format!("http://127.0.0.1:{}/health_check", port.to_string());
-        let mut up = false;
-        for i in 0..15 {
-            std::thread::sleep(Duration::from_millis(2 << i));
-            if client.get(&url)
-                .send()
-                .is_ok() {
-                println!("Connected to PythonModel");
-                up = true;
-                break;
-            } else {
-                println!("Not connected to PythonModel")
+        let url = format!("http://127.0.0.1:{}/predict_batch", self.status.port());
+        let vectors: Vec<FeatureVector> = features.iter().map(|f| f.to_vector()).collect();
+        let body = FeatureVector::batch_to_csv(&vectors);
+
+        let mut response = match self.client.post(&url).body(body).send() {
+            Ok(response) => response,
+            Err(e) => {
+                return res(Err(ErrorKind::RecoverableError(
+                    format!("Failed to predict {}", e).into())
+                    .into()));
             }
+        };
+
+        if !response.status().is_success() {
+            return res(Err(ErrorKind::RecoverableError(
+                format!("Prediction service returned {}", response.status()).into())
+                .into()));
         }
 
-        if !up {
-            panic!("Could not connect to Python service");
+        match response.text() {
+            Ok(text) => {
+                res(text.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .map(PythonModel::parse_probability)
+                    .collect())
+            }
+            Err(e) => {
+                res(Err(ErrorKind::RecoverableError(
+                    format!("Failed to read prediction {}", e).into())
+                    .into()))
+            }
         }
+    }
+}
 
+impl PythonModel {
+    pub fn new(status: ChildStatus) -> PythonModel {
         PythonModel {
-            python,
-            client,
-            port,
-            path
+            client: Client::new(),
+            status,
+        }
+    }
+
+    fn parse_probability(text: &str) -> Result<f32> {
+        match text.trim().parse::<f32>() {
+            Ok(p) if p >= 0.0 && p <= 1.0 => Ok(p),
+            _ => bail!(ErrorKind::UnrecoverableError(
+                format!("Unexpected prediction from model service: {}", text.trim()).into()))
         }
     }
 
@@ -133,52 +447,66 @@ impl PythonModel {
     }
 }
 
-impl Drop for PythonModel {
-    fn drop(&mut self) {
-        self.python.kill();
-    }
-}
-
 pub struct PredictionCache {
     self_ref: PredictionCacheActor,
     system: SystemActor,
-    cache: LruCache<Vec<u8>, bool>
-    //    connection: Connection
+    /// Shared with every other worker's PredictionCache
+    cache: Arc<VerdictCache>,
+    stats: Arc<CacheStats>,
+    report_every: usize,
+    /// Asked for its version on every lookup, so a reload stops old verdicts
+    /// being served
+    backend: Arc<ModelBackend>,
 }
 
-type GetResponse = std::sync::Arc<Fn(Result<Option<bool>>) + Send + Sync + 'static>;
+type GetResponse = std::sync::Arc<Fn(Result<Option<Verdict>>) + Send + Sync + 'static>;
 type Hash = Vec<u8>;
 
 #[derive_actor]
 impl PredictionCache {
+    /// An unreachable cache is reported as a miss
     pub fn get(&mut self, email_hash: Hash, res: GetResponse) {
-        let mut email_hash = email_hash;
-        email_hash.extend_from_slice(&b"prediction"[..]);
+        let key = cache_key(&email_hash, &self.backend.version());
+
+        let (cached, lookups) = match self.cache.get(&key) {
+            Ok(Some(hit)) => (Some(hit), self.stats.record_hit()),
+            Ok(None) => (None, self.stats.record_miss()),
+            Err(e) => {
+                println!("Prediction cache unavailable: {}", e);
+                (None, self.stats.record_error())
+            }
+        };
 
-        random_panic!(10);
-        random_latency!(10, 20);
+        if self.report_every > 0 && lookups % self.report_every == 0 {
+            println!("{}", self.stats.summary());
+        }
 
-        res(Ok(self.cache.get(&email_hash).cloned()));
+        res(Ok(cached))
     }
 
-    pub fn set(&mut self, email_hash: Hash, prediction: bool) {
-        let mut email_hash = email_hash;
-        email_hash.extend_from_slice(&b"prediction"[..]);
+    pub fn set(&mut self, email_hash: Hash, prediction: Verdict) {
+        let key = cache_key(&email_hash, &prediction.model_version);
 
-        self.cache.insert(email_hash, prediction);
+        if let Err(e) = self.cache.set(&key, &prediction) {
+            println!("Failed to write the prediction cache: {}", e);
+        }
     }
 }
 
 impl PredictionCache {
-    pub fn new(self_ref: PredictionCacheActor, system: SystemActor) -> PredictionCache {
-        //        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
-        //        let con = client.get_connection().unwrap();
-
-        let time_to_live = std::time::Duration::from_secs(60);
+    pub fn new(cache: Arc<VerdictCache>,
+               stats: Arc<CacheStats>,
+               report_every: usize,
+               backend: Arc<ModelBackend>,
+               self_ref: PredictionCacheActor,
+               system: SystemActor) -> PredictionCache {
         PredictionCache {
             self_ref,
             system,
-            cache: LruCache::with_expiry_duration_and_capacity(time_to_live, 10),
+            cache,
+            stats,
+            report_every,
+            backend,
         }
     }
 
@@ -193,3 +521,136 @@ impl PredictionCache {
         // t(self.self_ref.clone(), self.system.clone());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the size of each batch and gives every email a score, or one
+    /// too few when `short`
+    struct FakeBackend {
+        batches: Mutex<Vec<usize>>,
+        short: bool,
+    }
+
+    impl FakeBackend {
+        fn new(short: bool) -> Arc<FakeBackend> {
+            Arc::new(FakeBackend {
+                batches: Mutex::new(Vec::new()),
+                short,
+            })
+        }
+
+        fn batches(&self) -> Vec<usize> {
+            self.batches.lock().unwrap().clone()
+        }
+    }
+
+    impl ModelBackend for FakeBackend {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn version(&self) -> String {
+            "1".to_owned()
+        }
+
+        fn schema(&self) -> Vec<String> {
+            Features::columns()
+        }
+
+        fn predict_batch(&self, features: Vec<Features>, res: BatchScore) {
+            self.batches.lock().unwrap().push(features.len());
+            let scored = if self.short { features.len() - 1 } else { features.len() };
+            res(Ok(Scores {
+                model_version: self.version(),
+                probabilities: vec![0.9; scored],
+            }))
+        }
+    }
+
+    fn test_model(backend: Arc<FakeBackend>, max_size: usize, max_wait_ms: u64) -> ModelActor {
+        let batching = BatchConfig { max_size, max_wait_ms };
+        let model = move |self_ref, system| {
+            let backend: Arc<ModelBackend> = backend.clone();
+            Model::new(self_ref, system, backend, Thresholds::default(), batching.clone())
+        };
+        ModelActor::new(model, SystemActor::new(), Duration::from_secs(30))
+    }
+
+    /// Sends `count` emails to the model, their verdicts arriving on the receiver
+    fn predict(model: &ModelActor, count: usize) -> mpsc::Receiver<Result<Verdict>> {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let res: Prediction = Arc::new(move |verdict| {
+            let _ = tx.lock().unwrap().send(verdict);
+        });
+
+        for _ in 0..count {
+            model.predict(Features::default(), res.clone());
+        }
+        rx
+    }
+
+    /// Long enough for any verdict that's coming
+    const WAIT_SECS: u64 = 5;
+    /// A max_wait_ms no test outlasts
+    const NEVER_MS: u64 = 60 * 60 * 1000;
+
+    #[test]
+    fn full_batches_are_sent_at_once() {
+        let backend = FakeBackend::new(false);
+        let model = test_model(backend.clone(), 2, NEVER_MS);
+
+        let verdicts = predict(&model, 2);
+        for _ in 0..2 {
+            let verdict = verdicts.recv_timeout(Duration::from_secs(WAIT_SECS)).expect("verdict").expect("score");
+            assert_eq!(verdict.label, Label::Spam);
+            assert_eq!(verdict.model_version, "1");
+        }
+        assert_eq!(backend.batches(), vec![2]);
+    }
+
+    #[test]
+    fn partial_batches_are_sent_by_their_deadline() {
+        let backend = FakeBackend::new(false);
+        let model = test_model(backend.clone(), 32, 10);
+
+        let verdicts = predict(&model, 1);
+        assert!(verdicts.recv_timeout(Duration::from_secs(WAIT_SECS)).expect("verdict").is_ok());
+        assert_eq!(backend.batches(), vec![1]);
+    }
+
+    #[test]
+    fn stale_flushes_are_ignored() {
+        let backend = FakeBackend::new(false);
+        let model = test_model(backend.clone(), 2, NEVER_MS);
+
+        // The first batch fills up and is sent, the second waits
+        let first = predict(&model, 2);
+        let second = predict(&model, 1);
+        for _ in 0..2 {
+            assert!(first.recv_timeout(Duration::from_secs(WAIT_SECS)).expect("verdict").is_ok());
+        }
+
+        model.flush(0);
+        assert!(second.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!(backend.batches(), vec![2]);
+
+        model.flush(1);
+        assert!(second.recv_timeout(Duration::from_secs(WAIT_SECS)).expect("verdict").is_ok());
+        assert_eq!(backend.batches(), vec![2, 1]);
+    }
+
+    #[test]
+    fn missing_scores_fail_the_whole_batch() {
+        let backend = FakeBackend::new(true);
+        let model = test_model(backend.clone(), 2, NEVER_MS);
+
+        let verdicts = predict(&model, 2);
+        for _ in 0..2 {
+            let error = verdicts.recv_timeout(Duration::from_secs(WAIT_SECS)).expect("verdict").unwrap_err();
+            assert!(error.to_string().contains("returned 1 scores for 2 emails"));
+        }
+    }
+}
@@ -0,0 +1,628 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::collections::{BTreeSet, HashSet};
+use std::io::{Cursor, Read};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use zip::ZipArchive;
+
+use errors::*;
+use email::*;
+use attachments::attachments;
+use feature_vector::FeatureColumns;
+
+/// Archives within archives are only opened this deep
+const MAX_DEPTH: usize = 3;
+/// An entry expanding more than this many times over is treated as a zip bomb
+const MAX_RATIO: u64 = 100;
+/// Total bytes we'll decompress for a single attachment
+const MAX_EXPANDED_BYTES: u64 = 50 * 1024 * 1024;
+
+/// VBA procedures Office runs without the user asking
+const AUTO_EXEC: &'static [&'static str] = &[
+    "AutoOpen", "Auto_Open", "AutoExec", "AutoClose", "Auto_Close", "AutoNew",
+    "Document_Open", "DocumentOpen", "Document_Close", "Document_New",
+    "Workbook_Open", "Workbook_Activate", "Workbook_BeforeClose",
+];
+
+/// Namespaces every Office XML file refers to, which aren't links
+const XML_NAMESPACES: &'static [&'static str] = &[
+    "schemas.openxmlformats.org", "schemas.microsoft.com", "purl.org", "www.w3.org",
+];
+
+/// What we found inside one document or archive
+#[derive(Clone, Debug, Default)]
+pub struct Inspection {
+    pub vba_macros: bool,
+    pub auto_exec: BTreeSet<String>,
+    pub urls: BTreeSet<String>,
+    /// Entries we refused to open for exceeding a ratio, size or depth limit
+    pub limit_violations: usize,
+}
+
+impl Inspection {
+    /// From 0.0 to 1.0, how dangerous the document looks
+    pub fn risk(&self) -> f32 {
+        let mut risk = 0.0;
+        if self.vba_macros {
+            risk += 0.4;
+        }
+        if !self.auto_exec.is_empty() {
+            risk += 0.4;
+        }
+        if self.limit_violations > 0 {
+            risk += 0.3;
+        }
+        risk += (self.urls.len() as f32 * 0.05).min(0.2);
+        risk.min(1.0)
+    }
+
+    fn merge(&mut self, other: Inspection) {
+        self.vba_macros |= other.vba_macros;
+        self.auto_exec.extend(other.auto_exec);
+        self.urls.extend(other.urls);
+        self.limit_violations += other.limit_violations;
+    }
+}
+
+/// Looks inside zip based (docx, xlsm, ...) and OLE (doc, xls, ...) files.
+/// Anything else comes back empty.
+pub fn inspect(body: &[u8]) -> Inspection {
+    let mut budget = MAX_EXPANDED_BYTES;
+    inspect_at(body, 0, &mut budget)
+}
+
+fn inspect_at(body: &[u8], depth: usize, budget: &mut u64) -> Inspection {
+    if body.starts_with(b"PK\x03\x04") {
+        inspect_zip(body, depth, budget)
+    } else if body.starts_with(OLE_SIGNATURE) {
+        inspect_ole(body)
+    } else {
+        Inspection::default()
+    }
+}
+
+fn inspect_zip(body: &[u8], depth: usize, budget: &mut u64) -> Inspection {
+    let mut inspection = Inspection::default();
+
+    if depth >= MAX_DEPTH {
+        inspection.limit_violations += 1;
+        return inspection;
+    }
+
+    let mut archive = match ZipArchive::new(Cursor::new(body)) {
+        Ok(archive) => archive,
+        Err(_) => return inspection,
+    };
+
+    for i in 0..archive.len() {
+        let mut entry = match archive.by_index(i) {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+
+        let name = entry.name().to_lowercase();
+        let size = entry.size();
+
+        if size / std::cmp::max(entry.compressed_size(), 1) > MAX_RATIO || size > *budget {
+            inspection.limit_violations += 1;
+            continue;
+        }
+
+        let mut contents = Vec::with_capacity(size as usize);
+        // The declared size can lie, never read past what's left of the budget
+        if entry.by_ref().take(*budget).read_to_end(&mut contents).is_err() {
+            continue;
+        }
+        *budget -= contents.len() as u64;
+
+        if name.ends_with("vbaproject.bin") {
+            inspection.vba_macros = true;
+        }
+
+        if name.ends_with(".xml") || name.ends_with(".rels") {
+            inspection.urls.extend(urls(&String::from_utf8_lossy(&contents)));
+        } else {
+            inspection.merge(inspect_at(&contents, depth + 1, budget));
+        }
+    }
+
+    inspection
+}
+
+fn inspect_ole(body: &[u8]) -> Inspection {
+    let mut inspection = Inspection::default();
+
+    let ole = match Ole::parse(body) {
+        Some(ole) => ole,
+        None => return inspection,
+    };
+
+    for entry in &ole.entries {
+        let name = entry.name.to_lowercase();
+        if name == "_vba_project" || name == "vba" || name == "macros" || name == "_vba_project_cur" {
+            inspection.vba_macros = true;
+        }
+    }
+
+    for entry in ole.entries.iter().filter(|e| e.is_stream) {
+        let stream = ole.read(entry);
+
+        // Module streams hold compressed source starting with "Attribute VB_Name"
+        if let Some(source) = find_vba_source(&stream) {
+            inspection.vba_macros = true;
+
+            // VBA isn't case sensitive
+            let lowercase = source.to_lowercase();
+            for auto_exec in AUTO_EXEC {
+                if lowercase.contains(&auto_exec.to_lowercase()) {
+                    inspection.auto_exec.insert(auto_exec.to_string());
+                }
+            }
+
+            inspection.urls.extend(urls(&source));
+        } else {
+            inspection.urls.extend(urls(&String::from_utf8_lossy(&stream)));
+        }
+    }
+
+    inspection
+}
+
+/// Every http(s) URL in the text, other than XML namespaces
+fn urls(text: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("http") {
+        let candidate = &rest[start..];
+        let end = candidate.find(|c: char| c.is_whitespace() || "\"'<>()[]{}".contains(c))
+            .unwrap_or(candidate.len());
+        let url = &candidate[..end];
+
+        if (url.starts_with("http://") || url.starts_with("https://")) &&
+            !XML_NAMESPACES.iter().any(|ns| url.contains(ns)) {
+            urls.push(url.to_owned());
+        }
+
+        rest = &candidate[std::cmp::max(end, 4)..];
+    }
+
+    urls
+}
+
+fn find_vba_source(stream: &[u8]) -> Option<String> {
+    let marker = b"\x00Attribut";
+    let at = match stream.windows(marker.len()).position(|w| w == marker) {
+        Some(at) => at,
+        None => return None,
+    };
+
+    // The container signature and chunk header come just before the marker
+    if at < 3 || stream[at - 3] != 1 {
+        return None;
+    }
+
+    decompress_vba(&stream[at - 3..]).map(|source| String::from_utf8_lossy(&source).into_owned())
+}
+
+/// MS-OVBA decompression, stopping at MAX_RATIO times the input size
+fn decompress_vba(data: &[u8]) -> Option<Vec<u8>> {
+    if data.first() != Some(&1) {
+        return None;
+    }
+
+    let limit = data.len() * MAX_RATIO as usize;
+    let mut out = Vec::new();
+    let mut pos = 1;
+
+    while pos + 2 <= data.len() && out.len() < limit {
+        let header = LittleEndian::read_u16(&data[pos..]);
+        let chunk_end = std::cmp::min(data.len(), pos + (header & 0x0FFF) as usize + 3);
+        let compressed = header & 0x8000 != 0;
+        pos += 2;
+
+        let chunk_start = out.len();
+
+        if !compressed {
+            let end = std::cmp::min(data.len(), pos + 4096);
+            out.extend_from_slice(&data[pos..end]);
+            pos = end;
+            continue;
+        }
+
+        while pos < chunk_end {
+            let flags = data[pos];
+            pos += 1;
+
+            for bit in 0..8 {
+                if pos >= chunk_end {
+                    break;
+                }
+
+                if flags & (1 << bit) == 0 {
+                    out.push(data[pos]);
+                    pos += 1;
+                    continue;
+                }
+
+                if pos + 2 > chunk_end {
+                    pos = chunk_end;
+                    break;
+                }
+
+                let token = LittleEndian::read_u16(&data[pos..]) as usize;
+                pos += 2;
+
+                let difference = out.len() - chunk_start;
+                let mut bit_count = 4;
+                while (1 << bit_count) < difference && bit_count < 12 {
+                    bit_count += 1;
+                }
+
+                let length_mask = 0xFFFF >> bit_count;
+                let length = (token & length_mask) + 3;
+                let offset = (token >> (16 - bit_count)) + 1;
+
+                if offset > out.len() {
+                    return None;
+                }
+
+                let from = out.len() - offset;
+                for i in 0..length {
+                    let byte = out[from + i];
+                    out.push(byte);
+                }
+            }
+        }
+
+        pos = chunk_end;
+    }
+
+    Some(out)
+}
+
+const OLE_SIGNATURE: &'static [u8] = b"\xD0\xCF\x11\xE0\xA1\xB1\x1A\xE1";
+const END_OF_CHAIN: u32 = 0xFFFF_FFFE;
+const FREE_SECTOR: u32 = 0xFFFF_FFFF;
+
+struct OleEntry {
+    name: String,
+    is_stream: bool,
+    start: u32,
+    size: usize,
+}
+
+/// Just enough of a Compound File Binary reader to list and read streams
+struct Ole<'a> {
+    data: &'a [u8],
+    sector_size: usize,
+    mini_sector_size: usize,
+    mini_cutoff: usize,
+    fat: Vec<u32>,
+    mini_fat: Vec<u32>,
+    mini_stream: Vec<u8>,
+    entries: Vec<OleEntry>,
+}
+
+impl<'a> Ole<'a> {
+    fn parse(data: &'a [u8]) -> Option<Ole<'a>> {
+        if data.len() < 512 || !data.starts_with(OLE_SIGNATURE) {
+            return None;
+        }
+
+        let sector_shift = LittleEndian::read_u16(&data[0x1E..]) as usize;
+        let mini_shift = LittleEndian::read_u16(&data[0x20..]) as usize;
+        if sector_shift < 7 || sector_shift > 16 || mini_shift > sector_shift {
+            return None;
+        }
+
+        let mut ole = Ole {
+            data,
+            sector_size: 1 << sector_shift,
+            mini_sector_size: 1 << mini_shift,
+            mini_cutoff: LittleEndian::read_u32(&data[0x38..]) as usize,
+            fat: Vec::new(),
+            mini_fat: Vec::new(),
+            mini_stream: Vec::new(),
+            entries: Vec::new(),
+        };
+
+        // The first 109 FAT sectors are listed in the header, the rest in a DIFAT chain
+        let mut fat_sectors: Vec<u32> = (0..109)
+            .map(|i| LittleEndian::read_u32(&data[0x4C + i * 4..]))
+            .filter(|s| *s != FREE_SECTOR)
+            .collect();
+
+        // A file can't have more FAT sectors, or FAT entries, than sectors.
+        // Hostile files claim more, or loop their DIFAT chain, to make us
+        // read the same sectors over and over.
+        let max_sectors = ole.max_sectors();
+        let mut difat = LittleEndian::read_u32(&data[0x44..]);
+        let per_sector = ole.sector_size / 4;
+        let mut visited = HashSet::new();
+        while difat != END_OF_CHAIN && difat != FREE_SECTOR && fat_sectors.len() < max_sectors {
+            if !visited.insert(difat) {
+                break;
+            }
+            let sector = match ole.sector(difat) {
+                Some(sector) => sector,
+                None => return None,
+            };
+            for i in 0..per_sector - 1 {
+                let s = LittleEndian::read_u32(&sector[i * 4..]);
+                if s != FREE_SECTOR {
+                    fat_sectors.push(s);
+                }
+            }
+            difat = LittleEndian::read_u32(&sector[(per_sector - 1) * 4..]);
+        }
+        fat_sectors.truncate(max_sectors);
+
+        for s in fat_sectors {
+            if ole.fat.len() >= max_sectors {
+                break;
+            }
+            let sector = match ole.sector(s) {
+                Some(sector) => sector,
+                None => return None,
+            };
+            ole.fat.extend((0..per_sector).map(|i| LittleEndian::read_u32(&sector[i * 4..])));
+        }
+        ole.fat.truncate(max_sectors);
+
+        let mini_fat_start = LittleEndian::read_u32(&data[0x3C..]);
+        let mini_fat = ole.chain(mini_fat_start, usize::max_value());
+        ole.mini_fat = mini_fat.chunks(4)
+            .filter(|c| c.len() == 4)
+            .map(LittleEndian::read_u32)
+            .collect();
+
+        let directory = ole.chain(LittleEndian::read_u32(&data[0x30..]), usize::max_value());
+        for raw in directory.chunks(128).filter(|c| c.len() == 128) {
+            let name_len = std::cmp::min(LittleEndian::read_u16(&raw[64..]) as usize, 64);
+            let name: Vec<u16> = raw[..name_len].chunks(2)
+                .filter(|c| c.len() == 2)
+                .map(LittleEndian::read_u16)
+                .take_while(|c| *c != 0)
+                .collect();
+
+            ole.entries.push(OleEntry {
+                name: String::from_utf16_lossy(&name),
+                is_stream: raw[66] == 2,
+                start: LittleEndian::read_u32(&raw[116..]),
+                size: LittleEndian::read_u32(&raw[120..]) as usize,
+            });
+        }
+
+        // The root entry's stream holds every small stream
+        if let Some(root) = ole.entries.first().map(|r| (r.start, r.size)) {
+            ole.mini_stream = ole.chain(root.0, root.1);
+        }
+
+        Some(ole)
+    }
+
+    fn max_sectors(&self) -> usize {
+        self.data.len() / self.sector_size + 1
+    }
+
+    fn sector(&self, index: u32) -> Option<&'a [u8]> {
+        let start = (index as usize + 1) * self.sector_size;
+        let end = start + self.sector_size;
+        if end > self.data.len() {
+            None
+        } else {
+            Some(&self.data[start..end])
+        }
+    }
+
+    /// Follows a FAT chain, giving up on loops and out of range sectors
+    fn chain(&self, start: u32, size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut current = start;
+        let mut steps = 0;
+
+        while current != END_OF_CHAIN && out.len() < size && steps < self.max_sectors() {
+            match self.sector(current) {
+                Some(sector) => out.extend_from_slice(sector),
+                None => break,
+            }
+            current = match self.fat.get(current as usize) {
+                Some(next) => *next,
+                None => break,
+            };
+            steps += 1;
+        }
+
+        out.truncate(size);
+        out
+    }
+
+    fn read(&self, entry: &OleEntry) -> Vec<u8> {
+        if entry.size >= self.mini_cutoff {
+            return self.chain(entry.start, entry.size);
+        }
+
+        let mut out = Vec::new();
+        let mut current = entry.start;
+        let max_steps = self.mini_stream.len() / self.mini_sector_size + 1;
+        let mut steps = 0;
+
+        while current != END_OF_CHAIN && out.len() < entry.size && steps < max_steps {
+            let start = current as usize * self.mini_sector_size;
+            let end = start + self.mini_sector_size;
+            if end > self.mini_stream.len() {
+                break;
+            }
+            out.extend_from_slice(&self.mini_stream[start..end]);
+
+            current = match self.mini_fat.get(current as usize) {
+                Some(next) => *next,
+                None => break,
+            };
+            steps += 1;
+        }
+
+        out.truncate(entry.size);
+        out
+    }
+}
+
+pub struct DocumentInspector {
+    self_ref: DocumentInspectorActor,
+    system: SystemActor,
+}
+
+type DocumentFeaturesResponse = std::sync::Arc<Fn(Result<DocumentFeatures>) + Send + Sync + 'static>;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DocumentFeatures {
+    pub documents_inspected: usize,
+    pub vba_macros: usize,
+    pub auto_exec_macros: usize,
+    pub embedded_urls: usize,
+    pub limit_violations: usize,
+    /// The riskiest attachment's risk, from 0.0 to 1.0
+    pub attachment_risk: f32,
+    pub reasons: Vec<String>,
+}
+
+impl FeatureColumns for DocumentFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["documents_inspected", "vba_macros", "auto_exec_macros", "embedded_urls",
+             "limit_violations", "attachment_risk"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.documents_inspected as f32,
+             self.vba_macros as f32,
+             self.auto_exec_macros as f32,
+             self.embedded_urls as f32,
+             self.limit_violations as f32,
+             self.attachment_risk]
+    }
+}
+
+impl<'a> From<&'a Email> for DocumentFeatures {
+    fn from(email: &'a Email) -> DocumentFeatures {
+        let mut features = DocumentFeatures::default();
+
+        for attachment in attachments(email) {
+            let is_document = attachment.sniffed == Some("application/zip") ||
+                attachment.sniffed == Some("application/x-ole-storage");
+            if !is_document {
+                continue;
+            }
+
+            let name = attachment.name().unwrap_or("unnamed attachment");
+            let inspection = inspect(&attachment.body);
+            features.documents_inspected += 1;
+
+            if inspection.vba_macros {
+                features.vba_macros += 1;
+                features.reasons.push(format!("{} contains VBA macros", name));
+            }
+
+            if !inspection.auto_exec.is_empty() {
+                features.auto_exec_macros += inspection.auto_exec.len();
+                let entry_points: Vec<&str> = inspection.auto_exec.iter().map(|s| s.as_str()).collect();
+                features.reasons.push(format!("{} runs macros automatically ({})", name, entry_points.join(", ")));
+            }
+
+            if !inspection.urls.is_empty() {
+                features.embedded_urls += inspection.urls.len();
+                let urls: Vec<&str> = inspection.urls.iter().map(|s| s.as_str()).collect();
+                features.reasons.push(format!("{} links to {}", name, urls.join(", ")));
+            }
+
+            if inspection.limit_violations > 0 {
+                features.limit_violations += inspection.limit_violations;
+                features.reasons.push(format!("{} exceeds decompression limits", name));
+            }
+
+            features.attachment_risk = features.attachment_risk.max(inspection.risk());
+        }
+
+        features
+    }
+}
+
+#[derive_actor]
+impl DocumentInspector {
+    pub fn inspect(&self, email: ParsedEmail, res: DocumentFeaturesResponse) {
+        res(Ok(DocumentFeatures::from(&*email)))
+    }
+}
+
+impl DocumentInspector {
+    pub fn new(self_ref: DocumentInspectorActor, system: SystemActor) -> DocumentInspector {
+        DocumentInspector {
+            self_ref,
+            system,
+        }
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: DocumentInspectorMessage,
+                   t: Arc<T>)
+        where T: Fn(DocumentInspectorActor, SystemActor) -> DocumentInspector + Send + Sync + 'static
+    {
+        match msg {
+            DocumentInspectorMessage::InspectVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in document inspector".into()).into())
+                );
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_vba() {
+        // "#aaabcdefaaaaghijaaaaaklaaamnopqaaaaaaaaaaaarstuvwxyzaaa" compressed,
+        // from the MS-OVBA examples
+        let compressed = b"\x01\x2F\xB0\x00\x23\x61\x61\x61\x62\x63\x64\x65\x82\x66\x00\x70\x61\x67\x68\x69\x6A\x01\x38\x08\x61\x6B\x6C\x00\x30\x6D\x6E\x6F\x70\x06\x71\x02\x70\x04\x10\x72\x73\x74\x75\x76\x10\x77\x78\x79\x7A\x00\x3C";
+        let source = decompress_vba(compressed).unwrap();
+
+        assert_eq!(&source[..], &b"#aaabcdefaaaaghijaaaaaklaaamnopqaaaaaaaaaaaarstuvwxyzaaa"[..]);
+    }
+
+    #[test]
+    fn finds_urls() {
+        let text = r#"<Relationship Target="http://evil.example/payload.exe" xmlns="http://schemas.openxmlformats.org/x"/>"#;
+        assert_eq!(urls(text), vec!["http://evil.example/payload.exe".to_owned()]);
+    }
+
+    #[test]
+    fn survives_difat_loops() {
+        // A header and one DIFAT sector that lists itself as every FAT sector
+        // and as the next DIFAT sector
+        let mut data = vec![0u8; 1024];
+        data[..8].copy_from_slice(OLE_SIGNATURE);
+        LittleEndian::write_u16(&mut data[0x1E..], 9);
+        LittleEndian::write_u16(&mut data[0x20..], 6);
+        LittleEndian::write_u32(&mut data[0x30..], END_OF_CHAIN);
+        LittleEndian::write_u32(&mut data[0x38..], 4096);
+        LittleEndian::write_u32(&mut data[0x3C..], END_OF_CHAIN);
+        LittleEndian::write_u32(&mut data[0x44..], 0);
+        for i in 0..109 {
+            LittleEndian::write_u32(&mut data[0x4C + i * 4..], FREE_SECTOR);
+        }
+
+        let ole = Ole::parse(&data).expect("parses");
+        assert!(ole.fat.len() <= ole.max_sectors());
+        assert!(ole.entries.is_empty());
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashSet;
+
+/// Mozilla's public suffix list, https://publicsuffix.org/list/
+const PUBLIC_SUFFIX_LIST: &'static str = include_str!("public_suffix_list.dat");
+
+lazy_static! {
+    static ref RULES: Rules = Rules::parse(PUBLIC_SUFFIX_LIST);
+}
+
+struct Rules {
+    /// Suffixes like "co.uk"
+    exact: HashSet<String>,
+    /// "*.ck" is stored as "ck", any one label under it is a suffix
+    wildcards: HashSet<String>,
+    /// "!www.ck" is stored as "www.ck", a registrable domain despite a wildcard
+    exceptions: HashSet<String>,
+}
+
+impl Rules {
+    fn parse(list: &str) -> Rules {
+        let mut rules = Rules {
+            exact: HashSet::new(),
+            wildcards: HashSet::new(),
+            exceptions: HashSet::new(),
+        };
+
+        // Each rule is the first word on its line
+        let lines = list.lines()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|rule| !rule.starts_with("//"));
+
+        for rule in lines {
+            let rule = rule.to_lowercase();
+            if rule.starts_with("!") {
+                rules.exceptions.insert(rule[1..].to_owned());
+            } else if rule.starts_with("*.") {
+                rules.wildcards.insert(rule[2..].to_owned());
+            } else {
+                rules.exact.insert(rule);
+            }
+        }
+
+        rules
+    }
+
+    /// How many of the trailing labels form the public suffix. Domains no rule
+    /// covers fall under the implicit "*" rule, their last label.
+    fn suffix_labels(&self, labels: &[&str]) -> usize {
+        // Longest suffix first, an exception is always longer than the
+        // wildcard it overrides
+        for start in 0..labels.len() {
+            let suffix = labels[start..].join(".");
+            let len = labels.len() - start;
+
+            if self.exceptions.contains(&suffix) {
+                return len - 1;
+            }
+            if self.exact.contains(&suffix) {
+                return len;
+            }
+            if len > 1 && self.wildcards.contains(&labels[start + 1..].join(".")) {
+                return len;
+            }
+        }
+
+        1
+    }
+}
+
+/// The public suffix of a domain and the one label registered under it, so
+/// mail.bank.co.uk gives bank.co.uk. Domains that are themselves public
+/// suffixes are returned whole.
+///
+/// Rules for internationalized suffixes are only listed in Unicode, so
+/// punycode domains under them fall back to their last two labels.
+pub fn registrable_domain(domain: &str) -> String {
+    let domain = domain.trim_right_matches('.').to_lowercase();
+    let labels: Vec<&str> = domain.split('.').collect();
+
+    let keep = RULES.suffix_labels(&labels) + 1;
+    let start = labels.len().saturating_sub(keep);
+    labels[start..].join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrable_domains() {
+        assert_eq!(registrable_domain("mail.example.com"), "example.com");
+        assert_eq!(registrable_domain("secure.bank.co.uk"), "bank.co.uk");
+        assert_eq!(registrable_domain("Evil.CO.UK."), "evil.co.uk");
+        assert_eq!(registrable_domain("co.uk"), "co.uk");
+        assert_eq!(registrable_domain("user.github.io"), "user.github.io");
+        // *.ck with !www.ck
+        assert_eq!(registrable_domain("a.b.example.ck"), "b.example.ck");
+        assert_eq!(registrable_domain("mail.www.ck"), "www.ck");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use config::ReloadConfig;
+use errors::*;
+use extraction::Features;
+use feature_vector::*;
+use model::{BatchScore, ModelBackend};
+
+/// Builds a backend serving the model file at the given path
+pub type BackendFactory = Arc<Fn(&Path) -> Arc<ModelBackend> + Send + Sync + 'static>;
+
+/// Serves whichever model version in a directory is newest.
+///
+/// Every file in the directory is a model version, named by its file name; the
+/// greatest name wins. New versions are loaded beside the current one and only
+/// take traffic once they pass a schema, health and smoke check. Requests already
+/// sent to the old version are answered by it.
+pub struct ReloadingBackend {
+    current: Arc<RwLock<Arc<ModelBackend>>>,
+}
+
+impl ReloadingBackend {
+    pub fn start(config: ReloadConfig, factory: BackendFactory) -> ReloadingBackend {
+        let (version, path) = match latest_model(&config.dir) {
+            Ok(Some(latest)) => latest,
+            Ok(None) => panic!("No models found in {:#?}", config.dir),
+            Err(e) => panic!("Failed to read model directory {:#?}: {}", config.dir, e),
+        };
+
+        println!("Loading model version {} from {:#?}", version, path);
+        let current = Arc::new(RwLock::new(factory(&path)));
+
+        ReloadingBackend::watch(Arc::downgrade(&current), version, config, factory);
+
+        ReloadingBackend {
+            current
+        }
+    }
+
+    fn current(&self) -> Arc<ModelBackend> {
+        self.current.read().unwrap().clone()
+    }
+
+    fn watch(current: Weak<RwLock<Arc<ModelBackend>>>,
+             mut loaded: String,
+             config: ReloadConfig,
+             factory: BackendFactory) {
+        thread::spawn(move || {
+            let mut rejected = HashSet::new();
+
+            loop {
+                thread::sleep(config.poll_interval());
+
+                // The backend has been dropped, nothing left to reload
+                let current = match current.upgrade() {
+                    Some(current) => current,
+                    None => return,
+                };
+
+                let (version, path) = match latest_model(&config.dir) {
+                    Ok(Some(latest)) => latest,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("Failed to read model directory {:#?}: {}", config.dir, e);
+                        continue;
+                    }
+                };
+
+                if version == loaded || rejected.contains(&version) {
+                    continue;
+                }
+
+                println!("Loading model version {} from {:#?}", version, path);
+                match load_candidate(&factory, &path, config.smoke_test_timeout()) {
+                    Ok(candidate) => {
+                        *current.write().unwrap() = candidate;
+                        println!("Switched to model version {}", version);
+                        loaded = version;
+                    }
+                    Err(e) => {
+                        println!("Rejected model version {}: {}", version, e);
+                        rejected.insert(version);
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl ModelBackend for ReloadingBackend {
+    fn name(&self) -> &'static str {
+        self.current().name()
+    }
+
+    fn version(&self) -> String {
+        self.current().version()
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.current().schema()
+    }
+
+    fn is_available(&self) -> bool {
+        self.current().is_available()
+    }
+
+    fn predict_batch(&self, features: Vec<Features>, res: BatchScore) {
+        let backend = self.current();
+        let serving = backend.clone();
+
+        backend.predict_batch(features, Arc::new(move |scores| {
+            // Keeps the version that took this batch alive until it has answered,
+            // even if we've switched away from it in the meantime
+            let _ = &serving;
+            res(scores)
+        }));
+    }
+}
+
+/// The newest model in `dir` as (version, path), ignoring hidden files so that
+/// models can be written under a dotted name and renamed into place
+fn latest_model(dir: &Path) -> Result<Option<(String, PathBuf)>> {
+    let mut latest: Option<(String, PathBuf)> = None;
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+
+        let version = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        if version.starts_with('.') {
+            continue;
+        }
+
+        let newer = match latest {
+            Some((ref latest, _)) => version > *latest,
+            None => true,
+        };
+
+        if newer {
+            latest = Some((version, entry.path()));
+        }
+    }
+
+    Ok(latest)
+}
+
+fn load_candidate(factory: &BackendFactory, path: &Path, timeout: Duration) -> Result<Arc<ModelBackend>> {
+    // Backends panic when they fail to start - that only means this version is bad
+    let candidate = panic::catch_unwind(AssertUnwindSafe(|| factory(path)))
+        .map_err(|_| ErrorKind::UnrecoverableError(format!("Failed to load {:#?}", path).into()))?;
+
+    check_schema(&candidate.schema(), &Features::columns())?;
+
+    if !candidate.is_available() {
+        bail!(ErrorKind::UnrecoverableError("Model is not healthy".into()))
+    }
+
+    smoke_test(&*candidate, timeout)?;
+
+    Ok(candidate)
+}
+
+/// Scores an all zero feature vector, which any working model can do
+fn smoke_test(backend: &ModelBackend, timeout: Duration) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let tx = Mutex::new(tx);
+
+    backend.predict_batch(vec![Features::default()], Arc::new(move |scores| {
+        let _ = tx.lock().unwrap().send(scores.map(|s| s.probabilities));
+    }));
+
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(ref probabilities)) if probabilities.len() == 1 &&
+            probabilities[0] >= 0.0 && probabilities[0] <= 1.0 => Ok(()),
+        Ok(Ok(probabilities)) => bail!(ErrorKind::UnrecoverableError(
+            format!("Smoke test returned {:?}", probabilities).into())),
+        Ok(Err(e)) => Err(e),
+        Err(_) => bail!(ErrorKind::UnrecoverableError("Smoke test timed out".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::time::Instant;
+    use uuid::Uuid;
+    use model::Scores;
+
+    struct FakeBackend {
+        version: String,
+        schema: Vec<String>,
+        probabilities: Vec<f32>,
+    }
+
+    impl ModelBackend for FakeBackend {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn version(&self) -> String {
+            self.version.clone()
+        }
+
+        fn schema(&self) -> Vec<String> {
+            self.schema.clone()
+        }
+
+        fn predict_batch(&self, _features: Vec<Features>, res: BatchScore) {
+            res(Ok(Scores {
+                model_version: self.version.clone(),
+                probabilities: self.probabilities.clone(),
+            }))
+        }
+    }
+
+    fn model_dir(models: &[&str]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("reload_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        for model in models {
+            File::create(dir.join(model)).unwrap();
+        }
+        dir
+    }
+
+    /// Waits up to five seconds for `done`
+    fn wait_for<F: Fn() -> bool>(done: F) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            if done() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn latest_model_skips_hidden_files() {
+        let dir = model_dir(&["2018-01-02", "2018-01-10", ".2018-02-01"]);
+        fs::create_dir_all(dir.join("2018-03-01")).unwrap();
+
+        let (version, path) = latest_model(&dir).unwrap().expect("a model");
+        assert_eq!(version, "2018-01-10");
+        assert_eq!(path, dir.join("2018-01-10"));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(latest_model(&dir).is_err());
+    }
+
+    #[test]
+    fn bad_versions_are_rejected_once() {
+        let dir = model_dir(&["1"]);
+        let loads = Arc::new(Mutex::new(Vec::new()));
+
+        // Version 2 expects another column, 3 can't score anything
+        let loaded = loads.clone();
+        let factory: BackendFactory = Arc::new(move |path: &Path| {
+            let version = path.file_name().unwrap().to_string_lossy().into_owned();
+            loaded.lock().unwrap().push(version.clone());
+
+            let mut schema = Features::columns();
+            if version == "2" {
+                schema.push("extra".to_owned());
+            }
+            let probabilities = if version == "3" { vec![] } else { vec![0.5] };
+
+            let backend: Arc<ModelBackend> = Arc::new(FakeBackend {
+                version,
+                schema,
+                probabilities,
+            });
+            backend
+        });
+
+        let config = ReloadConfig {
+            dir: dir.clone(),
+            poll_interval_ms: 10,
+            smoke_test_timeout_ms: 1000,
+        };
+        let backend = ReloadingBackend::start(config, factory);
+        assert_eq!(backend.version(), "1");
+
+        for bad in &["2", "3"] {
+            File::create(dir.join(bad)).unwrap();
+            assert!(wait_for(|| loads.lock().unwrap().contains(&bad.to_string())));
+
+            // Plenty of polls later it's still the old version serving
+            thread::sleep(Duration::from_millis(200));
+            assert_eq!(loads.lock().unwrap().iter().filter(|v| v == bad).count(), 1);
+            assert_eq!(backend.version(), "1");
+            smoke_test(&backend, Duration::from_secs(1)).expect("version 1 still scores");
+        }
+
+        File::create(dir.join("4")).unwrap();
+        assert!(wait_for(|| backend.version() == "4"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
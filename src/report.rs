@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use serde_json;
+
+use errors::*;
+use config::OutputFormat;
+use verdict::*;
+
+#[derive(Serialize)]
+struct VerdictRow<'a> {
+    path: &'a Path,
+    verdict: Option<&'a Verdict>,
+    error: Option<String>,
+}
+
+/// Printed before any verdicts in CSV output
+pub const CSV_HEADER: &'static str = "path,label,probability,model_version,latency_ms";
+
+pub fn format_verdict(format: OutputFormat, path: &Path, verdict: &Result<Verdict>) -> String {
+    match (format, verdict) {
+        (OutputFormat::Text, &Ok(ref verdict)) if verdict.reasons.is_empty() => {
+            format!("{} {:?} {:.3} (model {})", path.display(), verdict.label, verdict.probability, verdict.model_version)
+        }
+        (OutputFormat::Text, &Ok(ref verdict)) => {
+            format!("{} {:?} {:.3} (model {}): {}",
+                    path.display(), verdict.label, verdict.probability, verdict.model_version,
+                    verdict.reasons.join("; "))
+        }
+        (OutputFormat::Text, &Err(ref e)) => {
+            format!("{} error: {}", path.display(), e)
+        }
+        (OutputFormat::Json, verdict) => {
+            let row = VerdictRow {
+                path,
+                verdict: verdict.as_ref().ok(),
+                error: verdict.as_ref().err().map(|e| e.to_string()),
+            };
+            serde_json::to_string(&row).unwrap_or_else(|e| format!("{{\"error\": \"{}\"}}", e))
+        }
+        (OutputFormat::Csv, &Ok(ref verdict)) => {
+            format!("{},{:?},{},{},{}",
+                    path.display(),
+                    verdict.label,
+                    verdict.probability,
+                    verdict.model_version,
+                    verdict.latency.as_secs() * 1000 + (verdict.latency.subsec_nanos() / 1_000_000) as u64)
+        }
+        (OutputFormat::Csv, &Err(_)) => {
+            format!("{},Error,,,", path.display())
+        }
+    }
+}
+
+/// Verdicts on a labeled corpus, where Spam is the positive class
+#[derive(Debug, Default)]
+pub struct Evaluation {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub true_negatives: usize,
+    pub false_negatives: usize,
+    /// Labeled Suspect, split by what the email really was
+    pub suspect_spam: usize,
+    pub suspect_ham: usize,
+    pub failed: usize,
+}
+
+impl Evaluation {
+    pub fn record(&mut self, spam: bool, verdict: &Verdict) {
+        match (verdict.label, spam) {
+            (Label::Spam, true) => self.true_positives += 1,
+            (Label::Spam, false) => self.false_positives += 1,
+            (Label::Ham, false) => self.true_negatives += 1,
+            (Label::Ham, true) => self.false_negatives += 1,
+            (Label::Suspect, true) => {
+                self.false_negatives += 1;
+                self.suspect_spam += 1;
+            }
+            (Label::Suspect, false) => {
+                self.true_negatives += 1;
+                self.suspect_ham += 1;
+            }
+        }
+    }
+
+    pub fn precision(&self) -> f64 {
+        ratio(self.true_positives, self.true_positives + self.false_positives)
+    }
+
+    pub fn recall(&self) -> f64 {
+        ratio(self.true_positives, self.true_positives + self.false_negatives)
+    }
+
+    pub fn accuracy(&self) -> f64 {
+        let correct = self.true_positives + self.true_negatives;
+        ratio(correct, correct + self.false_positives + self.false_negatives)
+    }
+
+    pub fn report(&self) -> String {
+        format!("precision {:.3} recall {:.3} accuracy {:.3}\n\
+                 true positives {} false positives {} true negatives {} false negatives {}\n\
+                 suspect: {} spam {} ham, {} failed",
+                self.precision(), self.recall(), self.accuracy(),
+                self.true_positives, self.false_positives, self.true_negatives, self.false_negatives,
+                self.suspect_spam, self.suspect_ham, self.failed)
+    }
+}
+
+fn ratio(n: usize, d: usize) -> f64 {
+    if d == 0 {
+        0.0
+    } else {
+        n as f64 / d as f64
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lru_time_cache::LruCache;
+use trust_dns_resolver::Resolver as TrustDnsResolver;
+use trust_dns_resolver::error::{ResolveError, ResolveErrorKind};
+use trust_dns_resolver::system_conf::read_system_conf;
+
+use errors::*;
+use config::AuthenticationConfig;
+
+/// The DNS lookups sender authentication needs.
+///
+/// A name with no records of the type is `Ok` and empty, an `Err` means the
+/// lookup itself failed and might succeed later.
+pub trait Resolver: Send + Sync {
+    fn txt(&self, name: &str) -> Result<Vec<String>>;
+    fn mx(&self, name: &str) -> Result<Vec<String>>;
+    fn ip(&self, name: &str) -> Result<Vec<IpAddr>>;
+}
+
+/// The zone file if one is configured, otherwise the system's name servers
+/// behind a cache
+pub fn resolver_from_config(config: &AuthenticationConfig) -> Arc<Resolver> {
+    match config.zone_file {
+        Some(ref path) => load_zone_file(path),
+        None => {
+            let dns = DnsResolver::new(config.lookup_timeout())
+                .expect("Failed to read the system DNS configuration");
+            Arc::new(CachingResolver::new(Arc::new(dns), config.cache_capacity, config.cache_ttl()))
+        }
+    }
+}
+
+/// For training and evaluation, whose features shouldn't depend on what DNS
+/// says today: the zone file if one is configured, otherwise no lookups at all
+pub fn offline_resolver_from_config(config: &AuthenticationConfig) -> Arc<Resolver> {
+    match config.zone_file {
+        Some(ref path) => load_zone_file(path),
+        None => Arc::new(OfflineResolver),
+    }
+}
+
+fn load_zone_file(path: &Path) -> Arc<Resolver> {
+    let zone = ZoneFileResolver::load(path)
+        .expect(&format!("Failed to load zone file {:#?}", path));
+    Arc::new(zone)
+}
+
+/// Asks the system's name servers
+pub struct DnsResolver {
+    resolver: TrustDnsResolver,
+}
+
+impl DnsResolver {
+    /// `timeout` applies to each query, which is tried once per name server
+    pub fn new(timeout: Duration) -> Result<DnsResolver> {
+        let (config, mut options) = read_system_conf()?;
+        options.timeout = timeout;
+        options.attempts = 1;
+
+        Ok(DnsResolver {
+            resolver: TrustDnsResolver::new(config, options)?,
+        })
+    }
+}
+
+fn lookup_error(name: &str, e: ResolveError) -> Result<()> {
+    match *e.kind() {
+        ResolveErrorKind::NoRecordsFound(_) => Ok(()),
+        _ => bail!(ErrorKind::RecoverableError(format!("DNS lookup for {} failed: {}", name, e).into())),
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        match self.resolver.txt_lookup(name) {
+            Ok(lookup) => {
+                Ok(lookup.iter()
+                    .map(|txt| {
+                        // Long records are split into strings that are joined back together
+                        txt.txt_data().iter()
+                            .map(|data| String::from_utf8_lossy(data).into_owned())
+                            .collect::<Vec<_>>()
+                            .join("")
+                    })
+                    .collect())
+            }
+            Err(e) => lookup_error(name, e).map(|_| Vec::new()),
+        }
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<String>> {
+        match self.resolver.mx_lookup(name) {
+            Ok(lookup) => {
+                Ok(lookup.iter()
+                    .map(|mx| mx.exchange().to_string().trim_right_matches('.').to_owned())
+                    .collect())
+            }
+            Err(e) => lookup_error(name, e).map(|_| Vec::new()),
+        }
+    }
+
+    fn ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        match self.resolver.lookup_ip(name) {
+            Ok(lookup) => Ok(lookup.iter().collect()),
+            Err(e) => lookup_error(name, e).map(|_| Vec::new()),
+        }
+    }
+}
+
+/// Remembers another resolver's answers, including that a name has no records.
+/// Failed lookups aren't cached so they're retried.
+pub struct CachingResolver {
+    resolver: Arc<Resolver>,
+    txt: Mutex<LruCache<String, Vec<String>>>,
+    mx: Mutex<LruCache<String, Vec<String>>>,
+    ip: Mutex<LruCache<String, Vec<IpAddr>>>,
+}
+
+impl CachingResolver {
+    pub fn new(resolver: Arc<Resolver>, capacity: usize, time_to_live: Duration) -> CachingResolver {
+        let cache = || Mutex::new(LruCache::with_expiry_duration_and_capacity(time_to_live, capacity));
+        CachingResolver {
+            resolver,
+            txt: cache(),
+            mx: cache(),
+            ip: cache(),
+        }
+    }
+}
+
+fn cached<T, F>(cache: &Mutex<LruCache<String, Vec<T>>>, name: &str, lookup: F) -> Result<Vec<T>>
+    where T: Clone,
+          F: FnOnce() -> Result<Vec<T>>
+{
+    let name = name.trim_right_matches('.').to_lowercase();
+    if let Some(answer) = cache.lock().unwrap().get(&name) {
+        return Ok(answer.clone());
+    }
+
+    // Not holding the lock while the query is out
+    let answer = lookup()?;
+    cache.lock().unwrap().insert(name, answer.clone());
+    Ok(answer)
+}
+
+impl Resolver for CachingResolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        cached(&self.txt, name, || self.resolver.txt(name))
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<String>> {
+        cached(&self.mx, name, || self.resolver.mx(name))
+    }
+
+    fn ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        cached(&self.ip, name, || self.resolver.ip(name))
+    }
+}
+
+/// Fails every lookup, so SPF, DKIM and DMARC come out as temperror
+pub struct OfflineResolver;
+
+impl OfflineResolver {
+    fn lookup<T>(&self, name: &str) -> Result<Vec<T>> {
+        bail!(ErrorKind::RecoverableError(format!("Not looking up {} offline", name).into()))
+    }
+}
+
+impl Resolver for OfflineResolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        self.lookup(name)
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<String>> {
+        self.lookup(name)
+    }
+
+    fn ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        self.lookup(name)
+    }
+}
+
+/// Answers from a local file instead of the network, for tests and offline runs.
+///
+/// Each line is `name TYPE value`, with TYPE one of TXT, MX, A or AAAA. TXT
+/// values may be quoted. Blank lines and lines starting with ';' are ignored.
+///
+///     example.com                TXT "v=spf1 ip4:192.0.2.0/24 -all"
+///     example.com                MX  mail.example.com
+///     mail.example.com           A   192.0.2.1
+#[derive(Debug, Default)]
+pub struct ZoneFileResolver {
+    records: HashMap<(String, String), Vec<String>>,
+}
+
+impl ZoneFileResolver {
+    pub fn load(path: &Path) -> Result<ZoneFileResolver> {
+        let file = File::open(path)?;
+        let mut zone = ZoneFileResolver::default();
+
+        for (line_no, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some(name), Some(kind)) if fields.clone().next().is_some() => {
+                    // The value may itself contain spaces
+                    let value = fields.collect::<Vec<_>>().join(" ");
+                    zone.add(name, kind, &value)
+                }
+                _ => bail!(ErrorKind::UnrecoverableError(
+                    format!("Line {} of {:#?} is not 'name TYPE value'", line_no + 1, path).into()))
+            }
+        }
+
+        Ok(zone)
+    }
+
+    pub fn add(&mut self, name: &str, kind: &str, value: &str) {
+        let name = name.trim().trim_right_matches('.').to_lowercase();
+        let value = value.trim().trim_matches('"').to_owned();
+        self.records.entry((name, kind.trim().to_uppercase()))
+            .or_insert_with(Vec::new)
+            .push(value);
+    }
+
+    fn lookup(&self, name: &str, kind: &str) -> Vec<String> {
+        let name = name.trim_right_matches('.').to_lowercase();
+        self.records.get(&(name, kind.to_owned()))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Resolver for ZoneFileResolver {
+    fn txt(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self.lookup(name, "TXT"))
+    }
+
+    fn mx(&self, name: &str) -> Result<Vec<String>> {
+        Ok(self.lookup(name, "MX"))
+    }
+
+    fn ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+        let mut ips = self.lookup(name, "A");
+        ips.extend(self.lookup(name, "AAAA"));
+        Ok(ips.iter().filter_map(|ip| ip.parse().ok()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct CountingResolver {
+        zone: ZoneFileResolver,
+        lookups: AtomicUsize,
+    }
+
+    impl Resolver for CountingResolver {
+        fn txt(&self, name: &str) -> Result<Vec<String>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.zone.txt(name)
+        }
+
+        fn mx(&self, name: &str) -> Result<Vec<String>> {
+            self.zone.mx(name)
+        }
+
+        fn ip(&self, name: &str) -> Result<Vec<IpAddr>> {
+            self.zone.ip(name)
+        }
+    }
+
+    #[test]
+    fn answers_are_cached() {
+        let mut counting = CountingResolver::default();
+        counting.zone.add("example.com", "TXT", "v=spf1 -all");
+        let counting = Arc::new(counting);
+
+        let cache = CachingResolver::new(counting.clone(), 10, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert_eq!(cache.txt("Example.com.").unwrap(), vec!["v=spf1 -all".to_owned()]);
+            assert!(cache.txt("missing.example.com").unwrap().is_empty());
+        }
+
+        assert_eq!(counting.lookups.load(Ordering::SeqCst), 2);
+        assert!(OfflineResolver.txt("example.com").is_err());
+    }
+}
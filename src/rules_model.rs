@@ -0,0 +1,61 @@
+use extraction::Features;
+use feature_vector::*;
+use model::{ModelBackend, BatchScore, Scores};
+
+/// Flags the email if the named column reaches the threshold
+struct Rule {
+    column: &'static str,
+    threshold: f32,
+}
+
+/// A fixed set of hand written rules, for when no trained model is available
+const RULES: &'static [Rule] = &[
+    Rule { column: "positive_sentiment", threshold: 10.0 },
+];
+
+pub struct RulesBackend {
+    schema: Vec<String>,
+}
+
+impl RulesBackend {
+    pub fn new() -> RulesBackend {
+        RulesBackend {
+            schema: Features::columns().into_iter().map(String::from).collect(),
+        }
+    }
+
+    fn matches(vector: &FeatureVector) -> bool {
+        RULES.iter().any(|rule| {
+            vector.columns()
+                .iter()
+                .position(|c| *c == rule.column)
+                .map(|i| vector.values()[i] >= rule.threshold)
+                .unwrap_or(false)
+        })
+    }
+}
+
+impl ModelBackend for RulesBackend {
+    fn name(&self) -> &'static str {
+        "rules"
+    }
+
+    fn version(&self) -> String {
+        format!("rules-{}", RULES.len())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.schema.clone()
+    }
+
+    fn predict_batch(&self, features: Vec<Features>, res: BatchScore) {
+        let probabilities = features.iter()
+            .map(|f| if RulesBackend::matches(&f.to_vector()) { 1.0 } else { 0.0 })
+            .collect();
+
+        res(Ok(Scores {
+            model_version: self.version(),
+            probabilities,
+        }));
+    }
+}
@@ -0,0 +1,170 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rustlearn::prelude::*;
+use rustlearn::ensemble::random_forest::{self, RandomForest};
+use rustlearn::trees::decision_tree;
+use serde_json;
+
+use errors::*;
+use dataset::Dataset;
+use extraction::Features;
+use feature_vector::*;
+use model::{ModelBackend, BatchScore, Probabilities, Scores};
+
+/// What we write to disk - the forest along with the columns it was trained on
+#[derive(Serialize, Deserialize)]
+pub struct StoredModel {
+    pub columns: Vec<String>,
+    /// Seconds since the epoch at which the model was trained
+    #[serde(default)]
+    pub version: String,
+    pub forest: RandomForest,
+}
+
+/// Serves a trained forest from disk without any IPC
+pub struct RustBackend {
+    model: RustModelActor,
+    version: String,
+    schema: Vec<String>,
+}
+
+impl RustBackend {
+    pub fn new(path: PathBuf, system: SystemActor) -> RustBackend {
+        let stored = RustModel::load(&path)
+            .expect(&format!("Failed to load model at {:#?}", path));
+
+        let forest = Arc::new(stored.forest);
+        let rust_model =
+            move |self_ref, system| RustModel::new(forest.clone(), self_ref, system);
+        let model = RustModelActor::new(rust_model, system.clone(), Duration::from_secs(30));
+
+        RustBackend {
+            model,
+            version: stored.version,
+            schema: stored.columns,
+        }
+    }
+}
+
+impl ModelBackend for RustBackend {
+    fn name(&self) -> &'static str {
+        "rust"
+    }
+
+    fn version(&self) -> String {
+        self.version.clone()
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.schema.clone()
+    }
+
+    fn predict_batch(&self, features: Vec<Features>, res: BatchScore) {
+        self.model.predict_batch(features, Scores::with_version(self.version.clone(), res));
+    }
+}
+
+/// An in-process random forest, a stand-in for the Python model service when
+/// Python is not available.
+pub struct RustModel {
+    self_ref: RustModelActor,
+    system: SystemActor,
+    forest: Arc<RandomForest>,
+}
+
+#[derive_actor]
+impl RustModel {
+    pub fn predict_batch(&mut self, features: Vec<Features>, res: Probabilities) {
+        let rows: Vec<Vec<f32>> = features.iter()
+            .map(|f| f.to_vector().values().to_vec())
+            .collect();
+        let x = Array::from(&rows);
+
+        match self.forest.decision_function(&x) {
+            Ok(p) => res(Ok(p.data().to_vec())),
+            Err(e) => {
+                res(Err(ErrorKind::UnrecoverableError(
+                    format!("Failed to predict {}", e).into())
+                    .into()))
+            }
+        }
+    }
+}
+
+impl RustModel {
+    pub fn new(forest: Arc<RandomForest>, self_ref: RustModelActor, system: SystemActor) -> RustModel {
+        RustModel {
+            self_ref,
+            system,
+            forest,
+        }
+    }
+
+    /// Trains a forest on a labeled feature CSV and writes it to `output`
+    pub fn train(data: &Path, output: &Path) -> Result<()> {
+        let data = Dataset::from_csv(data)?;
+
+        if data.rows.is_empty() {
+            bail!(ErrorKind::UnrecoverableError("No training rows".into()))
+        }
+
+        let x = Array::from(&data.rows);
+        let y = Array::from(data.labels.clone());
+
+        let mut tree_params = decision_tree::Hyperparameters::new(data.columns.len());
+        tree_params.min_samples_split(10);
+
+        let mut forest = random_forest::Hyperparameters::new(tree_params, 10).build();
+        forest.fit(&x, &y)
+            .map_err(|e| ErrorKind::UnrecoverableError(format!("Failed to train model {}", e).into()))?;
+
+        let trained_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let stored = StoredModel {
+            columns: data.columns,
+            version: trained_at.to_string(),
+            forest,
+        };
+
+        let file = File::create(output)?;
+        serde_json::to_writer(file, &stored)
+            .chain_err(|| format!("Failed to write model to {:#?}", output))?;
+
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<StoredModel> {
+        let file = File::open(path)?;
+        let stored = serde_json::from_reader(file)
+            .chain_err(|| format!("Failed to read model from {:#?}", path))?;
+        Ok(stored)
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: RustModelMessage,
+                   t: Arc<T>)
+        where T: Fn(RustModelActor, SystemActor) -> RustModel + Send + Sync + 'static
+    {
+        match msg {
+            RustModelMessage::PredictBatchVariant { features, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in the rust model".into()).into())
+                );
+            }
+        };
+    }
+}
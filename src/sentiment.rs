@@ -6,12 +6,40 @@ use std::sync::Arc;
 use _sentiment::*;
 
 use errors::*;
+use feature_vector::FeatureColumns;
 
 pub struct SentimentAnalyzer {
     self_ref: SentimentAnalyzerActor,
     system: SystemActor
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SentimentFeatures {
+    pub relative_sentiment: f32,
+    pub positive_sentiment: f32,
+    pub negative_sentiment: f32,
+}
+
+impl From<Analysis> for SentimentFeatures {
+    fn from(analysis: Analysis) -> SentimentFeatures {
+        SentimentFeatures {
+            relative_sentiment: analysis.comparative,
+            positive_sentiment: analysis.positive.score,
+            negative_sentiment: analysis.negative.score,
+        }
+    }
+}
+
+impl FeatureColumns for SentimentFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["relative_sentiment", "positive_sentiment", "negative_sentiment"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.relative_sentiment, self.positive_sentiment, self.negative_sentiment]
+    }
+}
+
 // TODO: Make Analysis a result
 type SentimentResponse = std::sync::Arc<Fn(Result<Analysis>) + Send + Sync + 'static>;
 
@@ -2,9 +2,11 @@ use derive_aktor::derive_actor;
 use aktors::actor::SystemActor;
 
 use std;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::hash::Hasher;
+use std::time::Duration;
 
+use lru_time_cache::LruCache;
 use twox_hash::XxHash;
 
 use byteorder::{ByteOrder, LittleEndian};
@@ -15,6 +17,10 @@ use model::*;
 use extraction::*;
 use state::*;
 use email_reader::*;
+use verdict::Verdict;
+use shadow::ShadowComparatorActor;
+use feature_store::FeatureStore;
+use campaign::CampaignIndex;
 
 pub struct SpamDetectionService {
     self_ref: SpamDetectionServiceActor,
@@ -22,9 +28,36 @@ pub struct SpamDetectionService {
     prediction_cache: PredictionCacheActor,
     extractor: FeatureExtractionManagerActor,
     model: ModelActor,
+    shadow: Option<Shadow>,
+    feature_store: Option<Arc<FeatureStore>>,
+    campaigns: Arc<CampaignIndex>,
 }
 
-pub type PredictionResult = std::sync::Arc<Fn(Result<bool>) + Send + Sync + 'static>;
+/// A second model that sees the same features as the primary one, but whose
+/// verdicts are only logged
+#[derive(Clone)]
+pub struct Shadow {
+    pub model: ModelActor,
+    pub comparator: ShadowComparatorActor,
+    /// Hashes of the emails the shadow model was already asked about, so a
+    /// retried email is only shadowed once
+    shadowed: Arc<Mutex<LruCache<Vec<u8>, ()>>>,
+}
+
+impl Shadow {
+    /// Clones share which emails have been shadowed
+    pub fn new(model: ModelActor, comparator: ShadowComparatorActor) -> Shadow {
+        Shadow {
+            model,
+            comparator,
+            // As long as the comparator waits for the other verdict
+            shadowed: Arc::new(Mutex::new(
+                LruCache::with_expiry_duration_and_capacity(Duration::from_secs(60), 10_000))),
+        }
+    }
+}
+
+pub type PredictionResult = std::sync::Arc<Fn(Result<Verdict>) + Send + Sync + 'static>;
 
 type PredErr = std::sync::Arc<Error>;
 
@@ -35,28 +68,45 @@ impl SpamDetectionService {
         let res = res.clone();
         let email = email.clone();
 
-        let hash = SpamDetectionService::hash_email(email.clone());
+        let hash = hash_email(&email);
 
         self.prediction_cache
             .get(hash, std::sync::Arc::new(move |cache_res| {
                 match cache_res {
-                    Ok(Some(hit)) => {
-                        println!("pred cache hit");
-                        res(Ok(hit));
-                    }
+                    Ok(Some(hit)) => res(Ok(hit)),
                     _ => self_ref.clone().predict(email.clone(), res.clone())
                 };
             }));
     }
 
     pub fn predict(&self, email: EmailBytes, res: PredictionResult) {
-        let self_ref = self.self_ref.clone();
         let model = self.model.clone();
+        let shadow = self.shadow.clone();
+        let feature_store = self.feature_store.clone();
+        let campaigns = self.campaigns.clone();
+        let hash = hash_email(&email);
+        let res = SpamDetectionService::write_back(self.prediction_cache.clone(), hash.clone(), res);
+
+        if let Some(ref store) = feature_store {
+            match store.get(&hash) {
+                Ok(Some(features)) => {
+                    return SpamDetectionService::classify(&model, &shadow, &campaigns, &hash, features, res);
+                }
+                Ok(None) => (),
+                Err(e) => println!("Failed to read the feature store: {}", e),
+            }
+        }
 
         self.extractor.extract(email, std::sync::Arc::new(move |features| {
             match features {
                 Ok(data) => {
-                    model.clone().predict(data, res.clone())
+                    if let Some(ref store) = feature_store {
+                        if let Err(e) = store.put(&hash, &data) {
+                            println!("Failed to write the feature store: {}", e);
+                        }
+                    }
+
+                    SpamDetectionService::classify(&model, &shadow, &campaigns, &hash, data, res.clone())
                 }
                 Err(e) => {
                     res(Err(e));
@@ -65,20 +115,77 @@ impl SpamDetectionService {
         }));
     }
 
-    fn hash_email(email: EmailBytes) -> Vec<u8> {
-        let mut hasher = XxHash::default();
-        hasher.write(email.as_ref());
-        let hash = hasher.finish();
-        let mut buf = vec![0; 8];
-        LittleEndian::write_u64(&mut buf, hash);
-        buf
+    /// Every try of the primary model is recorded, but the shadow model only
+    /// sees the email the first time
+    fn predict_with_shadow(model: &ModelActor,
+                           shadow: &Shadow,
+                           hash: &[u8],
+                           features: Features,
+                           res: PredictionResult) {
+        let id = comparison_id(hash);
+
+        let first_try = shadow.shadowed.lock().unwrap().insert(hash.to_vec(), ()).is_none();
+        if first_try {
+            let comparator = shadow.comparator.clone();
+            let shadow_id = id.clone();
+            shadow.model.predict(features.clone(), std::sync::Arc::new(move |verdict| {
+                if let Err(ref e) = verdict {
+                    println!("Shadow model failed {}", e);
+                }
+                comparator.record_shadow(shadow_id.clone(), verdict.ok());
+            }));
+        }
+
+        let comparator = shadow.comparator.clone();
+        model.predict(features, std::sync::Arc::new(move |verdict| {
+            comparator.record_primary(id.clone(), verdict.as_ref().ok().cloned());
+            res(verdict);
+        }));
+    }
+
+}
+
+/// The campaign's score for an email, with the email's own reasons and
+/// authentication results rather than those of the email first given it
+fn reused_verdict(prior: Verdict, features: &Features) -> Verdict {
+    let mut reasons = features.reasons();
+    if let Some(id) = features.campaign.campaign {
+        reasons.push(format!("matches campaign {:016x}", id));
+    }
+
+    Verdict {
+        probability: prior.probability,
+        label: prior.label,
+        model_version: prior.model_version,
+        // No model was asked
+        latency: Duration::from_secs(0),
+        reasons,
+        authentication: features.authentication.results.clone(),
     }
 }
 
+/// Pairs up an email's primary and shadow verdicts across retries
+fn comparison_id(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Identifies byte-identical emails, for the prediction cache and feature store
+pub fn hash_email(email: &[u8]) -> Vec<u8> {
+    let mut hasher = XxHash::default();
+    hasher.write(email);
+    let hash = hasher.finish();
+    let mut buf = vec![0; 8];
+    LittleEndian::write_u64(&mut buf, hash);
+    buf
+}
+
 impl SpamDetectionService {
     pub fn new(prediction_cache: PredictionCacheActor,
                extractor: FeatureExtractionManagerActor,
                model: ModelActor,
+               shadow: Option<Shadow>,
+               feature_store: Option<Arc<FeatureStore>>,
+               campaigns: Arc<CampaignIndex>,
                self_ref: SpamDetectionServiceActor,
                system: SystemActor) -> SpamDetectionService {
         SpamDetectionService {
@@ -87,6 +194,54 @@ impl SpamDetectionService {
             prediction_cache,
             extractor,
             model,
+            shadow,
+            feature_store,
+            campaigns,
+        }
+    }
+
+    /// Passes verdicts on to `res`, caching the successful ones
+    fn write_back(cache: PredictionCacheActor, hash: Vec<u8>, res: PredictionResult) -> PredictionResult {
+        std::sync::Arc::new(move |verdict| {
+            if let Ok(ref verdict) = verdict {
+                cache.set(hash.clone(), verdict.clone());
+            }
+            res(verdict)
+        })
+    }
+
+    /// Adds the email to its campaign, then reuses the campaign's verdict
+    /// where allowed, otherwise asks the model and remembers its verdict for
+    /// the campaign. Campaigns grow after features are stored, so they're
+    /// observed here rather than during extraction.
+    fn classify(model: &ModelActor,
+                shadow: &Option<Shadow>,
+                campaigns: &Arc<CampaignIndex>,
+                hash: &[u8],
+                mut features: Features,
+                res: PredictionResult) {
+        features.campaign = campaigns.observe(hash, &features.campaign);
+
+        if let Some(prior) = campaigns.prior_verdict(&features.campaign) {
+            // Neither model saw the email, there's nothing to compare
+            if let Some(ref shadow) = *shadow {
+                shadow.comparator.record_reused(comparison_id(hash));
+            }
+            return res(Ok(reused_verdict(prior, &features)));
+        }
+
+        let campaign = features.campaign.clone();
+        let campaigns = campaigns.clone();
+        let res: PredictionResult = std::sync::Arc::new(move |verdict| {
+            if let Ok(ref verdict) = verdict {
+                campaigns.record_verdict(&campaign, verdict);
+            }
+            res(verdict)
+        });
+
+        match *shadow {
+            Some(ref shadow) => SpamDetectionService::predict_with_shadow(model, shadow, hash, features, res),
+            None => model.predict(features, res),
         }
     }
 
@@ -102,4 +257,25 @@ impl SpamDetectionService {
     {
         // t(self.self_ref.clone(), self.system.clone());
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use verdict::Thresholds;
+
+    #[test]
+    fn reused_verdicts_describe_the_current_email() {
+        let prior = Verdict::new(0.95, &Thresholds::default(), "v1".to_owned(), Duration::from_millis(3))
+            .with_reasons(vec!["invoice.exe is an executable".to_owned()]);
+
+        let mut features = Features::default();
+        features.campaign.campaign = Some(0xab);
+
+        let verdict = reused_verdict(prior, &features);
+        assert_eq!(verdict.probability, 0.95);
+        assert_eq!(verdict.model_version, "v1");
+        assert_eq!(verdict.reasons, vec!["matches campaign 00000000000000ab".to_owned()]);
+        assert_eq!(verdict.latency, Duration::from_secs(0));
+    }
+}
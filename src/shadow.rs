@@ -0,0 +1,156 @@
+use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
+
+use std;
+use std::sync::Arc;
+use std::time::Duration;
+
+use lru_time_cache::LruCache;
+
+use verdict::Verdict;
+
+/// Which model a verdict came from
+#[derive(Clone, Debug)]
+enum Source {
+    Primary(Verdict),
+    Shadow(Verdict),
+}
+
+#[derive(Default)]
+struct ShadowStats {
+    compared: usize,
+    disagreements: usize,
+    /// Failed tries of the primary model. The email may be retried, so its
+    /// shadow verdict is kept for the next try.
+    primary_errors: usize,
+    shadow_errors: usize,
+    /// Emails given their campaign's verdict, which neither model saw and so
+    /// aren't compared
+    reused: usize,
+    // Shadow latency minus primary latency, summed over every comparison
+    latency_difference_ms: f64,
+}
+
+/// Pairs up the primary and shadow verdicts for each email and logs how they differ.
+///
+/// Shadow verdicts only ever come here, they never reach the caller. Verdicts
+/// are paired by an id naming the email, the same for every try of it.
+pub struct ShadowComparator {
+    self_ref: ShadowComparatorActor,
+    system: SystemActor,
+    // Whichever verdict arrived first, waiting for the other
+    pending: LruCache<String, Source>,
+    stats: ShadowStats,
+    report_every: usize,
+}
+
+#[derive_actor]
+impl ShadowComparator {
+    pub fn record_primary(&mut self, id: String, verdict: Option<Verdict>) {
+        let verdict = match verdict {
+            Some(verdict) => verdict,
+            None => {
+                self.stats.primary_errors += 1;
+                return;
+            }
+        };
+
+        match self.pending.remove(&id) {
+            Some(Source::Shadow(shadow)) => self.compare(&id, verdict, shadow),
+            _ => {
+                self.pending.insert(id, Source::Primary(verdict));
+            }
+        }
+    }
+
+    pub fn record_shadow(&mut self, id: String, verdict: Option<Verdict>) {
+        let verdict = match verdict {
+            Some(verdict) => verdict,
+            None => {
+                self.stats.shadow_errors += 1;
+                self.pending.remove(&id);
+                return;
+            }
+        };
+
+        match self.pending.remove(&id) {
+            Some(Source::Primary(primary)) => self.compare(&id, primary, verdict),
+            _ => {
+                self.pending.insert(id, Source::Shadow(verdict));
+            }
+        }
+    }
+
+    /// The email got its campaign's verdict instead of either model's
+    pub fn record_reused(&mut self, id: String) {
+        self.stats.reused += 1;
+        // A shadow verdict from an earlier try has nothing left to pair with
+        self.pending.remove(&id);
+    }
+}
+
+impl ShadowComparator {
+    pub fn new(report_every: usize,
+               self_ref: ShadowComparatorActor,
+               system: SystemActor) -> ShadowComparator {
+        ShadowComparator {
+            self_ref,
+            system,
+            // Verdicts whose pair never shows up are dropped after a minute
+            pending: LruCache::with_expiry_duration_and_capacity(Duration::from_secs(60), 10_000),
+            stats: ShadowStats::default(),
+            report_every,
+        }
+    }
+
+    fn compare(&mut self, id: &str, primary: Verdict, shadow: Verdict) {
+        let latency_difference = millis(shadow.latency) - millis(primary.latency);
+
+        self.stats.compared += 1;
+        self.stats.latency_difference_ms += latency_difference;
+
+        if primary.label != shadow.label {
+            self.stats.disagreements += 1;
+            println!("shadow disagreement {}: primary {:?} {:.3} (model {}) shadow {:?} {:.3} (model {})",
+                     id,
+                     primary.label, primary.probability, primary.model_version,
+                     shadow.label, shadow.probability, shadow.model_version);
+        }
+
+        println!("shadow {}: primary {:.3} in {:.1}ms, shadow {:.3} in {:.1}ms",
+                 id,
+                 primary.probability, millis(primary.latency),
+                 shadow.probability, millis(shadow.latency));
+
+        if self.report_every > 0 && self.stats.compared % self.report_every == 0 {
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        println!("shadow summary: {} compared, {} disagreements, \
+                  mean latency difference {:.1}ms, {} primary errors, {} shadow errors, \
+                  {} campaign verdicts reused and not compared",
+                 self.stats.compared,
+                 self.stats.disagreements,
+                 self.stats.latency_difference_ms / self.stats.compared as f64,
+                 self.stats.primary_errors,
+                 self.stats.shadow_errors,
+                 self.stats.reused);
+    }
+
+    fn on_timeout(&mut self) {}
+
+    fn on_error<T>(&mut self,
+                   err: Box<std::any::Any + Send>,
+                   msg: ShadowComparatorMessage,
+                   t: Arc<T>)
+        where T: Fn(ShadowComparatorActor, SystemActor) -> ShadowComparator + Send + Sync + 'static
+    {
+        // t(self.self_ref.clone(), self.system.clone());
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
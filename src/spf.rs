@@ -0,0 +1,203 @@
+use std::net::IpAddr;
+
+use resolver::Resolver;
+use authentication::AuthResult;
+
+/// RFC 7208 caps the DNS querying terms evaluated per check
+const MAX_LOOKUPS: usize = 10;
+
+/// Evaluates the SPF policy of `domain` for mail sent from `ip`. Macros and
+/// `ptr` aren't supported, terms using them never match.
+pub fn check_host(resolver: &Resolver, ip: IpAddr, domain: &str) -> AuthResult {
+    let mut lookups = 0;
+    check(resolver, ip, domain, &mut lookups)
+}
+
+fn check(resolver: &Resolver, ip: IpAddr, domain: &str, lookups: &mut usize) -> AuthResult {
+    let records = match resolver.txt(domain) {
+        Ok(records) => records,
+        Err(_) => return AuthResult::TempError,
+    };
+
+    let mut records = records.into_iter()
+        .filter(|r| r == "v=spf1" || r.starts_with("v=spf1 "));
+
+    let record = match (records.next(), records.next()) {
+        (Some(record), None) => record,
+        (None, _) => return AuthResult::None,
+        (Some(_), Some(_)) => return AuthResult::PermError,
+    };
+
+    let mut redirect = None;
+
+    for term in record.split_whitespace().skip(1) {
+        if term.starts_with("redirect=") {
+            redirect = Some(term["redirect=".len()..].to_owned());
+            continue;
+        }
+
+        if term.contains('=') {
+            // exp= and unknown modifiers don't affect the result
+            continue;
+        }
+
+        let (result, mechanism) = match term.chars().next() {
+            Some('+') => (AuthResult::Pass, &term[1..]),
+            Some('-') => (AuthResult::Fail, &term[1..]),
+            Some('~') => (AuthResult::SoftFail, &term[1..]),
+            Some('?') => (AuthResult::Neutral, &term[1..]),
+            _ => (AuthResult::Pass, term),
+        };
+
+        match matches(resolver, ip, domain, mechanism, lookups) {
+            Ok(true) => return result,
+            Ok(false) => (),
+            Err(error) => return error,
+        }
+    }
+
+    match redirect {
+        Some(target) => {
+            *lookups += 1;
+            if *lookups > MAX_LOOKUPS {
+                return AuthResult::PermError;
+            }
+
+            match check(resolver, ip, &target, lookups) {
+                AuthResult::None => AuthResult::PermError,
+                result => result,
+            }
+        }
+        None => AuthResult::Neutral,
+    }
+}
+
+/// Whether a mechanism matches, or the error that ends evaluation
+fn matches(resolver: &Resolver,
+           ip: IpAddr,
+           domain: &str,
+           mechanism: &str,
+           lookups: &mut usize) -> Result<bool, AuthResult> {
+    let (name, argument) = match mechanism.find(|c| c == ':' || c == '/') {
+        Some(i) => (&mechanism[..i], &mechanism[i..]),
+        None => (mechanism, ""),
+    };
+
+    if argument.contains('%') {
+        return Ok(false);
+    }
+
+    match name.to_lowercase().as_ref() {
+        "all" => Ok(true),
+        "ip4" | "ip6" => {
+            let (network, prefix) = split_cidr(argument.trim_left_matches(':'));
+            match network.parse::<IpAddr>() {
+                Ok(network) => Ok(in_network(ip, network, prefix)),
+                Err(_) => Err(AuthResult::PermError),
+            }
+        }
+        "include" => {
+            count_lookup(lookups)?;
+            let target = argument.trim_left_matches(':');
+            match check(resolver, ip, target, lookups) {
+                AuthResult::Pass => Ok(true),
+                AuthResult::Fail | AuthResult::SoftFail | AuthResult::Neutral => Ok(false),
+                AuthResult::TempError => Err(AuthResult::TempError),
+                _ => Err(AuthResult::PermError),
+            }
+        }
+        "a" => {
+            count_lookup(lookups)?;
+            let (target, prefix) = target_and_prefix(domain, argument);
+            let ips = resolver.ip(&target).map_err(|_| AuthResult::TempError)?;
+            Ok(ips.into_iter().any(|network| in_network(ip, network, prefix)))
+        }
+        "mx" => {
+            count_lookup(lookups)?;
+            let (target, prefix) = target_and_prefix(domain, argument);
+            let exchanges = resolver.mx(&target).map_err(|_| AuthResult::TempError)?;
+            for exchange in exchanges {
+                let ips = resolver.ip(&exchange).map_err(|_| AuthResult::TempError)?;
+                if ips.into_iter().any(|network| in_network(ip, network, prefix)) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        "exists" => {
+            count_lookup(lookups)?;
+            let target = argument.trim_left_matches(':');
+            let ips = resolver.ip(target).map_err(|_| AuthResult::TempError)?;
+            Ok(!ips.is_empty())
+        }
+        "ptr" => {
+            count_lookup(lookups)?;
+            Ok(false)
+        }
+        _ => Err(AuthResult::PermError),
+    }
+}
+
+fn count_lookup(lookups: &mut usize) -> Result<(), AuthResult> {
+    *lookups += 1;
+    if *lookups > MAX_LOOKUPS {
+        Err(AuthResult::PermError)
+    } else {
+        Ok(())
+    }
+}
+
+/// `:domain/prefix`, either part optional
+fn target_and_prefix(domain: &str, argument: &str) -> (String, Option<u8>) {
+    let (target, prefix) = split_cidr(argument.trim_left_matches(':'));
+    let target = if target.is_empty() { domain } else { target };
+    (target.to_owned(), prefix)
+}
+
+fn split_cidr(s: &str) -> (&str, Option<u8>) {
+    match s.find('/') {
+        Some(i) => (&s[..i], s[i + 1..].split('/').next().and_then(|p| p.parse().ok())),
+        None => (s, None),
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: Option<u8>) -> bool {
+    let (ip, network, max) = match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => (ip.octets().to_vec(), network.octets().to_vec(), 32),
+        (IpAddr::V6(ip), IpAddr::V6(network)) => (ip.octets().to_vec(), network.octets().to_vec(), 128),
+        _ => return false,
+    };
+
+    let prefix = ::std::cmp::min(prefix.unwrap_or(max), max) as usize;
+    let (bytes, bits) = (prefix / 8, prefix % 8);
+
+    if ip[..bytes] != network[..bytes] {
+        return false;
+    }
+
+    if bits == 0 {
+        return true;
+    }
+
+    let mask = 0xFFu8 << (8 - bits);
+    ip[bytes] & mask == network[bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use resolver::ZoneFileResolver;
+
+    #[test]
+    fn evaluates_includes_and_networks() {
+        let mut zone = ZoneFileResolver::default();
+        zone.add("example.com", "TXT", "\"v=spf1 ip4:192.0.2.0/24 include:_spf.mailer.net -all\"");
+        zone.add("_spf.mailer.net", "TXT", "v=spf1 a:out.mailer.net ~all");
+        zone.add("out.mailer.net", "A", "198.51.100.7");
+
+        assert_eq!(check_host(&zone, "192.0.2.55".parse().unwrap(), "example.com"), AuthResult::Pass);
+        assert_eq!(check_host(&zone, "198.51.100.7".parse().unwrap(), "example.com"), AuthResult::Pass);
+        assert_eq!(check_host(&zone, "203.0.113.1".parse().unwrap(), "example.com"), AuthResult::Fail);
+        assert_eq!(check_host(&zone, "203.0.113.1".parse().unwrap(), "nospf.com"), AuthResult::None);
+    }
+}
@@ -0,0 +1,288 @@
+use std;
+use std::io::{BufRead, BufReader, Read};
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use reqwest::Client;
+
+use config::SupervisorConfig;
+use errors::*;
+
+/// Builds the command to run the child on the given port
+pub type SpawnCommand = Arc<Fn(u16) -> Command + Send + Sync + 'static>;
+
+/// Run against every healthy child, the first and each one restarted, with
+/// the port it listens on. A child that fails it is killed and never used.
+pub type ReadyCheck = Arc<Fn(u16) -> Result<()> + Send + Sync + 'static>;
+
+/// Health checks of a starting child are spaced 2ms, 4ms, 8ms and so on
+/// apart, up to `max`
+fn health_check_delay(attempt: usize, max: Duration) -> Duration {
+    std::cmp::min(Duration::from_millis(2u64 << std::cmp::min(attempt, 32)), max)
+}
+
+/// What the rest of the system needs to know about a supervised child - where
+/// it's listening and whether it's currently usable.
+#[derive(Clone)]
+pub struct ChildStatus {
+    port: Arc<AtomicUsize>,
+    available: Arc<AtomicBool>,
+}
+
+impl ChildStatus {
+    pub fn port(&self) -> u16 {
+        self.port.load(Ordering::SeqCst) as u16
+    }
+
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::SeqCst)
+    }
+}
+
+/// Owns a child process serving HTTP on localhost.
+///
+/// The child's exit status is polled and its `health_path` endpoint is checked on a
+/// timer. If it exits or stops answering it is restarted on a fresh port with
+/// exponential backoff, and is marked unavailable until it's healthy again and
+/// has passed its ReadyCheck.
+/// Its stdout and stderr are forwarded to our own logs.
+pub struct ChildSupervisor {
+    name: String,
+    child: Arc<Mutex<Option<Child>>>,
+    status: ChildStatus,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl ChildSupervisor {
+    /// Starts the child, returning once it's healthy
+    pub fn start(name: String,
+                 spawn: SpawnCommand,
+                 health_path: String,
+                 ready: ReadyCheck,
+                 config: SupervisorConfig) -> Result<ChildSupervisor> {
+        let mut backoff = config.min_backoff();
+        let mut started = None;
+
+        for attempt in 0..config.startup_attempts {
+            match ChildSupervisor::spawn_healthy(&name, &spawn, &health_path, &ready, &config) {
+                Ok(child) => {
+                    started = Some(child);
+                    break;
+                }
+                Err(e) => {
+                    println!("Failed to start {} (attempt {}): {}", name, attempt + 1, e);
+                    thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, config.max_backoff());
+                }
+            }
+        }
+
+        let (child, port) = match started {
+            Some(started) => started,
+            None => bail!(ErrorKind::UnrecoverableError(
+                format!("Could not start {} after {} attempts", name, config.startup_attempts).into()))
+        };
+
+        let supervisor = ChildSupervisor {
+            name,
+            child: Arc::new(Mutex::new(Some(child))),
+            status: ChildStatus {
+                port: Arc::new(AtomicUsize::new(port as usize)),
+                available: Arc::new(AtomicBool::new(true)),
+            },
+            shutdown: Arc::new(AtomicBool::new(false)),
+        };
+
+        supervisor.watch(spawn, health_path, ready, config);
+
+        Ok(supervisor)
+    }
+
+    pub fn status(&self) -> ChildStatus {
+        self.status.clone()
+    }
+
+    fn watch(&self, spawn: SpawnCommand, health_path: String, ready: ReadyCheck, config: SupervisorConfig) {
+        let name = self.name.clone();
+        let child = self.child.clone();
+        let status = self.status.clone();
+        let shutdown = self.shutdown.clone();
+
+        thread::spawn(move || {
+            let client = Client::new();
+            let mut failed_checks = 0;
+
+            loop {
+                thread::sleep(config.health_check_interval());
+
+                if shutdown.load(Ordering::SeqCst) {
+                    return;
+                }
+
+                let exited = match child.lock().unwrap().as_mut().map(|c| c.try_wait()) {
+                    Some(Ok(Some(exit_status))) => {
+                        println!("{} exited with {}", name, exit_status);
+                        true
+                    }
+                    Some(Err(e)) => {
+                        println!("Failed to check on {}: {}", name, e);
+                        true
+                    }
+                    _ => false,
+                };
+
+                if !exited {
+                    if ChildSupervisor::is_healthy(&client, status.port(), &health_path) {
+                        failed_checks = 0;
+                        continue;
+                    }
+
+                    failed_checks += 1;
+                    println!("{} failed health check {} of {}", name, failed_checks, config.max_failed_checks);
+                    if failed_checks < config.max_failed_checks {
+                        continue;
+                    }
+                }
+
+                failed_checks = 0;
+                status.available.store(false, Ordering::SeqCst);
+
+                if let Some(mut old) = child.lock().unwrap().take() {
+                    let _ = old.kill();
+                    let _ = old.wait();
+                }
+
+                let mut backoff = config.min_backoff();
+                loop {
+                    if shutdown.load(Ordering::SeqCst) {
+                        return;
+                    }
+
+                    println!("Restarting {}", name);
+                    match ChildSupervisor::spawn_healthy(&name, &spawn, &health_path, &ready, &config) {
+                        Ok((new_child, port)) => {
+                            *child.lock().unwrap() = Some(new_child);
+                            status.port.store(port as usize, Ordering::SeqCst);
+                            status.available.store(true, Ordering::SeqCst);
+                            println!("Restarted {} on port {}", name, port);
+                            break;
+                        }
+                        Err(e) => {
+                            println!("Failed to restart {}: {}", name, e);
+                            thread::sleep(backoff);
+                            backoff = std::cmp::min(backoff * 2, config.max_backoff());
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Spawns the child on a free port and waits for it to pass a health check
+    /// and then `ready`
+    fn spawn_healthy(name: &str,
+                     spawn: &SpawnCommand,
+                     health_path: &str,
+                     ready: &ReadyCheck,
+                     config: &SupervisorConfig) -> Result<(Child, u16)> {
+        let port = free_port()?;
+
+        let mut child = spawn(port)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("Failed to spawn {}", name))?;
+
+        if let Some(stdout) = child.stdout.take() {
+            forward_output(format!("{} stdout", name), stdout);
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            forward_output(format!("{} stderr", name), stderr);
+        }
+
+        let client = Client::new();
+        for i in 0..config.health_check_retries {
+            thread::sleep(health_check_delay(i, config.max_backoff()));
+
+            if ChildSupervisor::is_healthy(&client, port, health_path) {
+                if let Err(e) = ready(port) {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(e).chain_err(|| format!("{} on port {} isn't usable", name, port));
+                }
+
+                println!("Connected to {} on port {}", name, port);
+                return Ok((child, port));
+            }
+
+            if let Ok(Some(exit_status)) = child.try_wait() {
+                bail!(ErrorKind::RecoverableError(
+                    format!("{} exited during startup with {}", name, exit_status).into()))
+            }
+        }
+
+        let _ = child.kill();
+        let _ = child.wait();
+        bail!(ErrorKind::RecoverableError(
+            format!("{} did not become healthy on port {}", name, port).into()))
+    }
+
+    fn is_healthy(client: &Client, port: u16, health_path: &str) -> bool {
+        let url = format!("http://127.0.0.1:{}/{}", port, health_path);
+        client.get(&url)
+            .send()
+            .map(|r| r.status().is_success())
+            .unwrap_or(false)
+    }
+}
+
+impl Drop for ChildSupervisor {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.status.available.store(false, Ordering::SeqCst);
+
+        if let Ok(mut child) = self.child.lock() {
+            if let Some(mut child) = child.take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+/// Asks the OS for an unused port. The listener is dropped before the child
+/// binds, but unlike picking a random port it can't collide with one in use.
+pub fn free_port() -> Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+fn forward_output<R: Read + Send + 'static>(prefix: String, output: R) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) => println!("[{}] {}", prefix, line),
+                Err(_) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_check_delays_are_capped() {
+        let max = Duration::from_secs(30);
+        assert_eq!(health_check_delay(0, max), Duration::from_millis(2));
+        assert_eq!(health_check_delay(3, max), Duration::from_millis(16));
+        assert_eq!(health_check_delay(40, max), max);
+        assert_eq!(health_check_delay(usize::max_value(), max), max);
+    }
+}
@@ -1,59 +1,131 @@
 use derive_aktor::derive_actor;
+use aktors::actor::SystemActor;
 
 use std;
-
-use std::hash::Hasher;
-
-use twox_hash::XxHash;
-
-use byteorder::{ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::sync::Arc;
 
 use errors::*;
 use email::*;
-use model::*;
-use extraction::*;
+use mime::*;
+use feature_vector::FeatureColumns;
 
-use std::sync::Arc;
+/// Common English words, most frequent first
+const WORDS: &'static str = include_str!("words.txt");
 
-pub struct TextFeatureExtractor
-{
-    self_ref: CompletionHandlerActor,
-    system: SystemActor
+pub struct TextFeatureExtractor {
+    self_ref: TextFeatureExtractorActor,
+    system: SystemActor,
+    // Word to its frequency rank in WORDS
+    dictionary: Arc<HashMap<String, usize>>,
 }
 
-type Completion = std::sync::Arc<Fn() + Send + Sync + 'static>;
+type TextFeaturesResponse = std::sync::Arc<Fn(Result<TextFeatures>) + Send + Sync + 'static>;
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct TextFeatures {
-    body_length: usize,
-    subject_length: usize,
-    body_subject_ration: f32,
-    header_count: usize,
-    min_header_size: usize,
-    max_header_size: usize,
-    avg_header_size: usize,
-    mimetype_commonality: f32,
-    charset_commonality: f32,
-    charset_label: f32,
-    content_name_commonality: f32,
-    unknown_words: f32,
-    known_unknown_words_ratio: f32,
-    grammar_errors: f32,
-    caps_lower_ratio: f32,
-    tf_idf: f32
+    pub body_length: usize,
+    pub subject_length: usize,
+    pub body_subject_ratio: f32,
+    pub header_count: usize,
+    pub min_header_size: usize,
+    pub max_header_size: usize,
+    pub avg_header_size: usize,
+    pub mimetype_commonality: f32,
+    pub charset_commonality: f32,
+    pub charset_label: f32,
+    pub content_name_commonality: f32,
+    pub unknown_words: f32,
+    pub known_unknown_words_ratio: f32,
+    pub grammar_errors: f32,
+    pub caps_lower_ratio: f32,
+    pub tf_idf: f32,
+}
+
+impl FeatureColumns for TextFeatures {
+    fn columns() -> Vec<&'static str> {
+        vec!["body_length", "subject_length", "body_subject_ratio",
+             "header_count", "min_header_size", "max_header_size", "avg_header_size",
+             "mimetype_commonality", "charset_commonality", "charset_label",
+             "content_name_commonality", "unknown_words", "known_unknown_words_ratio",
+             "grammar_errors", "caps_lower_ratio", "tf_idf"]
+    }
+
+    fn values(&self) -> Vec<f32> {
+        vec![self.body_length as f32,
+             self.subject_length as f32,
+             self.body_subject_ratio,
+             self.header_count as f32,
+             self.min_header_size as f32,
+             self.max_header_size as f32,
+             self.avg_header_size as f32,
+             self.mimetype_commonality,
+             self.charset_commonality,
+             self.charset_label,
+             self.content_name_commonality,
+             self.unknown_words,
+             self.known_unknown_words_ratio,
+             self.grammar_errors,
+             self.caps_lower_ratio,
+             self.tf_idf]
+    }
+}
+
+impl TextFeatures {
+    pub fn from_email(email: &Email, dictionary: &HashMap<String, usize>) -> TextFeatures {
+        let body = email.text_body();
+        let body = body.as_str();
+        let subject = email.subject();
+
+        let body_length = body.chars().count();
+        let subject_length = subject.chars().count();
+
+        let header_sizes: Vec<usize> = email.headers.iter()
+            .map(|&(ref key, ref value)| key.len() + value.len())
+            .collect();
+        let header_count = header_sizes.len();
+
+        let words = words(body);
+        let known = words.iter().filter(|w| dictionary.contains_key(*w)).count();
+        let unknown = words.len() - known;
+
+        let upper = body.chars().filter(|c| c.is_uppercase()).count();
+        let lower = body.chars().filter(|c| c.is_lowercase()).count();
+
+        TextFeatures {
+            body_length,
+            subject_length,
+            body_subject_ratio: body_length as f32 / std::cmp::max(subject_length, 1) as f32,
+            header_count,
+            min_header_size: header_sizes.iter().cloned().min().unwrap_or(0),
+            max_header_size: header_sizes.iter().cloned().max().unwrap_or(0),
+            avg_header_size: header_sizes.iter().sum::<usize>() / std::cmp::max(header_count, 1),
+            mimetype_commonality: mimetype_commonality(email.mimetype()),
+            charset_commonality: charset_commonality(email.charset()),
+            charset_label: charset_label(email.charset()),
+            content_name_commonality: content_name_commonality(email),
+            unknown_words: unknown as f32 / std::cmp::max(words.len(), 1) as f32,
+            known_unknown_words_ratio: known as f32 / (unknown + 1) as f32,
+            grammar_errors: grammar_errors(body),
+            caps_lower_ratio: upper as f32 / std::cmp::max(lower, 1) as f32,
+            tf_idf: tf_idf(&words, dictionary),
+        }
+    }
 }
 
 #[derive_actor]
-impl TextFeatureExtractor
-{
-    pub fn analyze(&self) {}
+impl TextFeatureExtractor {
+    pub fn analyze(&self, email: ParsedEmail, res: TextFeaturesResponse) {
+        res(Ok(TextFeatures::from_email(&email, &self.dictionary)))
+    }
 }
 
-impl TextFeatureExtractor
-{
-    pub fn new(f: F, self_ref: CompletionHandlerActor, system: SystemActor) -> TextFeatureExtractor {
+impl TextFeatureExtractor {
+    pub fn new(self_ref: TextFeatureExtractorActor, system: SystemActor) -> TextFeatureExtractor {
         TextFeatureExtractor {
             self_ref,
-            system
+            system,
+            dictionary: Arc::new(dictionary()),
         }
     }
 
@@ -65,12 +137,184 @@ impl TextFeatureExtractor
                    t: Arc<T>)
         where T: Fn(TextFeatureExtractorActor, SystemActor) -> TextFeatureExtractor + Send + Sync + 'static
     {
-
-        match TextFeatureExtractorMessage {
-            TextFeatureExtractorMessage::Kill => return,
-            _ => ()
+        match msg {
+            TextFeatureExtractorMessage::AnalyzeVariant { email, res } => {
+                res(Err(
+                    ErrorKind::UnrecoverableError(
+                        "An unexpected error occurred in text feature extractor".into()).into())
+                );
+            }
         };
-        // t(self.self_ref.clone(), self.system.clone());
     }
 }
 
+pub fn dictionary() -> HashMap<String, usize> {
+    let mut dictionary = HashMap::new();
+    for (rank, word) in WORDS.lines().map(str::trim).filter(|w| !w.is_empty()).enumerate() {
+        dictionary.entry(word.to_owned()).or_insert(rank + 1);
+    }
+    dictionary
+}
+
+/// Lowercased runs of letters and apostrophes
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphabetic() || c == '\''))
+        .map(|w| w.trim_matches('\'').to_lowercase())
+        .filter(|w| !w.is_empty())
+        .collect()
+}
+
+fn mimetype_commonality(mimetype: &str) -> f32 {
+    match mimetype {
+        "text/plain" => 1.0,
+        "multipart/alternative" => 0.9,
+        "text/html" => 0.8,
+        "multipart/mixed" => 0.7,
+        "multipart/related" => 0.5,
+        _ => 0.1,
+    }
+}
+
+/// Charsets, most common first. The label is the index into this list.
+const CHARSETS: &'static [&'static str] = &[
+    "us-ascii", "utf-8", "iso-8859-1", "windows-1252", "iso-8859-15",
+    "iso-2022-jp", "shift_jis", "euc-kr", "gb2312", "big5", "koi8-r", "windows-1251",
+];
+
+fn charset_commonality(charset: &str) -> f32 {
+    match charset_index(charset) {
+        Some(i) if i < 2 => 1.0,
+        Some(i) if i < 5 => 0.6,
+        Some(_) => 0.3,
+        None => 0.0,
+    }
+}
+
+fn charset_label(charset: &str) -> f32 {
+    charset_index(charset).map(|i| i as f32).unwrap_or(CHARSETS.len() as f32)
+}
+
+fn charset_index(charset: &str) -> Option<usize> {
+    let charset = charset.to_lowercase();
+    CHARSETS.iter().position(|c| *c == charset)
+}
+
+/// How usual the least usual named part (attachment) is. 1.0 without any.
+fn content_name_commonality(email: &Email) -> f32 {
+    email.parts.iter()
+        .filter_map(|p| p.name())
+        .map(|name| {
+            let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+            match extension.as_ref() {
+                "pdf" | "jpg" | "jpeg" | "png" | "gif" | "txt" | "ics" => 0.9,
+                "doc" | "docx" | "xls" | "xlsx" | "ppt" | "pptx" | "csv" => 0.7,
+                "zip" | "rar" | "7z" | "gz" => 0.3,
+                _ => 0.1,
+            }
+        })
+        .fold(1.0, |least: f32, c| least.min(c))
+}
+
+/// Cheap signs of careless writing per sentence - sentences starting in
+/// lowercase, a lone lowercase 'i', repeated words and runs of '!' or '?'
+fn grammar_errors(text: &str) -> f32 {
+    let sentences: Vec<&str> = text.split(|c: char| c == '.' || c == '!' || c == '?')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    if sentences.is_empty() {
+        return 0.0;
+    }
+
+    let mut errors = 0;
+    for sentence in &sentences {
+        if sentence.chars().next().map(|c| c.is_lowercase()).unwrap_or(false) {
+            errors += 1;
+        }
+
+        let tokens: Vec<&str> = sentence.split_whitespace().collect();
+        errors += tokens.iter().filter(|t| **t == "i").count();
+        errors += tokens.windows(2)
+            .filter(|w| w[0].eq_ignore_ascii_case(w[1]))
+            .count();
+    }
+
+    errors += text.matches("!!").count() + text.matches("??").count();
+
+    errors as f32 / sentences.len() as f32
+}
+
+/// The highest tf-idf of any word in the text. There's no corpus to count
+/// document frequencies in, so a word's idf comes from its rank in the
+/// dictionary and words outside it are treated as the rarest.
+fn tf_idf(words: &[String], dictionary: &HashMap<String, usize>) -> f32 {
+    if words.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for word in words {
+        *counts.entry(word.as_str()).or_insert(0) += 1;
+    }
+
+    let rarest = (dictionary.len() + 1) as f32;
+    counts.iter()
+        .map(|(word, count)| {
+            let rank = dictionary.get(*word).map(|r| *r as f32).unwrap_or(rarest);
+            (*count as f32 / words.len() as f32) * (1.0 + rank).ln()
+        })
+        .fold(0.0, |max: f32, x| max.max(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_features() {
+        let email = Email {
+            headers: vec![("Subject".to_owned(), "Hello".to_owned())],
+            parts: vec![MimePart {
+                mimetype: "text/plain".to_owned(),
+                charset: "utf-8".to_owned(),
+                text: Some("FREE money now".to_owned()),
+                ..MimePart::default()
+            }],
+        };
+
+        let features = TextFeatures::from_email(&email, &dictionary());
+
+        assert_eq!(features.subject_length, 5);
+        assert_eq!(features.body_length, 14);
+        assert_eq!(features.header_count, 1);
+        assert_eq!(features.unknown_words, 0.0);
+        assert_eq!(features.charset_commonality, 1.0);
+    }
+
+    #[test]
+    fn rare_words_stand_out() {
+        let dictionary = dictionary();
+        assert_eq!(dictionary.len(), 10_000);
+
+        let body = "Hi Sam, the invoice for last month is attached. \
+                    Please pay it by Friday or call me with any questions.";
+        let email = Email {
+            headers: vec![("Subject".to_owned(), "Invoice".to_owned())],
+            parts: vec![MimePart {
+                mimetype: "text/plain".to_owned(),
+                charset: "utf-8".to_owned(),
+                text: Some(body.to_owned()),
+                ..MimePart::default()
+            }],
+        };
+
+        let features = TextFeatures::from_email(&email, &dictionary);
+
+        // "sam" and "invoice" out of 20 words
+        assert_eq!(features.unknown_words, 0.1);
+        assert_eq!(features.known_unknown_words_ratio, 6.0);
+        // Each appears once and ranks after the whole dictionary
+        assert!((features.tf_idf - (10_002f32).ln() / 20.0).abs() < 1e-5);
+    }
+}
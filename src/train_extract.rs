@@ -0,0 +1,297 @@
+use std;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use channel;
+use walkdir::WalkDir;
+
+use errors::*;
+use dataset::Dataset;
+use extraction::*;
+use feature_vector::*;
+use feature_store::FeatureStore;
+use campaign::CampaignIndex;
+use service::hash_email;
+
+/// Attempts per email before it's left out of the training data
+const MAX_TRIES: usize = 5;
+
+#[derive(Clone, Debug)]
+pub struct LabeledEmail {
+    pub path: PathBuf,
+    pub spam: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct ExtractSummary {
+    pub written: usize,
+    pub failed: usize,
+    /// Rows written from the feature store rather than extracted
+    pub stored: usize,
+}
+
+/// Every .eml file under `root`, labeled by the nearest enclosing directory
+/// named 'spam' or 'ham'. Files under neither are skipped.
+pub fn from_directory(root: &Path) -> Vec<LabeledEmail> {
+    WalkDir::new(root)
+        .into_iter()
+        .filter_map(std::result::Result::ok)
+        .filter(|p| p.file_type().is_file())
+        .map(|p| p.path().to_owned())
+        .filter(|p| p.extension() == Some(std::ffi::OsStr::new("eml")))
+        .filter_map(|path| {
+            let label = path.strip_prefix(root)
+                .unwrap_or(&path)
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(name) => name.to_str().map(|n| n.to_lowercase()),
+                    _ => None,
+                })
+                .filter(|name| name == "spam" || name == "ham")
+                .last();
+
+            label.map(|label| LabeledEmail {
+                spam: label == "spam",
+                path,
+            })
+        })
+        .collect()
+}
+
+/// Reads a manifest of `path,label` lines. Relative paths are relative to the
+/// manifest, labels are anything `Dataset::parse_label` accepts.
+pub fn from_manifest(manifest: &Path) -> Result<Vec<LabeledEmail>> {
+    let base = manifest.parent().unwrap_or(Path::new("."));
+    let file = File::open(manifest)?;
+
+    let mut emails = Vec::new();
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (path, label) = match line.rfind(',') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => bail!(ErrorKind::UnrecoverableError(
+                format!("Line {} of {:#?} has no label", line_no + 1, manifest).into()))
+        };
+
+        emails.push(LabeledEmail {
+            path: base.join(path.trim()),
+            spam: Dataset::parse_label(label)? > 0.5,
+        });
+    }
+
+    Ok(emails)
+}
+
+/// The training CSV header - the extractor's columns followed by the label
+pub fn header() -> String {
+    let mut columns = Features::columns();
+    columns.push("label");
+    columns.join(",")
+}
+
+/// One row of the training CSV
+pub fn labeled_row(features: &Features, spam: bool) -> String {
+    let label = if spam { "True" } else { "False" };
+    format!("{},{}", features.to_vector().row(), label)
+}
+
+/// Runs every email through the live extraction pipeline and writes a labeled
+/// feature CSV to `output`. Emails already in `store` aren't extracted again,
+/// and newly extracted features are added to it. Each email is added to
+/// `campaigns` as it's written, the way the service would have seen it.
+pub fn extract_training_data(emails: Vec<LabeledEmail>,
+                             extractors: Vec<FeatureExtractionManagerActor>,
+                             store: Option<&FeatureStore>,
+                             campaigns: &CampaignIndex,
+                             output: &Path) -> Result<ExtractSummary> {
+    let mut writer = BufWriter::new(File::create(output)?);
+    writeln!(writer, "{}", header())?;
+
+    let (tx, rx) = channel::unbounded();
+    let mut extractors = extractors.into_iter().cycle();
+    let mut tries = HashMap::new();
+    let mut outstanding = 0;
+    let mut summary = ExtractSummary::default();
+
+    for (index, email) in emails.iter().enumerate() {
+        let mut buf = Vec::new();
+        if let Err(e) = File::open(&email.path).and_then(|mut f| f.read_to_end(&mut buf)) {
+            println!("Failed to read {:#?}: {}", email.path, e);
+            summary.failed += 1;
+            continue;
+        }
+
+        if let Some(store) = store {
+            let hash = hash_email(&buf);
+            match store.get(&hash) {
+                Ok(Some(mut features)) => {
+                    features.campaign = campaigns.observe(&hash, &features.campaign);
+                    writeln!(writer, "{}", labeled_row(&features, email.spam))?;
+                    summary.written += 1;
+                    summary.stored += 1;
+                    continue;
+                }
+                Ok(None) => (),
+                Err(e) => println!("Failed to load stored features for {:#?}: {}", email.path, e),
+            }
+        }
+
+        let buf = Arc::new(buf);
+        send(extractors.next().expect("No extractors"), index, buf.clone(), tx.clone());
+        tries.insert(index, (buf, 1));
+        outstanding += 1;
+    }
+
+    while outstanding > 0 {
+        let (index, features) = match rx.recv() {
+            Ok(result) => result,
+            Err(_) => break,
+        };
+
+        let email = &emails[index];
+        match features {
+            Ok(mut features) => {
+                if let Some(&(ref buf, _)) = tries.get(&index) {
+                    let hash = hash_email(buf);
+                    if let Some(store) = store {
+                        if let Err(e) = store.put(&hash, &features) {
+                            println!("Failed to store features for {:#?}: {}", email.path, e);
+                        }
+                    }
+                    features.campaign = campaigns.observe(&hash, &features.campaign);
+                }
+
+                writeln!(writer, "{}", labeled_row(&features, email.spam))?;
+                summary.written += 1;
+                outstanding -= 1;
+            }
+            Err(e) => {
+                let retry = match *e.kind() {
+                    ErrorKind::UnrecoverableError(_) => None,
+                    _ => match tries.get_mut(&index) {
+                        Some(entry) => {
+                            if entry.1 < MAX_TRIES {
+                                entry.1 += 1;
+                                Some(entry.0.clone())
+                            } else {
+                                None
+                            }
+                        }
+                        None => None,
+                    },
+                };
+
+                match retry {
+                    Some(buf) => send(extractors.next().expect("No extractors"), index, buf, tx.clone()),
+                    None => {
+                        println!("Failed to extract {:#?}: {}", email.path, e);
+                        summary.failed += 1;
+                        outstanding -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(summary)
+}
+
+fn send(extractor: FeatureExtractionManagerActor,
+        index: usize,
+        email: Arc<Vec<u8>>,
+        tx: channel::Sender<(usize, Result<Features>)>) {
+    extractor.extract(email, Arc::new(move |features| {
+        tx.send((index, features));
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("train_extract_{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn touch(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap();
+    }
+
+    fn write(path: &Path, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn directories_label_by_nearest_spam_or_ham() {
+        let root = temp_dir();
+        touch(&root.join("spam/a.eml"));
+        touch(&root.join("Ham/2018/b.eml"));
+        touch(&root.join("spam/reviewed/ham/c.eml"));
+        touch(&root.join("spam/notes.txt"));
+        touch(&root.join("unsorted/d.eml"));
+
+        let mut emails: Vec<(String, bool)> = from_directory(&root).into_iter()
+            .map(|email| {
+                let name = email.path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, email.spam)
+            })
+            .collect();
+        emails.sort();
+
+        assert_eq!(emails, vec![("a.eml".to_owned(), true),
+                                ("b.eml".to_owned(), false),
+                                ("c.eml".to_owned(), false)]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn manifests_split_on_the_last_comma() {
+        let dir = temp_dir();
+        let manifest = dir.join("manifest.csv");
+        write(&manifest, "# path,label\n\
+                              inbox/a.eml,spam\n\
+                              \n\
+                              inbox/Re: lunch, Friday.eml, False\n\
+                              /archive/c.eml,1\n");
+
+        let emails = from_manifest(&manifest).unwrap();
+        assert_eq!(emails.len(), 3);
+        assert_eq!(emails[0].path, dir.join("inbox/a.eml"));
+        assert!(emails[0].spam);
+        assert_eq!(emails[1].path, dir.join("inbox/Re: lunch, Friday.eml"));
+        assert!(!emails[1].spam);
+        assert_eq!(emails[2].path, PathBuf::from("/archive/c.eml"));
+        assert!(emails[2].spam);
+
+        write(&manifest, "inbox/a.eml,maybe\n");
+        assert!(from_manifest(&manifest).is_err());
+        write(&manifest, "inbox/a.eml\n");
+        assert!(from_manifest(&manifest).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rows_match_the_header() {
+        let columns = header().split(',').count();
+        assert_eq!(labeled_row(&Features::default(), true).split(',').count(), columns);
+        assert!(labeled_row(&Features::default(), true).ends_with(",True"));
+        assert!(labeled_row(&Features::default(), false).ends_with(",False"));
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use authentication::AuthenticationResults;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Label {
+    Ham,
+    Suspect,
+    Spam,
+}
+
+/// Maps a spam probability to a Label
+///
+/// Anything at or above `spam` is Spam, at or above `suspect` is Suspect,
+/// everything else is Ham.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Thresholds {
+    pub suspect: f32,
+    pub spam: f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Thresholds {
+        Thresholds {
+            suspect: 0.5,
+            spam: 0.8,
+        }
+    }
+}
+
+impl Thresholds {
+    pub fn label(&self, probability: f32) -> Label {
+        if probability >= self.spam {
+            Label::Spam
+        } else if probability >= self.suspect {
+            Label::Suspect
+        } else {
+            Label::Ham
+        }
+    }
+}
+
+/// The result of classifying a single email
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Verdict {
+    /// Probability, from 0.0 to 1.0, that the email is spam
+    pub probability: f32,
+    pub label: Label,
+    pub model_version: String,
+    /// Time spent in the model, not including feature extraction
+    pub latency: Duration,
+    /// Human readable reasons the email looks malicious, whatever the label
+    #[serde(default)]
+    pub reasons: Vec<String>,
+    /// SPF, DKIM and DMARC as we evaluated them
+    #[serde(default)]
+    pub authentication: AuthenticationResults,
+}
+
+impl Verdict {
+    pub fn new(probability: f32,
+               thresholds: &Thresholds,
+               model_version: String,
+               latency: Duration) -> Verdict {
+        Verdict {
+            probability,
+            label: thresholds.label(probability),
+            model_version,
+            latency,
+            reasons: Vec::new(),
+            authentication: AuthenticationResults::default(),
+        }
+    }
+
+    pub fn with_reasons(mut self, reasons: Vec<String>) -> Verdict {
+        self.reasons = reasons;
+        self
+    }
+
+    pub fn with_authentication(mut self, authentication: AuthenticationResults) -> Verdict {
+        self.authentication = authentication;
+        self
+    }
+
+    pub fn is_spam(&self) -> bool {
+        self.label == Label::Spam
+    }
+}
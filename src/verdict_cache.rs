@@ -0,0 +1,339 @@
+use std;
+use std::hash::Hasher;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use lru_time_cache::LruCache;
+use twox_hash::XxHash;
+use redis::{self, Commands, Connection};
+use serde_json;
+
+use errors::*;
+use config::{CacheBackend, PredictionCacheConfig};
+use verdict::Verdict;
+
+/// How long to stop trying redis after failing to reach it
+const RECONNECT_BACKOFF_SECS: u64 = 5;
+
+/// Where PredictionCache keeps verdicts.
+///
+/// An `Err` means the cache couldn't be reached; callers treat it as a miss.
+pub trait VerdictCache: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Verdict>>;
+    fn set(&self, key: &[u8], verdict: &Verdict) -> Result<()>;
+}
+
+pub fn verdict_cache_from_config(config: &PredictionCacheConfig) -> Arc<VerdictCache> {
+    match config.backend {
+        CacheBackend::Memory => {
+            Arc::new(ShardedVerdictCache::new(config.shards, config.capacity, config.ttl()))
+        }
+        CacheBackend::Redis => {
+            Arc::new(RedisVerdictCache::new(&config.redis_url,
+                                            config.shards,
+                                            config.ttl(),
+                                            config.redis_timeout()))
+        }
+    }
+}
+
+/// The key a verdict is cached under. Verdicts from one model version are
+/// never served once another version has taken over.
+pub fn cache_key(email_hash: &[u8], model_version: &str) -> Vec<u8> {
+    let mut key = email_hash.to_vec();
+    key.extend_from_slice(&b"prediction"[..]);
+    key.extend_from_slice(model_version.as_bytes());
+    key
+}
+
+/// An in-process LRU whose entries also expire
+pub struct LruVerdictCache {
+    cache: Mutex<LruCache<Vec<u8>, Verdict>>,
+}
+
+impl LruVerdictCache {
+    pub fn new(capacity: usize, time_to_live: Duration) -> LruVerdictCache {
+        LruVerdictCache {
+            cache: Mutex::new(LruCache::with_expiry_duration_and_capacity(time_to_live, capacity)),
+        }
+    }
+}
+
+impl VerdictCache for LruVerdictCache {
+    fn get(&self, key: &[u8]) -> Result<Option<Verdict>> {
+        Ok(self.cache.lock().unwrap().get(&key.to_vec()).cloned())
+    }
+
+    fn set(&self, key: &[u8], verdict: &Verdict) -> Result<()> {
+        self.cache.lock().unwrap().insert(key.to_vec(), verdict.clone());
+        Ok(())
+    }
+}
+
+/// LRUs that each hold a share of the keys, so workers looking up different
+/// emails rarely wait on the same lock
+pub struct ShardedVerdictCache {
+    shards: Vec<LruVerdictCache>,
+}
+
+impl ShardedVerdictCache {
+    /// `capacity` is split evenly between the shards
+    pub fn new(shards: usize, capacity: usize, time_to_live: Duration) -> ShardedVerdictCache {
+        let shards = std::cmp::max(shards, 1);
+        let per_shard = std::cmp::max((capacity + shards - 1) / shards, 1);
+
+        ShardedVerdictCache {
+            shards: (0..shards).map(|_| LruVerdictCache::new(per_shard, time_to_live)).collect(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &LruVerdictCache {
+        &self.shards[shard_index(key, self.shards.len())]
+    }
+}
+
+fn shard_index(key: &[u8], shards: usize) -> usize {
+    let mut hasher = XxHash::default();
+    hasher.write(key);
+    hasher.finish() as usize % shards
+}
+
+impl VerdictCache for ShardedVerdictCache {
+    fn get(&self, key: &[u8]) -> Result<Option<Verdict>> {
+        self.shard(key).get(key)
+    }
+
+    fn set(&self, key: &[u8], verdict: &Verdict) -> Result<()> {
+        self.shard(key).set(key, verdict)
+    }
+}
+
+/// Lookups across every PredictionCache sharing a VerdictCache
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    /// Lookups the cache couldn't answer, also counted as misses
+    errors: AtomicUsize,
+}
+
+impl CacheStats {
+    /// Each record returns the number of lookups so far, including this one
+    pub fn record_hit(&self) -> usize {
+        self.hits.fetch_add(1, Ordering::Relaxed) + 1 + self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn record_miss(&self) -> usize {
+        self.misses.fetch_add(1, Ordering::Relaxed) + 1 + self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn record_error(&self) -> usize {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.record_miss()
+    }
+
+    pub fn summary(&self) -> String {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = std::cmp::max(hits + misses, 1);
+
+        format!("prediction cache: {} hits, {} misses ({} errors), {:.1}% hit rate",
+                hits,
+                misses,
+                self.errors.load(Ordering::Relaxed),
+                hits as f64 * 100.0 / lookups as f64)
+    }
+}
+
+/// Verdicts in redis, stored as JSON and expired by redis itself.
+///
+/// Keys are spread over several connections, like ShardedVerdictCache's
+/// LRUs, so one slow request only holds up the lookups that share its
+/// connection. Connecting and each request give up after `timeout`.
+///
+/// Connections are made lazily and remade after an error. While redis is
+/// unreachable every call fails fast for RECONNECT_BACKOFF_SECS instead of waiting
+/// on another connection attempt.
+pub struct RedisVerdictCache {
+    url: String,
+    time_to_live: Duration,
+    timeout: Duration,
+    connections: Vec<Mutex<RedisConnection>>,
+}
+
+enum RedisConnection {
+    Disconnected,
+    Connected(Connection),
+    Failed(Instant),
+}
+
+impl RedisVerdictCache {
+    pub fn new(url: &str, connections: usize, time_to_live: Duration, timeout: Duration) -> RedisVerdictCache {
+        RedisVerdictCache {
+            url: url.to_owned(),
+            time_to_live,
+            // Socket timeouts of zero are rejected
+            timeout: std::cmp::max(timeout, Duration::from_millis(1)),
+            connections: (0..std::cmp::max(connections, 1))
+                .map(|_| Mutex::new(RedisConnection::Disconnected))
+                .collect(),
+        }
+    }
+
+    /// Connects on another thread, redis can't be given a connect timeout
+    fn connect(&self) -> Result<Connection> {
+        let (tx, rx) = mpsc::channel();
+        let url = self.url.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(redis::Client::open(url.as_str()).and_then(|client| client.get_connection()));
+        });
+
+        let con = match rx.recv_timeout(self.timeout) {
+            Ok(Ok(con)) => con,
+            Ok(Err(e)) => bail!(ErrorKind::RecoverableError(
+                format!("Failed to connect to redis at {}: {}", self.url, e).into())),
+            Err(_) => bail!(ErrorKind::RecoverableError(
+                format!("Timed out connecting to redis at {}", self.url).into())),
+        };
+
+        con.set_read_timeout(Some(self.timeout))
+            .and_then(|_| con.set_write_timeout(Some(self.timeout)))
+            .map_err(|e| ErrorKind::RecoverableError(format!("Failed to configure redis: {}", e).into()))?;
+
+        Ok(con)
+    }
+
+    fn with_connection<T, F>(&self, key: &[u8], f: F) -> Result<T>
+        where F: FnOnce(&Connection) -> redis::RedisResult<T>
+    {
+        let mut connection = self.connections[shard_index(key, self.connections.len())].lock().unwrap();
+
+        if let RedisConnection::Failed(at) = *connection {
+            if at.elapsed() < Duration::from_secs(RECONNECT_BACKOFF_SECS) {
+                bail!(ErrorKind::RecoverableError("redis is unreachable".into()));
+            }
+        }
+
+        let connected = match *connection {
+            RedisConnection::Connected(_) => true,
+            _ => false,
+        };
+
+        if !connected {
+            match self.connect() {
+                Ok(con) => *connection = RedisConnection::Connected(con),
+                Err(e) => {
+                    *connection = RedisConnection::Failed(Instant::now());
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = match *connection {
+            RedisConnection::Connected(ref con) => f(con),
+            _ => unreachable!("connected above"),
+        };
+
+        result.map_err(|e| {
+            // The connection may be broken, make a new one next time
+            *connection = RedisConnection::Failed(Instant::now());
+            ErrorKind::RecoverableError(format!("redis request failed: {}", e).into()).into()
+        })
+    }
+}
+
+impl VerdictCache for RedisVerdictCache {
+    fn get(&self, key: &[u8]) -> Result<Option<Verdict>> {
+        let value: Option<String> = self.with_connection(key, |con| con.get(key.to_vec()))?;
+
+        match value {
+            Some(json) => {
+                serde_json::from_str(&json)
+                    .map(Some)
+                    .chain_err(|| "Corrupt verdict in redis")
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: &[u8], verdict: &Verdict) -> Result<()> {
+        let json = serde_json::to_string(verdict).chain_err(|| "Failed to serialize verdict")?;
+        let seconds = std::cmp::max(self.time_to_live.as_secs(), 1) as usize;
+        self.with_connection(key, |con| con.set_ex(key.to_vec(), json, seconds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use verdict::Thresholds;
+
+    fn verdict(version: &str) -> Verdict {
+        Verdict::new(0.9, &Thresholds::default(), version.to_owned(), Duration::from_millis(1))
+    }
+
+    #[test]
+    fn keys_include_the_model_version() {
+        let cache = LruVerdictCache::new(10, Duration::from_secs(60));
+        cache.set(&cache_key(b"email", "v1"), &verdict("v1")).unwrap();
+
+        assert!(cache.get(&cache_key(b"email", "v1")).unwrap().is_some());
+        assert!(cache.get(&cache_key(b"email", "v2")).unwrap().is_none());
+    }
+
+    #[test]
+    fn sharded_cache_counts_hits_and_misses() {
+        let cache = ShardedVerdictCache::new(4, 100, Duration::from_secs(60));
+        let stats = CacheStats::default();
+
+        for i in 0..8u8 {
+            cache.set(&cache_key(&[i], "v1"), &verdict("v1")).unwrap();
+        }
+        for i in 0..10u8 {
+            match cache.get(&cache_key(&[i], "v1")).unwrap() {
+                Some(_) => stats.record_hit(),
+                None => stats.record_miss(),
+            };
+        }
+
+        assert_eq!(stats.summary(), "prediction cache: 8 hits, 2 misses (0 errors), 80.0% hit rate");
+    }
+
+    #[test]
+    fn unreachable_redis_fails_fast() {
+        // Nothing listens on port 1
+        let cache = RedisVerdictCache::new("redis://127.0.0.1:1/", 4, Duration::from_secs(60),
+                                           Duration::from_millis(100));
+        assert!(cache.get(b"key").is_err());
+
+        let started = Instant::now();
+        assert!(cache.set(b"key", &verdict("v1")).is_err());
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn unresponsive_redis_times_out() {
+        // Accepts connections but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let cache = RedisVerdictCache::new(&url, 4, Duration::from_secs(60), Duration::from_millis(50));
+
+        let started = Instant::now();
+        assert!(cache.get(b"key").is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    /// Needs `redis-server` listening on the default port
+    #[test]
+    #[ignore]
+    fn round_trips_through_redis() {
+        let cache = RedisVerdictCache::new("redis://127.0.0.1/", 4, Duration::from_secs(60),
+                                           Duration::from_millis(100));
+        let key = cache_key(b"round_trips_through_redis", "v1");
+
+        cache.set(&key, &verdict("v1")).unwrap();
+        let cached = cache.get(&key).unwrap().expect("cached verdict");
+        assert_eq!(cached.model_version, "v1");
+    }
+}